@@ -5,4 +5,8 @@ pushd user_programs
 
 cargo build --message-format=short && cp target/tinyos_x64_user_target/debug/shell ../tiny_os/tinyos_kernel/src/shell && cp target/tinyos_x64_user_target/debug/rec_fib ../tiny_os/tinyos_kernel/src/rec_fib
 
+rem Test programs, run by the kernel when built with the user_tests feature
+if not exist ..\tiny_os\tinyos_kernel\src\user_tests mkdir ..\tiny_os\tinyos_kernel\src\user_tests
+for %%p in (test_syscalls test_tasks test_read test_child) do cp target/tinyos_x64_user_target/debug/%%p ../tiny_os/tinyos_kernel/src/user_tests/%%p
+
 popd
\ No newline at end of file
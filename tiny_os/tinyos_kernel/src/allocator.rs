@@ -1,20 +1,32 @@
 
-use crate::memory;
+use crate::{memory, slab};
 use alloc::alloc::{GlobalAlloc, Layout};
 use core::ptr::null_mut;
-use buddy_system_allocator::LockedHeap;
+use core::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use buddy_system_allocator::{Heap, LockedHeapWithRescue};
 use x86_64::{
     structures::paging::{
-        mapper::MapToError, FrameAllocator, Mapper, Page, PageTableFlags, Size4KiB,
+        mapper::MapToError, FrameAllocator, Mapper, OffsetPageTable, Page, PageTableFlags, Size4KiB,
     },
     VirtAddr,
 };
 
 pub const KERNEL_HEAP_START: usize = 0x_4444_4444_0000;
-pub const KERNEL_HEAP_SIZE:  usize = 100 * 1024; // 100 KiB
+pub const KERNEL_HEAP_SIZE:  usize = 100 * 1024; // 100 KiB, mapped at boot
+/// Default for how big the heap can grow, see set_heap_limit.
+pub const KERNEL_HEAP_MAX_SIZE: usize = 64 * 1024 * 1024; // 64 MiB
+// Smallest amount the heap grows by
+const HEAP_GROW_STEP: usize = 64 * 1024;
 
 #[global_allocator]
-static ALLOCATOR: LockedHeap<32> = LockedHeap::empty();
+static ALLOCATOR: KernelAllocator = KernelAllocator;
+static HEAP: LockedHeapWithRescue<32> = LockedHeapWithRescue::new(grow_heap);
+
+// End of the mapped part of the heap
+static HEAP_END: AtomicUsize = AtomicUsize::new(KERNEL_HEAP_START);
+static HEAP_LIMIT: AtomicUsize = AtomicUsize::new(KERNEL_HEAP_MAX_SIZE);
+static HEAP_GROWS: AtomicU64 = AtomicU64::new(0);
+static PHYS_OFFSET: AtomicU64 = AtomicU64::new(0);
 
 pub fn init_heap(mapper: &mut impl Mapper<Size4KiB>)
                  -> Result<(), MapToError<Size4KiB>>
@@ -39,12 +51,133 @@ pub fn init_heap(mapper: &mut impl Mapper<Size4KiB>)
     }
 
     unsafe {
-        ALLOCATOR.lock().init(KERNEL_HEAP_START, KERNEL_HEAP_SIZE);
+        HEAP.lock().init(KERNEL_HEAP_START, KERNEL_HEAP_SIZE);
     }
+    HEAP_END.store(KERNEL_HEAP_START + KERNEL_HEAP_SIZE, Ordering::SeqCst);
+    PHYS_OFFSET.store(memory::KERNEL_MEM_INFO.lock().phys_offset.as_u64(), Ordering::SeqCst);
 
     return Ok(());
 }
 
+/// Small allocations come from the slab caches, the rest (and small
+/// ones the caches can't satisfy) from the heap.
+pub struct KernelAllocator;
+
+unsafe impl GlobalAlloc for KernelAllocator
+{
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8
+    {
+        let ptr = slab::alloc(&layout);
+        if !ptr.is_null() { return ptr; }
+        return unsafe { HEAP.alloc(layout) };
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout)
+    {
+        if slab::owns(ptr) {
+            unsafe { slab::dealloc(ptr, &layout) };
+        } else {
+            unsafe { HEAP.dealloc(ptr, layout) };
+        }
+    }
+}
+
+/// Sets how big the heap can grow (it never shrinks, so this doesn't unmap anything).
+pub fn set_heap_limit(bytes: usize)
+{
+    HEAP_LIMIT.store(bytes, Ordering::SeqCst);
+}
+
+// Called by the allocator, with its lock held, when an allocation fails.
+// Maps more pages after the end of the heap and adds them to it. Page
+// tables share the heap's level 3 table (see memory::clone_page_table),
+// so the new pages show up in every address space. This runs in the
+// middle of an allocation, so it mustn't allocate or log anything, and
+// it gives up instead of waiting for the frame allocator's lock.
+fn grow_heap(heap: &mut Heap<32>, layout: &Layout)
+{
+    let phys_offset = PHYS_OFFSET.load(Ordering::SeqCst);
+    if phys_offset == 0 { return; }
+
+    // The buddy allocator needs a free block of this size, aligned to it
+    let block = core::cmp::max(layout.size().next_power_of_two(), core::cmp::max(layout.align(), 8));
+    let start = HEAP_END.load(Ordering::SeqCst);
+    let block_start = (start + block - 1) & !(block - 1);
+    let end = core::cmp::max(block_start + block, start + HEAP_GROW_STEP);
+    let end = (end + 4095) & !4095;
+    if end - KERNEL_HEAP_START > HEAP_LIMIT.load(Ordering::SeqCst) { return; }
+
+    let mut frame_allocator = match memory::FRAME_ALLOCATOR.try_lock()
+    {
+        Some(frame_allocator) => frame_allocator,
+        None => return,
+    };
+    let phys_offset = VirtAddr::new(phys_offset);
+    let mut mapper = unsafe { OffsetPageTable::new(memory::active_level_4_table(phys_offset), phys_offset) };
+
+    // Whatever could be mapped is added, even if it's not enough
+    let mut mapped_end = start;
+    while mapped_end < end
+    {
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(mapped_end as u64));
+        let frame = match frame_allocator.allocate_frame()
+        {
+            Some(frame) => frame,
+            None => break,
+        };
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        match unsafe { mapper.map_to(page, frame, flags, &mut *frame_allocator) }
+        {
+            Ok(flush) => flush.flush(),
+            Err(_) => break,
+        }
+        mapped_end += 4096;
+    }
+
+    if mapped_end > start
+    {
+        unsafe { heap.add_to_heap(start, mapped_end) };
+        HEAP_END.store(mapped_end, Ordering::SeqCst);
+        HEAP_GROWS.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[alloc_error_handler]
+fn alloc_error_handler(layout: Layout) -> !
+{
+    crate::error!("Out of heap memory: allocating {} bytes aligned to {} failed", layout.size(), layout.align());
+    if let Some(stats) = try_heap_stats() {
+        crate::error!("Heap: {} of {} bytes allocated ({} taken), limit {} bytes", stats.allocated, stats.total, stats.actual, stats.limit);
+    }
+    panic!("allocation error: {:?}", layout)
+}
+
+pub struct HeapStats
+{
+    pub total: usize,
+    /// Bytes requested by allocations.
+    pub allocated: usize,
+    /// Bytes actually taken, which is more because of rounding to powers of two.
+    pub actual: usize,
+    /// How big the heap can grow.
+    pub limit: usize,
+    /// How many times it has grown since boot.
+    pub grows: u64,
+}
+
+/// Doesn't wait for the allocator's lock, returns None if it's taken.
+pub fn try_heap_stats() -> Option<HeapStats>
+{
+    let heap = HEAP.try_lock()?;
+    return Some(HeapStats {
+        total: heap.stats_total_bytes(),
+        allocated: heap.stats_alloc_user(),
+        actual: heap.stats_alloc_actual(),
+        limit: HEAP_LIMIT.load(Ordering::Relaxed),
+        grows: HEAP_GROWS.load(Ordering::Relaxed),
+    });
+}
+
 pub struct Dummy;
 
 unsafe impl GlobalAlloc for Dummy
@@ -59,3 +192,67 @@ unsafe impl GlobalAlloc for Dummy
         panic!("dealloc should be never called")
     }
 }
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use alloc::{boxed::Box, vec::Vec};
+
+    #[test_case]
+    fn simple_allocation()
+    {
+        let a = Box::new(41);
+        let b = Box::new(13);
+        assert_eq!(*a, 41);
+        assert_eq!(*b, 13);
+    }
+
+    #[test_case]
+    fn large_vec()
+    {
+        let n = 1000;
+        let mut vec = Vec::new();
+        for i in 0..n {
+            vec.push(i);
+        }
+        assert_eq!(vec.iter().sum::<u64>(), (n - 1) * n / 2);
+    }
+
+    #[test_case]
+    fn heap_grows()
+    {
+        let grows = try_heap_stats().unwrap().grows;
+        let vec: Vec<u64> = alloc::vec![7; KERNEL_HEAP_SIZE / 2];
+        assert!(vec.iter().all(|&x| x == 7));
+
+        let stats = try_heap_stats().unwrap();
+        assert!(stats.grows > grows);
+        assert!(stats.total > KERNEL_HEAP_SIZE);
+        assert!(stats.total <= stats.limit);
+    }
+
+    #[test_case]
+    fn heap_limit()
+    {
+        let layout = Layout::from_size_align(2 * KERNEL_HEAP_MAX_SIZE, 8).unwrap();
+        let ptr = unsafe { alloc::alloc::alloc(layout) };
+        assert!(ptr.is_null());
+    }
+
+    #[test_case]
+    fn many_boxes()
+    {
+        // Would run out of memory if freed blocks weren't reused,
+        // since the heap isn't allowed to grow meanwhile
+        let stats = try_heap_stats().unwrap();
+        set_heap_limit(stats.total);
+        for i in 0..KERNEL_HEAP_SIZE / 8 {
+            let x = Box::new([i; 512]);  // Too big for the slab caches
+            assert_eq!(x[511], i);
+        }
+        let grows = try_heap_stats().unwrap().grows;
+        set_heap_limit(stats.limit);
+        assert_eq!(grows, stats.grows);
+    }
+}
@@ -0,0 +1,125 @@
+// Parser for the subset of VT100/ANSI escape sequences the console
+// understands. It only splits the output into characters and commands,
+// what they do is up to whoever is drawing.
+
+pub const ESC: char = '\x1b';
+pub const MAX_PARAMS: usize = 8;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action
+{
+    /// A character to draw, or a control character like '\n'.
+    Print(char),
+    /// ESC followed by a single character, e.g. ESC 7 (save cursor).
+    Escape(char),
+    /// Control sequence: ESC [ params final_char.
+    Csi(CsiCommand),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CsiCommand
+{
+    pub params: [u16; MAX_PARAMS],
+    pub num_params: usize,
+    /// Sequences like ESC [ ? 25 h, which are mostly ignored.
+    pub private: bool,
+    pub final_char: char,
+}
+
+impl CsiCommand
+{
+    /// Returns the parameter at `idx`, or `default` if it's missing or 0.
+    pub fn param(&self, idx: usize, default: u16) -> u16
+    {
+        if idx >= self.num_params || self.params[idx] == 0 { return default; }
+        return self.params[idx];
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum State
+{
+    Ground,
+    Escape,
+    Csi,
+}
+
+pub struct Parser
+{
+    state: State,
+    csi: CsiCommand,
+}
+
+impl Parser
+{
+    pub const fn new() -> Self
+    {
+        return Parser {
+            state: State::Ground,
+            csi: CsiCommand { params: [0; MAX_PARAMS], num_params: 0, private: false, final_char: '\0' },
+        };
+    }
+
+    /// Feeds a character to the parser. Returns an action when
+    /// a character or a whole sequence has been read.
+    pub fn feed(&mut self, c: char) -> Option<Action>
+    {
+        match self.state
+        {
+            State::Ground =>
+            {
+                if c == ESC { self.state = State::Escape; return None; }
+                return Some(Action::Print(c));
+            }
+            State::Escape =>
+            {
+                if c == '['
+                {
+                    self.state = State::Csi;
+                    self.csi.params = [0; MAX_PARAMS];
+                    self.csi.num_params = 0;
+                    self.csi.private = false;
+                    return None;
+                }
+
+                self.state = State::Ground;
+                return Some(Action::Escape(c));
+            }
+            State::Csi =>
+            {
+                match c
+                {
+                    '0'..='9' =>
+                    {
+                        if self.csi.num_params == 0 { self.csi.num_params = 1; }
+                        let param = &mut self.csi.params[self.csi.num_params - 1];
+                        *param = param.saturating_mul(10).saturating_add(c as u16 - '0' as u16);
+                    }
+                    ';' =>
+                    {
+                        if self.csi.num_params == 0 { self.csi.num_params = 1; }
+                        if self.csi.num_params < MAX_PARAMS { self.csi.num_params += 1; }
+                    }
+                    '?' | '>' | '=' => self.csi.private = true,
+                    '\x40'..='\x7e' =>
+                    {
+                        self.state = State::Ground;
+                        self.csi.final_char = c;
+                        return Some(Action::Csi(self.csi));
+                    }
+                    // Malformed sequence, drop it
+                    _ => self.state = State::Ground,
+                }
+                return None;
+            }
+        }
+    }
+}
+
+impl Default for Parser
+{
+    fn default() -> Self
+    {
+        return Self::new();
+    }
+}
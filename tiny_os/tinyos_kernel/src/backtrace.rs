@@ -0,0 +1,84 @@
+// Stack traces, found by following the chain of saved frame pointers.
+// Every frame starts with the caller's rbp, followed by the return address.
+// The kernel is built with frame pointers forced on (see .cargo/config.toml).
+
+use crate::{memory, symbols};
+use core::fmt::{self, Write};
+
+/// Maximum number of frames walked, in case the chain is corrupted.
+pub const MAX_FRAMES: usize = 32;
+
+pub fn current_frame_pointer() -> u64
+{
+    let rbp: u64;
+    unsafe { core::arch::asm!("mov {}, rbp", out(reg) rbp, options(nomem, nostack, preserves_flags)) };
+    return rbp;
+}
+
+/// Calls `f` with the return address of each frame, starting from the
+/// frame that `rbp` points to. Stops at the first frame that doesn't
+/// look valid, since following a bad pointer would fault.
+pub fn walk_stack(mut rbp: u64, mut f: impl FnMut(u64))
+{
+    for _ in 0..MAX_FRAMES
+    {
+        if rbp == 0 || rbp % 8 != 0 { return; }
+        if !memory::is_mapped(rbp) || !memory::is_mapped(rbp + 8) { return; }
+
+        let (next_rbp, ret_addr) = unsafe {
+            let frame = rbp as *const u64;
+            (*frame, *frame.add(1))
+        };
+        if ret_addr == 0 { return; }
+        f(ret_addr);
+
+        // Stacks grow down, so callers' frames are at higher addresses
+        if next_rbp <= rbp { return; }
+        rbp = next_rbp;
+    }
+}
+
+/// Shows a code address along with the function it's in, if known,
+/// e.g. "0x2051a4 tinyos::process::Scheduler::run_next_task+0x4a".
+pub struct Symbolized(pub u64);
+
+impl fmt::Display for Symbolized
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "{:#018x}", self.0)?;
+        if let Some((name, offset)) = symbols::lookup(self.0) {
+            write!(f, " {}+{:#x}", name, offset)?;
+        }
+        return Ok(());
+    }
+}
+
+/// Writes a line like "#3 0x2051a4 tinyos::process::Scheduler::run_next_task+0x4a".
+pub fn write_frame(out: &mut impl Write, idx: usize, ret_addr: u64) -> fmt::Result
+{
+    // Return addresses point after the call, which could be in the next function
+    // already, so look up the call instruction instead.
+    let call_addr = ret_addr.saturating_sub(1);
+    write!(out, "  #{:<2} {:#018x}", idx, ret_addr)?;
+    if let Some((name, offset)) = symbols::lookup(call_addr) {
+        write!(out, " {}+{:#x}", name, offset + 1)?;
+    }
+    return writeln!(out);
+}
+
+/// Writes the backtrace starting from the frame that `rbp` points to.
+pub fn write_backtrace(out: &mut impl Write, rbp: u64) -> fmt::Result
+{
+    if !symbols::is_available() {
+        writeln!(out, "  (no symbols, run the kernel through cargo or tools/embed_symbols.py to get function names)")?;
+    }
+
+    let mut idx = 0;
+    let mut res = Ok(());
+    walk_stack(rbp, |ret_addr| {
+        if res.is_ok() { res = write_frame(out, idx, ret_addr); }
+        idx += 1;
+    });
+    return res;
+}
@@ -0,0 +1,40 @@
+// Virtual consoles. Each one has its own screen (see vga_buffer) and
+// its own tty, and every task is bound to one of them. Only the active
+// console is shown on the VGA and receives keyboard input.
+
+use crate::vga_buffer;
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+pub const NUM_CONSOLES: usize = 6;
+
+// Console shown on screen
+static ACTIVE: AtomicUsize = AtomicUsize::new(0);
+// Console of the task that's running, which is where print! goes
+static CURRENT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn active() -> usize
+{
+    return ACTIVE.load(Ordering::Relaxed);
+}
+
+pub fn current() -> usize
+{
+    return CURRENT.load(Ordering::Relaxed);
+}
+
+/// Called by the scheduler when it switches to a task.
+pub fn set_current(console: usize)
+{
+    CURRENT.store(console, Ordering::Relaxed);
+}
+
+/// Shows another console on screen and sends keyboard input to it.
+pub fn switch_to(console: usize)
+{
+    if console >= NUM_CONSOLES { return; }
+
+    let old = ACTIVE.swap(console, Ordering::Relaxed);
+    if old != console {
+        vga_buffer::switch_screen(old, console);
+    }
+}
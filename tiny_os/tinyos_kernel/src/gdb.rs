@@ -0,0 +1,449 @@
+// GDB remote stub on COM2. When the debugger sends something, or a
+// breakpoint or single step trap is hit while it's attached, the whole
+// system stops and the stub talks to GDB until it says to continue.
+// Registers are the ones of the interrupted code (normally the current
+// task), and memory is accessed through the active page table.
+//
+// With QEMU: -serial stdio -serial tcp::1234,server,nowait
+// and then in gdb: target remote :1234
+
+use crate::memory;
+use crate::process::Context;
+use core::sync::atomic::{AtomicBool, Ordering};
+use lazy_static::lazy_static;
+use spin::Mutex;
+use uart_16550::SerialPort;
+use x86_64::instructions::port::Port;
+
+pub const COM2_BASE: u16 = 0x2F8;
+
+// Signal numbers reported to GDB
+const SIGINT:  u8 = 2;
+const SIGTRAP: u8 = 5;
+
+const TRAP_FLAG: u64 = 1 << 8;
+const CTRL_C: u8 = 0x03;
+
+const MAX_PACKET_SIZE: usize = 4096;
+
+lazy_static!
+{
+    static ref SERIAL2: Mutex<SerialPort> =
+    {
+        // This also enables the "data received" interrupt
+        let mut serial_port = unsafe { SerialPort::new(COM2_BASE) };
+        serial_port.init();
+        Mutex::new(serial_port)
+    };
+}
+
+// Set once GDB has talked to the stub, until it detaches
+static ATTACHED: AtomicBool = AtomicBool::new(false);
+
+pub fn init()
+{
+    lazy_static::initialize(&SERIAL2);
+}
+
+pub fn is_attached() -> bool
+{
+    return ATTACHED.load(Ordering::Relaxed);
+}
+
+// Serial I/O. Everything is polled, interrupts are off while the stub runs.
+
+fn data_ready() -> bool
+{
+    const DATA_READY: u8 = 1 << 0;
+    let mut line_status: Port<u8> = Port::new(COM2_BASE + 5);
+    return unsafe { line_status.read() } & DATA_READY != 0;
+}
+
+// Kept out of the stack, which is small in interrupt handlers
+struct Buffers
+{
+    packet: [u8; MAX_PACKET_SIZE],
+    reply: [u8; MAX_PACKET_SIZE],
+}
+
+static BUFFERS: Mutex<Buffers> = Mutex::new(Buffers {
+    packet: [0; MAX_PACKET_SIZE],
+    reply: [0; MAX_PACKET_SIZE],
+});
+
+struct Connection<'a>
+{
+    port: &'a mut SerialPort,
+    reply: &'a mut [u8; MAX_PACKET_SIZE],
+    reply_len: usize,
+}
+
+impl Connection<'_>
+{
+    // Receives a packet into `buf` and returns its length
+    fn receive_packet(&mut self, buf: &mut [u8; MAX_PACKET_SIZE]) -> usize
+    {
+        loop
+        {
+            // Skip acks and whatever else comes before the start of a packet
+            while self.port.receive() != b'$' {}
+
+            let mut len = 0;
+            let mut checksum: u8 = 0;
+            loop
+            {
+                let byte = self.port.receive();
+                if byte == b'#' { break; }
+                if len < MAX_PACKET_SIZE { buf[len] = byte; len += 1; }
+                checksum = checksum.wrapping_add(byte);
+            }
+
+            let high = hex_digit(self.port.receive());
+            let low = hex_digit(self.port.receive());
+            if high.zip(low).map(|(h, l)| h << 4 | l) == Some(checksum)
+            {
+                self.port.send_raw(b'+');
+                return len;
+            }
+
+            self.port.send_raw(b'-');
+        }
+    }
+
+    // Sends the reply that's been built, until GDB acknowledges it
+    fn send_reply(&mut self)
+    {
+        loop
+        {
+            self.port.send_raw(b'$');
+            let mut checksum: u8 = 0;
+            for &byte in &self.reply[..self.reply_len]
+            {
+                self.port.send_raw(byte);
+                checksum = checksum.wrapping_add(byte);
+            }
+            self.port.send_raw(b'#');
+            self.port.send_raw(HEX_DIGITS[(checksum >> 4) as usize]);
+            self.port.send_raw(HEX_DIGITS[(checksum & 0xF) as usize]);
+
+            match self.port.receive()
+            {
+                b'-' => continue,
+                _ => break,
+            }
+        }
+
+        self.reply_len = 0;
+    }
+
+    fn push(&mut self, bytes: &[u8])
+    {
+        for &byte in bytes
+        {
+            if self.reply_len == MAX_PACKET_SIZE { return; }
+            self.reply[self.reply_len] = byte;
+            self.reply_len += 1;
+        }
+    }
+
+    fn push_hex_byte(&mut self, byte: u8)
+    {
+        self.push(&[HEX_DIGITS[(byte >> 4) as usize], HEX_DIGITS[(byte & 0xF) as usize]]);
+    }
+
+    // Registers are sent in target (little endian) byte order
+    fn push_hex_le(&mut self, value: u64, size: usize)
+    {
+        for byte in &value.to_le_bytes()[..size] {
+            self.push_hex_byte(*byte);
+        }
+    }
+}
+
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+fn hex_digit(c: u8) -> Option<u8>
+{
+    return match c
+    {
+        b'0'..=b'9' => Some(c - b'0'),
+        b'a'..=b'f' => Some(c - b'a' + 10),
+        b'A'..=b'F' => Some(c - b'A' + 10),
+        _ => None,
+    };
+}
+
+fn parse_hex(s: &[u8]) -> Option<u64>
+{
+    if s.is_empty() { return None; }
+
+    let mut res: u64 = 0;
+    for &c in s {
+        res = res.checked_mul(16)? | hex_digit(c)? as u64;
+    }
+    return Some(res);
+}
+
+// Little endian hex, as registers are sent
+fn parse_hex_le(s: &[u8]) -> Option<u64>
+{
+    let mut res: u64 = 0;
+    for (i, pair) in s.chunks(2).enumerate().take(8)
+    {
+        let byte = parse_hex(pair)?;
+        res |= byte << (8 * i);
+    }
+    return Some(res);
+}
+
+// "addr,len" as used by m and M
+fn parse_addr_len(s: &[u8]) -> Option<(u64, usize)>
+{
+    let comma = s.iter().position(|&c| c == b',')?;
+    return Some((parse_hex(&s[..comma])?, parse_hex(&s[comma + 1..])? as usize));
+}
+
+// Registers, in the order of GDB's x86-64 'g' packet
+
+const NUM_REGISTERS: usize = 24;
+
+// Size in bytes of each register in the 'g' packet
+fn register_size(reg: usize) -> usize
+{
+    return if reg <= 16 { 8 } else { 4 };
+}
+
+fn read_register(ctx: &Context, reg: usize) -> u64
+{
+    return match reg
+    {
+        0  => ctx.rax, 1  => ctx.rbx, 2  => ctx.rcx, 3  => ctx.rdx,
+        4  => ctx.rsi, 5  => ctx.rdi, 6  => ctx.rbp, 7  => ctx.rsp,
+        8  => ctx.r8,  9  => ctx.r9,  10 => ctx.r10, 11 => ctx.r11,
+        12 => ctx.r12, 13 => ctx.r13, 14 => ctx.r14, 15 => ctx.r15,
+        16 => ctx.rip,
+        17 => ctx.rflags,
+        18 => ctx.cs,
+        19 => ctx.ss,
+        // ds, es, fs and gs aren't saved, and are the same as ss anyway
+        _  => ctx.ss,
+    };
+}
+
+fn write_register(ctx: &mut Context, reg: usize, value: u64)
+{
+    match reg
+    {
+        0  => ctx.rax = value, 1  => ctx.rbx = value, 2  => ctx.rcx = value, 3  => ctx.rdx = value,
+        4  => ctx.rsi = value, 5  => ctx.rdi = value, 6  => ctx.rbp = value, 7  => ctx.rsp = value,
+        8  => ctx.r8  = value, 9  => ctx.r9  = value, 10 => ctx.r10 = value, 11 => ctx.r11 = value,
+        12 => ctx.r12 = value, 13 => ctx.r13 = value, 14 => ctx.r14 = value, 15 => ctx.r15 = value,
+        16 => ctx.rip = value,
+        17 => ctx.rflags = value,
+        // Changing segments would only make the return from the interrupt fault
+        _  => {},
+    }
+}
+
+// Memory
+
+fn read_memory(addr: u64) -> Option<u8>
+{
+    let ptr = memory::physical_ptr(addr)?;
+    return Some(unsafe { ptr.read_volatile() });
+}
+
+fn write_memory(addr: u64, value: u8) -> bool
+{
+    match memory::physical_ptr(addr)
+    {
+        Some(ptr) => { unsafe { ptr.write_volatile(value) }; return true; }
+        None => return false,
+    }
+}
+
+// Stub
+
+/// Called on a breakpoint (int3) or a debug exception. Returns false if
+/// GDB isn't attached, in which case the exception isn't ours to handle.
+pub fn handle_trap(ctx: &mut Context) -> bool
+{
+    if !is_attached() { return false; }
+
+    // Either a single step finished or a breakpoint was hit
+    ctx.rflags &= !TRAP_FLAG;
+    run_stub(ctx, SIGTRAP, true);
+    return true;
+}
+
+/// Called on the COM2 interrupt, i.e. when GDB sends something
+/// while the system is running (to attach, or Ctrl-C to stop it).
+pub fn handle_interrupt(ctx: &mut Context)
+{
+    if !data_ready() { return; }
+
+    if !is_attached()
+    {
+        // Just attaching, GDB will ask why we stopped
+        ATTACHED.store(true, Ordering::Relaxed);
+        run_stub(ctx, SIGINT, false);
+        return;
+    }
+
+    let byte = SERIAL2.lock().receive();
+    if byte == CTRL_C {
+        run_stub(ctx, SIGINT, true);
+    }
+}
+
+// Talks to GDB until it resumes execution. GDB waits for a stop reply
+// after a continue, a step or Ctrl-C, but asks for it when attaching.
+fn run_stub(ctx: &mut Context, signal: u8, send_stop_reply: bool)
+{
+    let mut port = SERIAL2.lock();
+    let mut buffers = BUFFERS.lock();
+    let Buffers { packet: packet_buf, reply } = &mut *buffers;
+    let mut conn = Connection { port: &mut port, reply, reply_len: 0 };
+
+    if send_stop_reply
+    {
+        conn.push(b"S");
+        conn.push_hex_byte(signal);
+        conn.send_reply();
+    }
+
+    loop
+    {
+        let len = conn.receive_packet(packet_buf);
+        let packet = &packet_buf[..len];
+        if packet.is_empty() { conn.send_reply(); continue; }
+
+        let (command, args) = (packet[0], &packet[1..]);
+        match command
+        {
+            b'?' =>
+            {
+                conn.push(b"S");
+                conn.push_hex_byte(signal);
+            }
+            b'g' =>
+            {
+                for reg in 0..NUM_REGISTERS {
+                    conn.push_hex_le(read_register(ctx, reg), register_size(reg));
+                }
+            }
+            b'G' =>
+            {
+                let mut pos = 0;
+                for reg in 0..NUM_REGISTERS
+                {
+                    let end = pos + 2 * register_size(reg);
+                    if end > args.len() { break; }
+                    if let Some(value) = parse_hex_le(&args[pos..end]) {
+                        write_register(ctx, reg, value);
+                    }
+                    pos = end;
+                }
+                conn.push(b"OK");
+            }
+            b'p' =>
+            {
+                match parse_hex(args).map(|reg| reg as usize)
+                {
+                    Some(reg) if reg < NUM_REGISTERS => conn.push_hex_le(read_register(ctx, reg), register_size(reg)),
+                    _ => conn.push(b"E01"),
+                }
+            }
+            b'P' =>
+            {
+                let eq = args.iter().position(|&c| c == b'=').unwrap_or(args.len());
+                let reg = parse_hex(&args[..eq]).map(|reg| reg as usize);
+                let value = args.get(eq + 1..).and_then(parse_hex_le);
+                match (reg, value)
+                {
+                    (Some(reg), Some(value)) if reg < NUM_REGISTERS => { write_register(ctx, reg, value); conn.push(b"OK"); }
+                    _ => conn.push(b"E01"),
+                }
+            }
+            b'm' =>
+            {
+                match parse_addr_len(args)
+                {
+                    Some((addr, len)) =>
+                    {
+                        let len = core::cmp::min(len, MAX_PACKET_SIZE / 2);
+                        for i in 0..len
+                        {
+                            match read_memory(addr.wrapping_add(i as u64))
+                            {
+                                Some(byte) => conn.push_hex_byte(byte),
+                                None => break,
+                            }
+                        }
+                        // Nothing readable at all is an error
+                        if conn.reply_len == 0 && len > 0 { conn.push(b"E14"); }
+                    }
+                    None => conn.push(b"E01"),
+                }
+            }
+            b'M' =>
+            {
+                let colon = args.iter().position(|&c| c == b':').unwrap_or(args.len());
+                let data = args.get(colon + 1..).unwrap_or(&[]);
+                match parse_addr_len(&args[..colon])
+                {
+                    Some((addr, len)) if data.len() >= 2 * len =>
+                    {
+                        let mut ok = true;
+                        for i in 0..len
+                        {
+                            let byte = parse_hex(&data[2 * i..2 * i + 2]).unwrap_or(0) as u8;
+                            ok &= write_memory(addr.wrapping_add(i as u64), byte);
+                        }
+                        conn.push(if ok { &b"OK"[..] } else { &b"E14"[..] });
+                    }
+                    _ => conn.push(b"E01"),
+                }
+            }
+            b'c' | b's' =>
+            {
+                if let Some(addr) = parse_hex(args) {
+                    ctx.rip = addr;
+                }
+                if command == b's' { ctx.rflags |= TRAP_FLAG; } else { ctx.rflags &= !TRAP_FLAG; }
+                // No reply until we stop again
+                return;
+            }
+            b'D' =>
+            {
+                ctx.rflags &= !TRAP_FLAG;
+                ATTACHED.store(false, Ordering::Relaxed);
+                conn.push(b"OK");
+                conn.send_reply();
+                return;
+            }
+            // Kill. There's no one to reply to, just let things go on
+            b'k' =>
+            {
+                ctx.rflags &= !TRAP_FLAG;
+                ATTACHED.store(false, Ordering::Relaxed);
+                return;
+            }
+            b'H' => conn.push(b"OK"),
+            b'q' =>
+            {
+                if args.starts_with(b"Supported") {
+                    conn.push(b"PacketSize=1000");
+                } else if args.starts_with(b"Attached") {
+                    conn.push(b"1");
+                } else if args.starts_with(b"C") {
+                    conn.push(b"QC1");
+                }
+            }
+            // Unsupported packets get an empty reply
+            _ => {},
+        }
+
+        conn.send_reply();
+    }
+}
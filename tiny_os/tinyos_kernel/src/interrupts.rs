@@ -1,10 +1,12 @@
 
-use crate::{gdt, hlt_loop, print, println, process, interrupts, memory};
+use crate::{error, warn};
+use crate::{backtrace, console, gdb, gdt, hlt_loop, print, println, process, profile, interrupts, keyboard, log, memory, serial, signal, strace, time, tty, user_tests};
 use lazy_static::lazy_static;
 use pic8259::ChainedPics;
 use spin;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame, PageFaultErrorCode, HandlerFunc};
 use x86_64::PrivilegeLevel;
+use x86_64::structures::paging::PageTableFlags;
 use core::arch::{naked_asm, asm};
 use alloc::{vec::Vec};
 
@@ -17,6 +19,8 @@ pub enum InterruptIndex
 {
     Timer = PIC_1_OFFSET,
     Keyboard,
+    Serial2 = PIC_1_OFFSET + 3,  // COM2, used by the GDB stub
+    Serial1 = PIC_1_OFFSET + 4,  // COM1
     Syscall = 0x80,
 }
 
@@ -38,7 +42,6 @@ lazy_static!
 {
     static ref IDT: InterruptDescriptorTable = {
         let mut idt = InterruptDescriptorTable::new();
-        idt.breakpoint.set_handler_fn(breakpoint_handler);
         idt.page_fault.set_handler_fn(page_fault_handler);
         unsafe {
             idt.double_fault
@@ -60,10 +63,34 @@ lazy_static!
                     extern "x86-interrupt" fn(InterruptStackFrame)
                 >(syscall_interrupt_handler)
             ).set_privilege_level(PrivilegeLevel::Ring3);
+
+            // These save the whole context, which the GDB stub can look at and change.
+            // int3 can be used from user mode.
+            idt.breakpoint.set_handler_fn(
+                core::mem::transmute::<
+                    extern "sysv64" fn(),
+                    extern "x86-interrupt" fn(InterruptStackFrame)
+                >(breakpoint_entry)
+            ).set_privilege_level(PrivilegeLevel::Ring3);
+
+            idt.debug.set_handler_fn(
+                core::mem::transmute::<
+                    extern "sysv64" fn(),
+                    extern "x86-interrupt" fn(InterruptStackFrame)
+                >(debug_entry)
+            );
+
+            idt[InterruptIndex::Serial2.as_usize()].set_handler_fn(
+                core::mem::transmute::<
+                    extern "sysv64" fn(),
+                    extern "x86-interrupt" fn(InterruptStackFrame)
+                >(serial2_entry)
+            );
         }
 
         //idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Serial1.as_usize()].set_handler_fn(serial_interrupt_handler);
         idt
     };
 }
@@ -78,9 +105,59 @@ pub unsafe fn notify_end_of_timer_interrupt()
     unsafe { PICS.lock().notify_end_of_interrupt(InterruptIndex::Timer.as_u8()) };
 }
 
-extern "x86-interrupt" fn breakpoint_handler(stack_frame: InterruptStackFrame)
+/// Defines an interrupt entry point that saves the whole context (like the
+/// timer and syscall ones) and passes it to `$handler`, which can change it.
+/// Only for interrupts without an error code.
+macro_rules! context_interrupt_entry {
+    ($name:ident, $handler:ident) => {
+        #[naked]
+        extern "sysv64" fn $name()
+        {
+            unsafe
+            {
+                naked_asm!("\
+                push r15; push r14; push r13; push r12; push r11; push r10; push r9;\
+                push r8; push rdi; push rsi; push rdx; push rcx; push rbx; push rax; push rbp;\
+                mov rdi, rsp
+                sub rsp, 0x400
+                call {handler}
+                add rsp, 0x400
+                pop rbp; pop rax; pop rbx; pop rcx; pop rdx; pop rsi; pop rdi; pop r8; pop r9;\
+                pop r10; pop r11; pop r12; pop r13; pop r14; pop r15;\
+                iretq
+                ", handler = sym $handler);
+            }
+        }
+    };
+}
+
+context_interrupt_entry!(breakpoint_entry, breakpoint_handler);
+context_interrupt_entry!(debug_entry, debug_handler);
+context_interrupt_entry!(serial2_entry, serial2_interrupt_handler);
+
+extern "sysv64" fn breakpoint_handler(ctx: *mut process::Context)
+{
+    let ctx = unsafe { &mut *ctx };
+    if gdb::handle_trap(ctx) { return; }
+
+    warn!("EXCEPTION: BREAKPOINT at {}", backtrace::Symbolized(ctx.rip));
+}
+
+// Single steps end up here
+extern "sysv64" fn debug_handler(ctx: *mut process::Context)
 {
-    println!("EXCEPTION: BREAKPOINT\n{:#?}", stack_frame);
+    let ctx = unsafe { &mut *ctx };
+    if gdb::handle_trap(ctx) { return; }
+
+    warn!("EXCEPTION: DEBUG at {}", backtrace::Symbolized(ctx.rip));
+    // Don't keep single stepping without a debugger
+    ctx.rflags &= !(1 << 8);
+}
+
+extern "sysv64" fn serial2_interrupt_handler(ctx: *mut process::Context)
+{
+    unsafe { PICS.lock().notify_end_of_interrupt(InterruptIndex::Serial2.as_u8()) };
+    gdb::handle_interrupt(unsafe { &mut *ctx });
 }
 
 extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame,
@@ -88,16 +165,16 @@ extern "x86-interrupt" fn page_fault_handler(stack_frame: InterruptStackFrame,
 {
     use x86_64::registers::control::Cr2;
 
-    println!("EXCEPTION: PAGE FAULT");
-    println!("Accessed Address: {:?}", Cr2::read());
-    println!("Error Code: {:?}", error_code);
-    println!("{:#?}", stack_frame);
-    hlt_loop();
+    error!("EXCEPTION: PAGE FAULT");
+    error!("Accessed Address: {:?}", Cr2::read());
+    error!("Error Code: {:?}", error_code);
+    error!("{:#?}", stack_frame);
+    panic!("Page fault at {}", backtrace::Symbolized(stack_frame.instruction_pointer.as_u64()));
 }
 
 extern "x86-interrupt" fn double_fault_handler(stack_frame: InterruptStackFrame, _error_code: u64) -> !
 {
-    panic!("EXCEPTION: DOUBLE FAULT\n{:#?}", stack_frame);
+    panic!("EXCEPTION: DOUBLE FAULT at {}\n{:#?}", backtrace::Symbolized(stack_frame.instruction_pointer.as_u64()), stack_frame);
 }
 
 #[naked]
@@ -111,7 +188,11 @@ extern "sysv64" fn timer_interrupt_context_switch_handler()
         push r8; push rdi; push rsi; push rdx; push rcx; push rbx; push rax; push rbp;\
         mov rdi, rsp          // Pass the context ptr as first argument (stack array)
         sub rsp, 0x400        // Allocate some stack space
-        jmp {context_switch}
+        call {context_switch} // Only returns if the interrupted code should keep running
+        add rsp, 0x400
+        pop rbp; pop rax; pop rbx; pop rcx; pop rdx; pop rsi; pop rdi; pop r8; pop r9;\
+        pop r10; pop r11; pop r12; pop r13; pop r14; pop r15;\
+        iretq
         ", context_switch = sym context_switch);
     }
 }
@@ -119,9 +200,10 @@ extern "sysv64" fn timer_interrupt_context_switch_handler()
 pub unsafe extern "sysv64" fn context_switch(ctx: *const process::Context)
 {
     unsafe {
-        process::SCHEDULER.save_current_context(ctx);
         interrupts::notify_end_of_timer_interrupt();
-        process::SCHEDULER.run_next_task();
+        time::on_timer_tick();
+        profile::on_timer_tick(&*ctx);
+        process::SCHEDULER.on_timer_tick(ctx);
     }
 }
 
@@ -130,90 +212,79 @@ extern "sysv64" fn syscall_interrupt_handler()
 {
     unsafe {
         naked_asm!("\
-        push rcx        // backup registers for sysretq
-        push r11
-        push rbp        // save callee-saved registers
-        push rbx
-        push r12
-        push r13
-        push r14
-        push r15
-        mov rbp, rsp    // save rsp
+        // Save the whole user context, so that syscalls
+        // can block and resume the task later on.
+        push r15; push r14; push r13; push r12; push r11; push r10; push r9;\
+        push r8; push rdi; push rsi; push rdx; push rcx; push rbx; push rax; push rbp;\
+        mov rdi, rsp    // Pass the context ptr as first argument
         sub rsp, 0x400  // make some room in the stack
-        mov rcx, r10    // move fourth syscall arg to rcx which is the fourth argument register in sysv64
-        mov r8, rax     // move syscall number to the 5th argument register
-        call {syscall_alloc_stack} // call the handler with the syscall number in r8
-        mov rsp, rbp    // restore rsp from rbp
-        pop r15         // restore callee-saved registers
-        pop r14
-        pop r13
-        pop r12
-        pop rbx
-        pop rbp         // restore stack and registers for sysretq
-        pop r11
-        pop rcx
+        call {syscall_alloc_stack}
+        add rsp, 0x400
+        pop rbp; pop rax; pop rbx; pop rcx; pop rdx; pop rsi; pop rdi; pop r8; pop r9;\
+        pop r10; pop r11; pop r12; pop r13; pop r14; pop r15;\
         iretq           // use the specialized instruction to return from the interrupt (to user mode)",
         syscall_alloc_stack = sym syscall_alloc_stack);
     }
 }
 
-unsafe extern "sysv64" fn syscall_alloc_stack(arg0: u64, arg1: u64, arg2: u64, arg3: u64, syscall: u64) -> u64
+// Syscall arguments are passed in rdi, rsi, rdx, r10 and the
+// syscall number in rax, which is also where the result goes.
+unsafe extern "sysv64" fn syscall_alloc_stack(ctx: *mut process::Context)
 {
-    //println!("syscall: {}, {}, {}, {}, {}", arg0, arg1, arg2, arg3, syscall);
+    let (arg0, arg1, arg2, arg3, syscall) = unsafe {
+        ((*ctx).rdi, (*ctx).rsi, (*ctx).rdx, (*ctx).r10, (*ctx).rax)
+    };
+
+    let traced = process::SCHEDULER.current_traced_pid();
+    if let Some(pid) = traced.filter(|_| strace::never_returns(syscall)) {
+        strace::log_syscall_entry(pid, syscall, [arg0, arg1, arg2, arg3]);
+    }
+    let start_tsc = time::read_tsc();
 
     /*
     let syscall_stack: Vec<u8> = Vec::with_capacity(0x10000);
     let stack_ptr = syscall_stack.as_ptr();
     */
-    let retval = handle_syscall_with_temp_stack(arg0, arg1, arg2, arg3, syscall);
+    process::SCHEDULER.enter_kernel();
+    let retval = handle_syscall_with_temp_stack(ctx, arg0, arg1, arg2, arg3, syscall);
+    process::SCHEDULER.leave_kernel();
     //drop(syscall_stack);
 
+    if let Some(pid) = traced.filter(|_| !strace::never_returns(syscall)) {
+        strace::log_syscall(pid, syscall, [arg0, arg1, arg2, arg3], retval, time::read_tsc() - start_tsc);
+    }
+
     unsafe
     {
+        (*ctx).rax = retval;
         PICS.lock().notify_end_of_interrupt(InterruptIndex::Syscall.as_u8());
+        process::SCHEDULER.deliver_signals(ctx);
     }
-    return retval;
-}
-
-lazy_static! {
-    pub static ref STDIN: spin::Mutex<Vec::<u8>> = spin::Mutex::new(Vec::new());
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame)
 {
-    use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
-    use spin::Mutex;
     use x86_64::instructions::port::Port;
 
-    lazy_static!
-    {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Us104Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(
-                ScancodeSet1::new(),
-                layouts::Us104Key,
-                HandleControl::Ignore
-            ));
-    }
-
-    let mut keyboard = KEYBOARD.lock();
     let mut port = Port::new(0x60);
-
     let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode)
+    keyboard::handle_scancode(scancode);
+
+    unsafe
     {
-        if let Some(key) = keyboard.process_keyevent(key_event)
-        {
-            match key {
-                DecodedKey::Unicode(character) => STDIN.lock().push(character as u8),
-                DecodedKey::RawKey(key) => {},
-            }
-        }
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
     }
+}
+
+extern "x86-interrupt" fn serial_interrupt_handler(_stack_frame: InterruptStackFrame)
+{
+    serial::handle_interrupt();
 
     unsafe
     {
         PICS.lock()
-            .notify_end_of_interrupt(InterruptIndex::Keyboard.as_u8());
+            .notify_end_of_interrupt(InterruptIndex::Serial1.as_u8());
     }
 }
 
@@ -231,10 +302,34 @@ pub enum Syscall
     GetArg0 = 6,
     Exit = 7,
     Shutdown = 8,
+    ListProcesses = 9,
+    Kill = 10,
+    Signal = 11,
+    SigProcMask = 12,
+    SigReturn = 13,
+    GetPid = 14,
+    Wait = 15,
+    SetForeground = 16,
+    Read = 17,
+    Ioctl = 18,
+    SetKeymap = 19,
+    GetKeymap = 20,
+    Dmesg = 21,
+    TestReport = 22,
+    MemoryMap = 23,
+    Trace = 24,
+    Profile = 25,
+    Brk = 26,
+    Sbrk = 27,
 }
 
+// Flags of the create_task syscall
+// NOTE: This should be kept up to date along with its
+// counterpart in the usercode library.
+pub const CREATE_TRACED: u64 = 1 << 0;
+
 #[inline(never)]
-extern "sysv64" fn handle_syscall_with_temp_stack(arg0: u64, arg1: u64, arg2: u64, arg3: u64, syscall: u64) -> u64
+extern "sysv64" fn handle_syscall_with_temp_stack(ctx: *mut process::Context, arg0: u64, arg1: u64, arg2: u64, arg3: u64, syscall: u64) -> u64
 {
 /*    let old_stack: *const u8;
     unsafe {
@@ -253,14 +348,33 @@ extern "sysv64" fn handle_syscall_with_temp_stack(arg0: u64, arg1: u64, arg2: u6
         x if x == Syscall::Print as u64 => sys_print(arg0, arg1),
         x if x == Syscall::PrintNum as u64 => sys_print_num(arg0),
         x if x == Syscall::PrintChar as u64 => sys_print_char(arg0),
-        x if x == Syscall::ReadChar as u64 => sys_read_char(),
-        x if x == Syscall::CreateTask as u64 => sys_create_task(arg0, arg1),
+        x if x == Syscall::ReadChar as u64 => sys_read_char(ctx),
+        x if x == Syscall::CreateTask as u64 => sys_create_task(arg0, arg1, arg2),
         x if x == Syscall::GetArg0 as u64 => sys_get_arg_0(),
         x if x == Syscall::Exit as u64 => sys_exit(arg0),
         x if x == Syscall::Shutdown as u64 => sys_shutdown(),
+        x if x == Syscall::ListProcesses as u64 => sys_list_processes(arg0, arg1),
+        x if x == Syscall::Kill as u64 => sys_kill(arg0, arg1),
+        x if x == Syscall::Signal as u64 => sys_signal(arg0, arg1, arg2),
+        x if x == Syscall::SigProcMask as u64 => sys_sigprocmask(arg0, arg1),
+        x if x == Syscall::SigReturn as u64 => sys_sigreturn(ctx),
+        x if x == Syscall::GetPid as u64 => sys_get_pid(),
+        x if x == Syscall::Wait as u64 => sys_wait(ctx, arg0, arg1),
+        x if x == Syscall::SetForeground as u64 => sys_set_foreground(arg0),
+        x if x == Syscall::Read as u64 => sys_read(ctx, arg0, arg1),
+        x if x == Syscall::Ioctl as u64 => sys_ioctl(arg0, arg1),
+        x if x == Syscall::SetKeymap as u64 => sys_set_keymap(arg0, arg1),
+        x if x == Syscall::GetKeymap as u64 => sys_get_keymap(arg0, arg1),
+        x if x == Syscall::Dmesg as u64 => sys_dmesg(arg0, arg1),
+        x if x == Syscall::TestReport as u64 => sys_test_report(arg0, arg1, arg2),
+        x if x == Syscall::MemoryMap as u64 => sys_memory_map(arg0, arg1),
+        x if x == Syscall::Trace as u64 => sys_trace(arg0, arg1),
+        x if x == Syscall::Profile as u64 => sys_profile(arg0),
+        x if x == Syscall::Brk as u64 => sys_brk(arg0),
+        x if x == Syscall::Sbrk as u64 => sys_sbrk(arg0),
         //0x1338 => sys_getline(arg0, arg1),
         //0x8EAD => sys_read(arg0, arg1, arg2),
-        _ => syscall_unhandled(),
+        _ => syscall_unhandled(syscall),
     };
 
 /*
@@ -287,13 +401,43 @@ extern "sysv64" fn handle_syscall_with_temp_stack(arg0: u64, arg1: u64, arg2: u6
 // for security we should sanitize all user arguments
 // to make sure that they're actually userspace addresses
 
+// Bytes the kernel reads for a task, like user_slice_mut.
+fn user_slice<'a>(ptr: u64, len: u64) -> Option<&'a [u8]>
+{
+    if !memory::is_user_range(ptr, len, false) { return None; }
+    if len == 0 { return Some(&[]); }
+
+    return Some(unsafe { core::slice::from_raw_parts(ptr as *const u8, len as usize) });
+}
+
+// A buffer of `len` values the kernel writes to for a task. Returns None
+// unless it's all in user space and mapped writable, so that a task can't
+// make the kernel overwrite kernel memory, or fault.
+fn user_slice_mut<'a, T>(ptr: u64, len: u64) -> Option<&'a mut [T]>
+{
+    let size = len.checked_mul(core::mem::size_of::<T>() as u64)?;
+    if !memory::is_user_range(ptr, size, true) { return None; }
+    if len == 0 { return Some(&mut []); }
+    if ptr % core::mem::align_of::<T>() as u64 != 0 { return None; }
+
+    return Some(unsafe { core::slice::from_raw_parts_mut(ptr as *mut T, len as usize) });
+}
+
 fn sys_print(str_ptr: u64, str_len: u64) -> u64
 {
+    let bytes = match user_slice(str_ptr, str_len)
+    {
+        Some(bytes) => bytes,
+        None => return 1,
+    };
 
-    unsafe {
-        let string = core::slice::from_raw_parts(str_ptr as *const u8, str_len as usize);
-        let string = core::str::from_utf8_unchecked(string);
-        print!("{}", string);
+    // Invalid UTF-8 is shown as replacement characters
+    for chunk in bytes.utf8_chunks()
+    {
+        print!("{}", chunk.valid());
+        if !chunk.invalid().is_empty() {
+            print!("{}", char::REPLACEMENT_CHARACTER);
+        }
     }
 
     return 0;
@@ -315,38 +459,214 @@ fn sys_print_char(c: u64) -> u64
     }
 }
 
-fn sys_read_char() -> u64
+// Returns the Unicode code point of the next character typed, or 0 if there's none yet.
+fn sys_read_char(ctx: *mut process::Context) -> u64
 {
-    let mut stdin = STDIN.lock();
-    if stdin.len() <= 0 { return 0; }
-    return stdin.remove(0) as u64;
+    if let Some(c) = tty::current().lock().read_char() {
+        return c as u64;
+    }
+
+    // Signals are delivered on the way out of this syscall,
+    // so don't go to sleep if there's any.
+    if process::SCHEDULER.current_has_pending_signals() { return 0; }
+
+    // Nothing to read yet. Sleep until the next key press, at which
+    // point the task sees a 0 (no input) and asks again.
+    unsafe
+    {
+        (*ctx).rax = 0;
+        process::SCHEDULER.block_current_task(ctx, process::WaitReason::Input(console::current()));
+    }
+    return 0;
 }
 
-fn sys_create_task(task_name_ptr: u64, task_name_len: u64) -> u64
-{
-    unsafe {
-        let string = core::slice::from_raw_parts(task_name_ptr as *const u8, task_name_len as usize);
-        let string = core::str::from_utf8_unchecked(string);
+/// Returned by read when there's nothing to read yet.
+pub const READ_PENDING: u64 = u64::MAX - 1;
 
-        if string == "shell"
+// Reads from the tty, returns the number of bytes read or 0 on end of
+// file. When woken up by a signal it returns READ_PENDING, and the
+// caller should ask again.
+fn sys_read(ctx: *mut process::Context, buf_ptr: u64, buf_len: u64) -> u64
+{
+    {
+        let mut tty = tty::current().lock();
+        if tty.can_read()
         {
-            let kern_mem_info = memory::KERNEL_MEM_INFO.lock();
-            let task = process::create_task(process::USER_PROGRAM_SHELL, kern_mem_info.phys_offset, kern_mem_info.kernel_page_table_phys_addr, 0);
-            process::SCHEDULER.schedule_task(task.unwrap());
-            return 1;
+            return match user_slice_mut::<u8>(buf_ptr, buf_len)
+            {
+                Some(buf) => tty.read(buf) as u64,
+                None => 0,
+            };
         }
-        else if string == "rec_fib"
+    }
+
+    if process::SCHEDULER.current_has_pending_signals() { return READ_PENDING; }
+
+    unsafe
+    {
+        (*ctx).rax = READ_PENDING;
+        process::SCHEDULER.block_current_task(ctx, process::WaitReason::Input(console::current()));
+    }
+    return READ_PENDING;
+}
+
+// Gets or sets the tty mode flags.
+fn sys_ioctl(request: u64, arg: u64) -> u64
+{
+    let mut tty = tty::current().lock();
+    match request
+    {
+        tty::TTY_GET_MODE => return tty.mode(),
+        tty::TTY_SET_MODE => { tty.set_mode(arg); return 0; }
+        _ => return u64::MAX,
+    }
+}
+
+// Returns 1 if the layout was switched, 0 if there's no layout with that name.
+fn sys_set_keymap(name_ptr: u64, name_len: u64) -> u64
+{
+    let name = match user_slice(name_ptr, name_len).map(core::str::from_utf8)
+    {
+        Some(Ok(name)) => name,
+        _ => return 0,
+    };
+
+    return keyboard::set_layout(name) as u64;
+}
+
+// Copies the name of the current layout into the buffer
+// and returns its length.
+fn sys_get_keymap(buf_ptr: u64, buf_len: u64) -> u64
+{
+    let name = keyboard::layout_name().as_bytes();
+    let len = name.len().min(buf_len as usize);
+    let buf = match user_slice_mut::<u8>(buf_ptr, len as u64)
+    {
+        Some(buf) => buf,
+        None => return 0,
+    };
+
+    buf.copy_from_slice(&name[..len]);
+    return len as u64;
+}
+
+// Copies the most recent kernel log messages into the buffer
+// and returns the number of bytes written.
+fn sys_dmesg(buf_ptr: u64, buf_len: u64) -> u64
+{
+    return match user_slice_mut::<u8>(buf_ptr, buf_len)
+    {
+        Some(buf) => log::read_buffer(buf) as u64,
+        None => 0,
+    };
+}
+
+// Result of a check done by a user space test program. Only kernels built
+// to run the tests take it, others return u64::MAX.
+#[cfg(feature = "user_tests")]
+fn sys_test_report(name_ptr: u64, name_len: u64, passed: u64) -> u64
+{
+    let name = user_slice(name_ptr, name_len).and_then(|name| core::str::from_utf8(name).ok());
+    user_tests::report(name.unwrap_or("?"), passed != 0);
+    return 0;
+}
+
+#[cfg(not(feature = "user_tests"))]
+fn sys_test_report(_name_ptr: u64, _name_len: u64, _passed: u64) -> u64
+{
+    return u64::MAX;
+}
+
+// Returns the pid of the new task, or 0 if it couldn't be created.
+// The flags are CREATE_* values.
+fn sys_create_task(task_name_ptr: u64, task_name_len: u64, flags: u64) -> u64
+{
+    let string = match user_slice(task_name_ptr, task_name_len).map(core::str::from_utf8)
+    {
+        Some(Ok(string)) => string,
+        _ => return 0,
+    };
+
+    let (blob, arg0) = if string == "shell"
+    {
+        (process::USER_PROGRAM_SHELL, 0)
+    }
+    else if string == "rec_fib"
+    {
+        (process::USER_PROGRAM_SHELL, 1)
+    }
+    else if let Some(blob) = user_tests::find_program(string)
+    {
+        (blob, 0)
+    }
+    else
+    {
+        return 0;
+    };
+
+    let task = {
+        let kern_mem_info = memory::KERNEL_MEM_INFO.lock();
+        process::create_task(string, blob, kern_mem_info.phys_offset, kern_mem_info.kernel_page_table_phys_addr, arg0)
+    };
+
+    return match task
+    {
+        Some(mut task) =>
         {
-            let kern_mem_info = memory::KERNEL_MEM_INFO.lock();
-            let task = process::create_task(process::USER_PROGRAM_SHELL, kern_mem_info.phys_offset, kern_mem_info.kernel_page_table_phys_addr, 1);
-            process::SCHEDULER.schedule_task(task.unwrap());
-            return 1;
+            // Set before it's scheduled, so that its first syscall is traced too
+            task.trace_syscalls = flags & CREATE_TRACED != 0;
+            process::SCHEDULER.schedule_task(task)
         }
-        else
+        None => 0,
+    };
+}
+
+// Moves the end of the caller's heap to the address, if it can. Returns
+// where the end is afterwards, so 0 can be passed to find out where it is.
+fn sys_brk(addr: u64) -> u64
+{
+    let phys_offset = memory::KERNEL_MEM_INFO.lock().phys_offset;
+    return process::SCHEDULER.with_current_heap(|heap| {
+        if addr != 0 { heap.set_break(addr, phys_offset); }
+        heap.brk
+    }).unwrap_or(0);
+}
+
+// Moves the end of the caller's heap by a (signed) number of bytes.
+// Returns the previous end, which is where new memory starts, or u64::MAX on error.
+fn sys_sbrk(increment: u64) -> u64
+{
+    let phys_offset = memory::KERNEL_MEM_INFO.lock().phys_offset;
+    return process::SCHEDULER.with_current_heap(|heap| {
+        let old_brk = heap.brk;
+        match old_brk.checked_add_signed(increment as i64)
         {
-            return 0;
+            Some(new_brk) if heap.set_break(new_brk, phys_offset) => old_brk,
+            _ => u64::MAX,
         }
+    }).unwrap_or(u64::MAX);
+}
+
+// Starts, stops or dumps the profiler (see the PROFILE_* commands).
+// Returns the number of samples, or u64::MAX for an unknown command.
+fn sys_profile(command: u64) -> u64
+{
+    match command
+    {
+        profile::PROFILE_START => profile::start(),
+        profile::PROFILE_STOP => profile::stop(),
+        profile::PROFILE_DUMP => { let _ = profile::dump(&mut serial::SerialWriter); }
+        _ => return u64::MAX,
     }
+    return profile::num_samples() as u64;
+}
+
+// Turns syscall tracing on or off for a task (0 is the caller).
+// Returns 1 on success, 0 if there's no such task.
+fn sys_trace(pid: u64, enable: u64) -> u64
+{
+    let pid = if pid == 0 { process::SCHEDULER.current_pid().unwrap_or(0) } else { pid };
+    return process::SCHEDULER.set_tracing(pid, enable != 0) as u64;
 }
 
 fn sys_get_arg_0() -> u64
@@ -358,7 +678,7 @@ fn sys_exit(val: u64) -> u64
 {
     unsafe
     {
-        process::SCHEDULER.remove_current_task();
+        process::SCHEDULER.remove_current_task(val);
         PICS.lock().notify_end_of_interrupt(InterruptIndex::Syscall.as_u8());
         process::SCHEDULER.run_next_task();
     }
@@ -373,7 +693,150 @@ fn sys_shutdown() -> u64
     return 0;
 }
 
-fn syscall_unhandled() -> u64
+// Fills the user buffer with up to max_entries ProcessInfo
+// records, and returns the number of records written.
+fn sys_list_processes(buf_ptr: u64, max_entries: u64) -> u64
+{
+    let processes = process::SCHEDULER.process_list();
+    let count = core::cmp::min(processes.len() as u64, max_entries) as usize;
+    let buf = match user_slice_mut::<process::ProcessInfo>(buf_ptr, count as u64)
+    {
+        Some(buf) => buf,
+        None => return 0,
+    };
+
+    buf.copy_from_slice(&processes[..count]);
+    return count as u64;
+}
+
+// Fills the user buffer with up to max_entries MemoryMapEntry records describing
+// the caller's user space mappings, and returns the number of records written.
+fn sys_memory_map(buf_ptr: u64, max_entries: u64) -> u64
 {
-    panic!("Unhandled syscall!");
+    let phys_offset = memory::KERNEL_MEM_INFO.lock().phys_offset;
+    // We're still in the caller's address space
+    let page_table = unsafe { memory::active_level_4_table_addr() };
+    let buf = match user_slice_mut::<memory::MemoryMapEntry>(buf_ptr, max_entries)
+    {
+        Some(buf) => buf,
+        None => return 0,
+    };
+
+    let mappings = memory::mappings(page_table, phys_offset, 0, memory::USER_SPACE_END);
+    let mut count = 0;
+    for (entry, mapping) in buf.iter_mut().zip(mappings.filter(|m| m.flags.contains(PageTableFlags::USER_ACCESSIBLE)))
+    {
+        *entry = mapping.into();
+        count += 1;
+    }
+
+    return count;
+}
+
+// Returns 1 if the signal was sent, 0 otherwise.
+fn sys_kill(pid: u64, sig: u64) -> u64
+{
+    if sig > u32::MAX as u64 { return 0; }
+    return process::SCHEDULER.send_signal(pid, sig as u32) as u64;
+}
+
+// Installs a signal handler (or SIG_DFL/SIG_IGN). The restorer is the
+// code handlers return to, which should call sigreturn. Returns the
+// previous handler, or u64::MAX on error.
+fn sys_signal(sig: u64, handler: u64, restorer: u64) -> u64
+{
+    if sig >= signal::NUM_SIGNALS as u64 { return u64::MAX; }
+    if handler >= memory::USER_SPACE_END || restorer >= memory::USER_SPACE_END { return u64::MAX; }
+
+    let res = process::SCHEDULER.with_current_signals(|signals| {
+        let is_user_handler = handler != signal::SIG_DFL && handler != signal::SIG_IGN;
+        if is_user_handler && restorer == 0 && signals.restorer == 0 { return None; }
+        signals.set_handler(sig as u32, handler, restorer)
+    });
+
+    return res.flatten().unwrap_or(u64::MAX);
+}
+
+// Changes the set of blocked signals, returns the previous one or u64::MAX on error.
+fn sys_sigprocmask(how: u64, set: u64) -> u64
+{
+    let res = process::SCHEDULER.with_current_signals(|signals| signals.set_blocked(how, set as u32));
+    return res.flatten().map(|old| old as u64).unwrap_or(u64::MAX);
+}
+
+// Returns from a signal handler. The user stack pointer is expected to
+// point at the signal frame, which is where it is once the handler returns
+// into the restorer.
+fn sys_sigreturn(ctx: *mut process::Context) -> u64
+{
+    let ctx = unsafe { &mut *ctx };
+    let frame_addr = ctx.rsp;
+    match unsafe { signal::pop_signal_frame(ctx, frame_addr) }
+    {
+        Some(blocked) =>
+        {
+            process::SCHEDULER.with_current_signals(|signals| { signals.set_blocked(signal::SIG_SETMASK, blocked); });
+            // Keep the value rax had when the signal arrived
+            return ctx.rax;
+        }
+        None =>
+        {
+            // The frame is garbage, there's no sensible way to continue.
+            if let Some(pid) = process::SCHEDULER.current_pid() {
+                process::SCHEDULER.send_signal(pid, signal::SIGKILL);
+            }
+            return 0;
+        }
+    }
+}
+
+fn sys_get_pid() -> u64
+{
+    return process::SCHEDULER.current_pid().unwrap_or(0);
+}
+
+// Waits until the child exits, is killed or stops, and returns its status.
+// Like sys_read_char, this may return WAIT_PENDING when woken up by a
+// signal or with WAIT_NO_HANG, in which case the caller should just ask again.
+fn sys_wait(ctx: *mut process::Context, pid: u64, options: u64) -> u64
+{
+    let status = process::SCHEDULER.take_child_status(pid);
+    if status != process::WAIT_PENDING { return status; }
+    if options & process::WAIT_NO_HANG != 0 { return status; }
+    if process::SCHEDULER.current_has_pending_signals() { return status; }
+
+    unsafe
+    {
+        (*ctx).rax = process::WAIT_PENDING;
+        process::SCHEDULER.block_current_task(ctx, process::WaitReason::Child(pid));
+    }
+    return status;
+}
+
+// Makes the task the one receiving Ctrl-C and Ctrl-Z. Returns 1 on success.
+fn sys_set_foreground(pid: u64) -> u64
+{
+    return process::SCHEDULER.set_foreground(pid) as u64;
+}
+
+/// Returned by syscalls with an unknown number.
+// NOTE: This should be kept up to date along with its
+// counterpart in the usercode library.
+pub const SYSCALL_UNKNOWN: u64 = u64::MAX;
+
+// A task calling something that doesn't exist is its own problem, not the kernel's
+fn syscall_unhandled(syscall: u64) -> u64
+{
+    warn!("Unknown syscall {} from task {:?}.", syscall, process::SCHEDULER.current_pid());
+    return SYSCALL_UNKNOWN;
+}
+
+#[cfg(test)]
+mod tests
+{
+    #[test_case]
+    fn breakpoint_returns()
+    {
+        x86_64::instructions::interrupts::int3();
+    }
 }
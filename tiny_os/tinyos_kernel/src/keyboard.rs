@@ -0,0 +1,355 @@
+// Keyboard decoding. Characters go to the tty encoded as UTF-8, while
+// keys without a character (arrows, function keys, ...) are encoded as
+// the escape sequences a VT100/xterm terminal would send.
+
+use crate::{console, monitor, tty, vga_buffer};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use lazy_static::lazy_static;
+use pc_keyboard::{layouts, DecodedKey, HandleControl, KeyCode, KeyEvent, KeyState, Keyboard, KeyboardLayout, Modifiers, ScancodeSet1};
+use spin::Mutex;
+
+const ESC: u8 = 0x1B;
+
+#[derive(Default, Clone, Copy, Debug)]
+pub struct KeyModifiers
+{
+    pub lshift: bool,
+    pub rshift: bool,
+    pub lctrl: bool,
+    pub rctrl: bool,
+    pub alt: bool,
+}
+
+impl KeyModifiers
+{
+    pub fn shift(&self) -> bool { return self.lshift || self.rshift; }
+    pub fn ctrl(&self)  -> bool { return self.lctrl || self.rctrl; }
+
+    fn update(&mut self, event: &KeyEvent)
+    {
+        let down = event.state != KeyState::Up;
+        match event.code
+        {
+            KeyCode::LShift   => self.lshift = down,
+            KeyCode::RShift   => self.rshift = down,
+            KeyCode::LControl => self.lctrl = down,
+            KeyCode::RControl => self.rctrl = down,
+            KeyCode::LAlt     => self.alt = down,
+            _ => {},
+        }
+    }
+
+    // Modifier parameter used by xterm, e.g. ESC [ 1 ; 5 D is Ctrl+Left.
+    fn xterm_param(&self) -> u8
+    {
+        return 1 + (self.shift() as u8) + 2 * (self.alt as u8) + 4 * (self.ctrl() as u8);
+    }
+}
+
+// Layouts
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LayoutKind
+{
+    Us104,
+    Uk105,
+    De105,
+    It105,
+    Azerty,
+    Dvorak104,
+    DvorakProgrammer104,
+    Colemak,
+    Jis109,
+}
+
+/// Every supported layout along with the name used to select it.
+pub const LAYOUTS: [(&str, LayoutKind); 9] = [
+    ("us",      LayoutKind::Us104),
+    ("uk",      LayoutKind::Uk105),
+    ("de",      LayoutKind::De105),
+    ("it",      LayoutKind::It105),
+    ("fr",      LayoutKind::Azerty),
+    ("dvorak",  LayoutKind::Dvorak104),
+    ("dvp",     LayoutKind::DvorakProgrammer104),
+    ("colemak", LayoutKind::Colemak),
+    ("jp",      LayoutKind::Jis109),
+];
+
+// Index in LAYOUTS of the active layout
+static CUR_LAYOUT: AtomicUsize = AtomicUsize::new(0);
+
+pub fn layout_name() -> &'static str
+{
+    return LAYOUTS[CUR_LAYOUT.load(Ordering::Relaxed)].0;
+}
+
+/// Switches to the layout with the given name. Returns false if there's no such layout.
+pub fn set_layout(name: &str) -> bool
+{
+    match LAYOUTS.iter().position(|&(layout_name, _)| layout_name == name)
+    {
+        Some(idx) => { CUR_LAYOUT.store(idx, Ordering::Relaxed); return true; }
+        None => return false,
+    }
+}
+
+/// Forwards to whatever layout is currently selected, so that
+/// it can be switched without losing the decoder's state.
+pub struct ActiveLayout;
+
+impl KeyboardLayout for ActiveLayout
+{
+    fn map_keycode(&self, keycode: KeyCode, modifiers: &Modifiers, handle_ctrl: HandleControl) -> DecodedKey
+    {
+        let kind = LAYOUTS[CUR_LAYOUT.load(Ordering::Relaxed)].1;
+        return match kind
+        {
+            LayoutKind::Us104  => layouts::Us104Key.map_keycode(keycode, modifiers, handle_ctrl),
+            LayoutKind::Uk105  => layouts::Uk105Key.map_keycode(keycode, modifiers, handle_ctrl),
+            LayoutKind::De105  => layouts::De105Key.map_keycode(keycode, modifiers, handle_ctrl),
+            LayoutKind::It105  => It105Key.map_keycode(keycode, modifiers, handle_ctrl),
+            LayoutKind::Azerty => layouts::Azerty.map_keycode(keycode, modifiers, handle_ctrl),
+            LayoutKind::Dvorak104 => layouts::Dvorak104Key.map_keycode(keycode, modifiers, handle_ctrl),
+            LayoutKind::DvorakProgrammer104 => layouts::DVP104Key.map_keycode(keycode, modifiers, handle_ctrl),
+            LayoutKind::Colemak => layouts::Colemak.map_keycode(keycode, modifiers, handle_ctrl),
+            LayoutKind::Jis109  => layouts::Jis109Key.map_keycode(keycode, modifiers, handle_ctrl),
+        };
+    }
+}
+
+/// Standard Italian 105-key keyboard, which pc_keyboard doesn't have.
+/// Only the keys that differ from the US layout are mapped here.
+pub struct It105Key;
+
+impl KeyboardLayout for It105Key
+{
+    fn map_keycode(&self, keycode: KeyCode, modifiers: &Modifiers, handle_ctrl: HandleControl) -> DecodedKey
+    {
+        // Picks the character depending on Shift and AltGr
+        let pick = |normal: char, shifted: char, alt_gr: Option<char>| -> DecodedKey {
+            if let Some(c) = alt_gr {
+                if modifiers.alt_gr { return DecodedKey::Unicode(c); }
+            }
+            return DecodedKey::Unicode(if modifiers.is_shifted() { shifted } else { normal });
+        };
+
+        return match keycode
+        {
+            KeyCode::Oem8      => pick('\\', '|', None),
+            KeyCode::Key2      => pick('2', '"', None),
+            KeyCode::Key3      => pick('3', '£', None),
+            KeyCode::Key6      => pick('6', '&', None),
+            KeyCode::Key7      => pick('7', '/', None),
+            KeyCode::Key8      => pick('8', '(', None),
+            KeyCode::Key9      => pick('9', ')', None),
+            KeyCode::Key0      => pick('0', '=', None),
+            KeyCode::OemMinus  => pick('\'', '?', None),
+            KeyCode::OemPlus   => pick('ì', '^', None),
+            KeyCode::Oem4      => pick('è', 'é', Some('[')),
+            KeyCode::Oem6      => pick('+', '*', Some(']')),
+            KeyCode::Oem1      => pick('ò', 'ç', Some('@')),
+            KeyCode::Oem3      => pick('à', '°', Some('#')),
+            KeyCode::Oem7      => pick('ù', '§', None),
+            KeyCode::Oem5      => pick('<', '>', None),
+            KeyCode::OemComma  => pick(',', ';', None),
+            KeyCode::OemPeriod => pick('.', ':', None),
+            KeyCode::Oem2      => pick('-', '_', None),
+            KeyCode::E if modifiers.alt_gr => DecodedKey::Unicode('€'),
+            _ => layouts::Us104Key.map_keycode(keycode, modifiers, handle_ctrl),
+        };
+    }
+}
+
+// Decoding
+
+struct KeyboardState
+{
+    decoder: Keyboard<ActiveLayout, ScancodeSet1>,
+    modifiers: KeyModifiers,
+}
+
+lazy_static!
+{
+    static ref KEYBOARD: Mutex<KeyboardState> = Mutex::new(KeyboardState {
+        decoder: Keyboard::new(
+            ScancodeSet1::new(),
+            ActiveLayout,
+            HandleControl::MapLettersToUnicode
+        ),
+        modifiers: KeyModifiers::default(),
+    });
+}
+
+pub fn modifiers() -> KeyModifiers
+{
+    return KEYBOARD.lock().modifiers;
+}
+
+/// Decodes a byte read from the keyboard controller.
+pub fn handle_scancode(scancode: u8)
+{
+    let mut keyboard = KEYBOARD.lock();
+    let event = match keyboard.decoder.add_byte(scancode)
+    {
+        Ok(Some(event)) => event,
+        _ => return,
+    };
+
+    keyboard.modifiers.update(&event);
+    let modifiers = keyboard.modifiers;
+
+    // The layouts turn Delete into a DEL character, but
+    // terminals send an escape sequence for it.
+    if event.code == KeyCode::Delete
+    {
+        if event.state == KeyState::Down { send_key_sequence(KeyCode::Delete, modifiers); }
+        return;
+    }
+
+    // Alt+SysRq (Alt+PrintScreen) enters the debug monitor, which
+    // reads the keyboard itself until it's left
+    let is_sysrq = event.code == KeyCode::SysRq || (event.code == KeyCode::PrintScreen && modifiers.alt);
+    if is_sysrq && event.state == KeyState::Down
+    {
+        drop(keyboard);
+        monitor::run(monitor::Entry::SysRq);
+
+        // Key releases went to the monitor, start over with nothing held
+        let mut keyboard = KEYBOARD.lock();
+        keyboard.decoder = Keyboard::new(ScancodeSet1::new(), ActiveLayout, HandleControl::MapLettersToUnicode);
+        keyboard.modifiers = KeyModifiers::default();
+        return;
+    }
+
+    // Alt+F1..F6 switch virtual console
+    if modifiers.alt && event.state == KeyState::Down
+    {
+        let console = match event.code
+        {
+            KeyCode::F1 => Some(0),
+            KeyCode::F2 => Some(1),
+            KeyCode::F3 => Some(2),
+            KeyCode::F4 => Some(3),
+            KeyCode::F5 => Some(4),
+            KeyCode::F6 => Some(5),
+            _ => None,
+        };
+
+        if let Some(console) = console
+        {
+            console::switch_to(console);
+            return;
+        }
+    }
+
+    // Shift+PageUp/PageDown scroll the console instead of going to the tty
+    if modifiers.shift() && event.state == KeyState::Down
+    {
+        match event.code
+        {
+            KeyCode::PageUp   => { vga_buffer::scroll_half_page(true); return; }
+            KeyCode::PageDown => { vga_buffer::scroll_half_page(false); return; }
+            _ => {},
+        }
+    }
+
+    match keyboard.decoder.process_keyevent(event)
+    {
+        Some(DecodedKey::Unicode(character)) =>
+        {
+            // Alt+key is sent as ESC followed by the key
+            if modifiers.alt { tty::input_byte(ESC); }
+
+            let mut buf: [u8; 4] = [0; 4];
+            for &byte in character.encode_utf8(&mut buf).as_bytes() {
+                tty::input_byte(byte);
+            }
+        }
+        Some(DecodedKey::RawKey(code)) => send_key_sequence(code, modifiers),
+        None => {},
+    }
+}
+
+fn send_key_sequence(code: KeyCode, modifiers: KeyModifiers)
+{
+    let mut buf: [u8; 8] = [0; 8];
+    let len = key_sequence(code, modifiers, &mut buf);
+    for &byte in &buf[..len] {
+        tty::input_byte(byte);
+    }
+}
+
+/// Writes the escape sequence for a key without a character into `buf`,
+/// and returns its length (0 if the key doesn't send anything).
+pub fn key_sequence(code: KeyCode, modifiers: KeyModifiers, buf: &mut [u8; 8]) -> usize
+{
+    let modifier = modifiers.xterm_param();
+
+    // ESC [ X, or ESC [ 1 ; m X with modifiers
+    let csi_letter = match code
+    {
+        KeyCode::ArrowUp    => Some(b'A'),
+        KeyCode::ArrowDown  => Some(b'B'),
+        KeyCode::ArrowRight => Some(b'C'),
+        KeyCode::ArrowLeft  => Some(b'D'),
+        KeyCode::Home       => Some(b'H'),
+        KeyCode::End        => Some(b'F'),
+        _ => None,
+    };
+
+    // ESC O X, or ESC [ 1 ; m X with modifiers
+    let ss3_letter = match code
+    {
+        KeyCode::F1 => Some(b'P'),
+        KeyCode::F2 => Some(b'Q'),
+        KeyCode::F3 => Some(b'R'),
+        KeyCode::F4 => Some(b'S'),
+        _ => None,
+    };
+
+    // ESC [ n ~, or ESC [ n ; m ~ with modifiers
+    let csi_number: Option<u8> = match code
+    {
+        KeyCode::Insert   => Some(2),
+        KeyCode::Delete   => Some(3),
+        KeyCode::PageUp   => Some(5),
+        KeyCode::PageDown => Some(6),
+        KeyCode::F5  => Some(15),
+        KeyCode::F6  => Some(17),
+        KeyCode::F7  => Some(18),
+        KeyCode::F8  => Some(19),
+        KeyCode::F9  => Some(20),
+        KeyCode::F10 => Some(21),
+        KeyCode::F11 => Some(23),
+        KeyCode::F12 => Some(24),
+        _ => None,
+    };
+
+    let mut len = 0;
+    let mut push = |byte: u8| { buf[len] = byte; len += 1; };
+
+    if let Some(letter) = csi_letter.or(ss3_letter)
+    {
+        push(ESC);
+        if modifier > 1
+        {
+            push(b'['); push(b'1'); push(b';'); push(b'0' + modifier);
+        }
+        else
+        {
+            push(if ss3_letter.is_some() { b'O' } else { b'[' });
+        }
+        push(letter);
+    }
+    else if let Some(number) = csi_number
+    {
+        push(ESC);
+        push(b'[');
+        if number >= 10 { push(b'0' + number / 10); }
+        push(b'0' + number % 10);
+        if modifier > 1 { push(b';'); push(b'0' + modifier); }
+        push(b'~');
+    }
+
+    return len;
+}
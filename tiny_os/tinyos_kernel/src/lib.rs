@@ -1,8 +1,13 @@
 
 #![no_std]
+#![cfg_attr(test, no_main)]
 #![feature(abi_x86_interrupt)]
 #![feature(naked_functions)]
 #![feature(str_from_raw_parts)]
+#![feature(custom_test_frameworks)]
+#![feature(alloc_error_handler)]
+#![test_runner(crate::test_runner)]
+#![reexport_test_harness_main = "test_main"]
 #![allow(unsafe_op_in_unsafe_fn)]
 
 #![allow(dead_code)]
@@ -10,22 +15,73 @@
 #![allow(unused_imports)]
 
 extern crate alloc;
+use bootloader::BootInfo;
 use core::panic::PanicInfo;
+use x86_64::VirtAddr;
 
 pub mod allocator;
+pub mod slab;
+pub mod gdb;
 pub mod gdt;
 pub mod interrupts;
 pub mod memory;
+pub mod monitor;
 pub mod serial;
 pub mod vga_buffer;
+pub mod ansi;
 pub mod process;
 pub mod base;
+pub mod sched;
+pub mod time;
+pub mod signal;
+pub mod strace;
+pub mod profile;
+pub mod tty;
+pub mod keyboard;
+pub mod console;
+pub mod log;
+pub mod backtrace;
+pub mod symbols;
+pub mod panic;
+pub mod user_tests;
 
 pub fn init()
 {
     gdt::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
+    serial::init();
+    gdb::init();
+
+    // Make sure the COM1 and COM2 interrupts aren't masked
+    unsafe
+    {
+        let mut pics = interrupts::PICS.lock();
+        let [mask1, mask2] = pics.read_masks();
+        pics.write_masks(mask1 & !(1 << 4) & !(1 << 3), mask2);
+    }
+}
+
+/// Sets up paging info, the frame allocator and the heap.
+pub fn init_memory(boot_info: &'static BootInfo)
+{
+    // Get memory info from boot loader
+    let mut kernel_page_table;
+    {
+        let mut kernel_mem_info = memory::KERNEL_MEM_INFO.lock();
+        kernel_mem_info.phys_offset = VirtAddr::new(boot_info.physical_memory_offset);
+        kernel_page_table = unsafe { memory::init_kernel_page_table(kernel_mem_info.phys_offset) };
+        kernel_mem_info.kernel_page_table_phys_addr = unsafe { memory::active_level_4_table_addr() };
+    }
+
+    // Init frame allocator
+    {
+        let mut frame_allocator = memory::FRAME_ALLOCATOR.lock();
+        *frame_allocator = unsafe { memory::BootInfoFrameAllocator::init(&boot_info.memory_map, VirtAddr::new(boot_info.physical_memory_offset)) };
+    }
+
+    allocator::init_heap(&mut kernel_page_table).expect("Heap initialization failed.");
+    slab::init(VirtAddr::new(boot_info.physical_memory_offset));
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -49,3 +105,62 @@ pub fn hlt_loop() -> !
 {
     loop { x86_64::instructions::hlt(); }
 }
+
+// Testing. `cargo test` boots a kernel that runs every #[test_case]
+// and reports over serial, then exits QEMU with the result.
+
+pub trait Testable
+{
+    fn run(&self);
+}
+
+impl<T: Fn()> Testable for T
+{
+    fn run(&self)
+    {
+        serial_print!("{}...\t", core::any::type_name::<T>());
+        self();
+        serial_println!("[ok]");
+    }
+}
+
+pub fn test_runner(tests: &[&dyn Testable])
+{
+    serial_println!("Running {} tests", tests.len());
+    for test in tests {
+        test.run();
+    }
+
+    exit_qemu(QemuExitCode::Success);
+}
+
+/// Panic handler for test kernels: reports the failure (with a
+/// backtrace) over serial and exits QEMU.
+pub fn test_panic_handler(info: &PanicInfo) -> !
+{
+    serial_println!("[failed]\n");
+    panic::set_exit_qemu_on_panic(true);
+    panic::handle_panic(info);
+}
+
+#[cfg(test)]
+bootloader::entry_point!(test_kernel_main);
+
+#[cfg(test)]
+fn test_kernel_main(boot_info: &'static BootInfo) -> !
+{
+    init();
+    init_memory(boot_info);
+    // Only the test results should go to serial
+    log::set_sinks(0);
+
+    test_main();
+    hlt_loop();
+}
+
+#[cfg(test)]
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !
+{
+    test_panic_handler(info);
+}
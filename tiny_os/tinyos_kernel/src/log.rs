@@ -0,0 +1,304 @@
+// Kernel log. Messages have a level and come from a module, and the
+// ones that pass the filters are timestamped and kept in a ring buffer
+// (which user space can read with the dmesg syscall). They can also be
+// shown on the screen and sent to the serial port as they're logged.
+
+use crate::{console, serial, time, vga_buffer};
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicU8, Ordering};
+use spin::Mutex;
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[repr(u8)]
+pub enum Level
+{
+    Error = 1,
+    Warn  = 2,
+    Info  = 3,
+    Debug = 4,
+    Trace = 5,
+}
+
+impl Level
+{
+    pub fn name(self) -> &'static str
+    {
+        return match self
+        {
+            Level::Error => "ERROR",
+            Level::Warn  => "WARN",
+            Level::Info  => "INFO",
+            Level::Debug => "DEBUG",
+            Level::Trace => "TRACE",
+        };
+    }
+
+    pub fn by_name(name: &str) -> Option<Level>
+    {
+        return match name
+        {
+            "error" => Some(Level::Error),
+            "warn"  => Some(Level::Warn),
+            "info"  => Some(Level::Info),
+            "debug" => Some(Level::Debug),
+            "trace" => Some(Level::Trace),
+            _ => None,
+        };
+    }
+}
+
+// Filtering
+
+pub const MAX_MODULE_FILTERS: usize = 8;
+
+struct Filters
+{
+    default_level: Level,
+    // Module path prefix and the most verbose level logged for it
+    modules: [(&'static str, Level); MAX_MODULE_FILTERS],
+    num_modules: usize,
+}
+
+impl Filters
+{
+    // The most verbose level logged for a module. The longest matching prefix wins.
+    fn max_level(&self, module: &str) -> Level
+    {
+        let mut res = self.default_level;
+        let mut best_len = 0;
+        for &(prefix, level) in &self.modules[..self.num_modules]
+        {
+            if module.starts_with(prefix) && prefix.len() >= best_len
+            {
+                res = level;
+                best_len = prefix.len();
+            }
+        }
+
+        return res;
+    }
+}
+
+static FILTERS: Mutex<Filters> = Mutex::new(Filters {
+    default_level: Level::Info,
+    modules: [("", Level::Info); MAX_MODULE_FILTERS],
+    num_modules: 0,
+});
+
+/// Sets the filters from a spec like "info,tinyos::process=debug": a
+/// default level and levels for modules (and their submodules).
+/// Returns false if the spec isn't valid, in which case nothing changes.
+pub fn set_filters(spec: &'static str) -> bool
+{
+    let mut filters = Filters {
+        default_level: Level::Info,
+        modules: [("", Level::Info); MAX_MODULE_FILTERS],
+        num_modules: 0,
+    };
+
+    for directive in spec.split(',').map(|d| d.trim()).filter(|d| !d.is_empty())
+    {
+        match directive.split_once('=')
+        {
+            None => filters.default_level = match Level::by_name(directive) { Some(level) => level, None => return false },
+            Some((module, level)) =>
+            {
+                if filters.num_modules == MAX_MODULE_FILTERS { return false; }
+                let level = match Level::by_name(level) { Some(level) => level, None => return false };
+                filters.modules[filters.num_modules] = (module, level);
+                filters.num_modules += 1;
+            }
+        }
+    }
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        *FILTERS.lock() = filters;
+    });
+    return true;
+}
+
+pub fn enabled(level: Level, module: &str) -> bool
+{
+    return x86_64::instructions::interrupts::without_interrupts(|| {
+        return level <= FILTERS.lock().max_level(module);
+    });
+}
+
+// Sinks
+
+pub const SINK_VGA:    u8 = 1 << 0;
+pub const SINK_SERIAL: u8 = 1 << 1;
+
+static SINKS: AtomicU8 = AtomicU8::new(SINK_VGA);
+
+pub fn set_sinks(sinks: u8)
+{
+    SINKS.store(sinks, Ordering::Relaxed);
+}
+
+/// Parses a list of sinks like "vga,serial". "none" only keeps the ring buffer.
+pub fn sinks_by_name(names: &str) -> Option<u8>
+{
+    let mut sinks = 0;
+    for name in names.split(',').map(|n| n.trim())
+    {
+        match name
+        {
+            "vga"    => sinks |= SINK_VGA,
+            "serial" => sinks |= SINK_SERIAL,
+            "none" | "" => {},
+            _ => return None,
+        }
+    }
+
+    return Some(sinks);
+}
+
+// Ring buffer
+
+pub const LOG_BUFFER_SIZE: usize = 16 * 1024;
+
+struct LogBuffer
+{
+    data: [u8; LOG_BUFFER_SIZE],
+    start: usize,
+    len: usize,
+    wrapped: bool,  // Whether old messages have been overwritten
+}
+
+impl LogBuffer
+{
+    fn push(&mut self, byte: u8)
+    {
+        let idx = (self.start + self.len) % LOG_BUFFER_SIZE;
+        self.data[idx] = byte;
+        if self.len < LOG_BUFFER_SIZE
+        {
+            self.len += 1;
+        }
+        else
+        {
+            self.start = (self.start + 1) % LOG_BUFFER_SIZE;
+            self.wrapped = true;
+        }
+    }
+
+    fn byte(&self, pos: usize) -> u8
+    {
+        return self.data[(self.start + pos) % LOG_BUFFER_SIZE];
+    }
+
+    // Copies the most recent whole lines that fit in `buf`, returns the number of bytes written
+    fn read(&self, buf: &mut [u8]) -> usize
+    {
+        let mut from = self.len.saturating_sub(buf.len());
+        // Don't start in the middle of a message
+        if from > 0 || self.wrapped
+        {
+            while from < self.len && self.byte(from) != b'\n' { from += 1; }
+            from = core::cmp::min(from + 1, self.len);
+        }
+
+        let count = self.len - from;
+        for (i, slot) in buf[..count].iter_mut().enumerate() {
+            *slot = self.byte(from + i);
+        }
+
+        return count;
+    }
+}
+
+impl fmt::Write for LogBuffer
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result
+    {
+        for byte in s.bytes() {
+            self.push(byte);
+        }
+        return Ok(());
+    }
+}
+
+static LOG_BUFFER: Mutex<LogBuffer> = Mutex::new(LogBuffer {
+    data: [0; LOG_BUFFER_SIZE],
+    start: 0,
+    len: 0,
+    wrapped: false,
+});
+
+/// Copies the most recent messages into `buf`. Returns the number of bytes written.
+pub fn read_buffer(buf: &mut [u8]) -> usize
+{
+    return x86_64::instructions::interrupts::without_interrupts(|| {
+        return LOG_BUFFER.lock().read(buf);
+    });
+}
+
+// Logging
+
+struct Record<'a>
+{
+    ticks: u64,
+    level: Level,
+    module: &'static str,
+    args: fmt::Arguments<'a>,
+}
+
+impl fmt::Display for Record<'_>
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        return writeln!(f, "[{:>8}] {:<5} {}: {}", self.ticks, self.level.name(), self.module, self.args);
+    }
+}
+
+#[doc(hidden)]
+pub fn _log(level: Level, module: &'static str, args: fmt::Arguments)
+{
+    if !enabled(level, module) { return; }
+
+    let record = Record { ticks: time::ticks(), level, module, args };
+
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let _ = write!(LOG_BUFFER.lock(), "{}", record);
+    });
+
+    let sinks = SINKS.load(Ordering::Relaxed);
+    if sinks & SINK_VGA != 0 {
+        vga_buffer::print_to(console::active(), format_args!("{}", record));
+    }
+    if sinks & SINK_SERIAL != 0 {
+        serial::_print(format_args!("{}", record));
+    }
+}
+
+/// Logs a message with the given level, e.g. `log!(Level::Info, "{} tasks", n)`.
+#[macro_export]
+macro_rules! log {
+    ($level:expr, $($arg:tt)*) => ($crate::log::_log($level, module_path!(), format_args!($($arg)*)));
+}
+
+#[macro_export]
+macro_rules! error {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Error, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! warn {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Warn, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! info {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Info, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! debug {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Debug, $($arg)*));
+}
+
+#[macro_export]
+macro_rules! trace {
+    ($($arg:tt)*) => ($crate::log!($crate::log::Level::Trace, $($arg)*));
+}
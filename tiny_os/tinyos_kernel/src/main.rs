@@ -8,10 +8,14 @@
 extern crate alloc;
 
 use alloc::{boxed::Box, rc::Rc, vec, vec::Vec};
-use tinyos::println;
+use tinyos::{debug, info, println};
+use tinyos::log;
 use bootloader::{entry_point, BootInfo};
 use core::panic::PanicInfo;
 use tinyos::process;
+use tinyos::sched;
+use tinyos::console;
+use tinyos::serial;
 use lazy_static::lazy_static;
 use tinyos::allocator;
 use tinyos::memory::{self, BootInfoFrameAllocator};
@@ -23,36 +27,62 @@ fn kernel_main(boot_info: &'static BootInfo) -> !
 {
     tinyos::init();
 
-    // Get memory info from boot loader
-    let mut kernel_page_table;
+    // Logging can be configured at build time, e.g.
+    // TINYOS_LOG=info,tinyos::process=debug TINYOS_LOG_SINKS=vga,serial cargo run
     {
-        let mut kernel_mem_info = memory::KERNEL_MEM_INFO.lock();
-        (*kernel_mem_info).phys_offset = VirtAddr::new(boot_info.physical_memory_offset);
-        //phys_offset = VirtAddr::new(boot_info.physical_memory_offset);
-        kernel_page_table = unsafe { memory::init_kernel_page_table(kernel_mem_info.phys_offset) };
-        (*kernel_mem_info).kernel_page_table_phys_addr = unsafe { memory::active_level_4_table_addr() };
-        //kernel_page_table_phys_addr = unsafe { memory::active_level_4_table_addr() };
+        let filters = option_env!("TINYOS_LOG").unwrap_or("info");
+        if !log::set_filters(filters) { println!("Invalid log filters: {}", filters); }
+        let sinks = option_env!("TINYOS_LOG_SINKS").unwrap_or("vga");
+        log::set_sinks(log::sinks_by_name(sinks).expect("Unknown log sink."));
     }
 
-    // Init frame allocator
+    tinyos::init_memory(boot_info);
+
+    // Useful for automated runs, e.g. TINYOS_PANIC_EXIT=1 cargo run
+    tinyos::panic::set_exit_qemu_on_panic(option_env!("TINYOS_PANIC_EXIT") == Some("1"));
+
+    // Console output can also go to the serial port, e.g.
+    // TINYOS_SERIAL_CONSOLE=redirect for running without a screen.
     {
-        let mut frame_allocator = memory::FRAME_ALLOCATOR.lock();
-        *frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+        let mode_name = option_env!("TINYOS_SERIAL_CONSOLE").unwrap_or("mirror");
+        let mode = serial::console_mode_by_name(mode_name).expect("Unknown serial console mode.");
+        serial::set_console_mode(mode);
     }
 
-    allocator::init_heap(&mut kernel_page_table).expect("Heap initialization failed.");
+    // The scheduling policy can be picked at build time,
+    // e.g. TINYOS_SCHEDULER=mlfq cargo run
+    {
+        let policy_name = option_env!("TINYOS_SCHEDULER").unwrap_or("round_robin");
+        let policy = sched::policy_by_name(policy_name).expect("Unknown scheduling policy.");
+        process::SCHEDULER.set_policy(policy);
+        info!("Using the {} scheduler.", process::SCHEDULER.policy_name());
+    }
 
+    // The user space tests are run instead of the shells, with the results
+    // on serial, e.g. cargo run --features user_tests
+    if cfg!(feature = "user_tests")
+    {
+        tinyos::panic::set_exit_qemu_on_panic(true);
+        serial::set_console_mode(serial::ConsoleMode::Off);
+        tinyos::user_tests::start();
+
+        x86_64::instructions::interrupts::enable();
+        tinyos::hlt_loop();
+    }
+
+    // A shell for each virtual console
+    for console in 0..console::NUM_CONSOLES
     {
         let kern_mem_info = memory::KERNEL_MEM_INFO.lock();
-        let task = process::create_task(process::USER_PROGRAM_SHELL, kern_mem_info.phys_offset, kern_mem_info.kernel_page_table_phys_addr, 0);
-        println!("Created task.");
+        let mut task = process::create_task("shell", process::USER_PROGRAM_SHELL, kern_mem_info.phys_offset, kern_mem_info.kernel_page_table_phys_addr, 0).unwrap();
+        task.console = console;
 
-        process::SCHEDULER.schedule_task(task.unwrap());
-        println!("Scheduled task.");
+        process::SCHEDULER.schedule_task(task);
     }
+    info!("Scheduled the shells. Use Alt+F1..F{} to switch console.", console::NUM_CONSOLES);
 
     // We will be interrupted soon
-    println!("End of main.");
+    debug!("End of main.");
     x86_64::instructions::interrupts::enable();
     tinyos::hlt_loop();
 }
@@ -60,6 +90,5 @@ fn kernel_main(boot_info: &'static BootInfo) -> !
 #[panic_handler]
 fn panic(info: &PanicInfo) -> !
 {
-    println!("{}", info);
-    tinyos::hlt_loop();
+    tinyos::panic::handle_panic(info);
 }
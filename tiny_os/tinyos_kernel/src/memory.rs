@@ -2,12 +2,16 @@
 use lazy_static::lazy_static;
 use bootloader::bootinfo::{MemoryMap, MemoryRegionType};
 use crate::println;
+use core::fmt;
 use x86_64::
 {
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PageTableFlags, PhysFrame, Size4KiB},
+    structures::paging::{FrameAllocator, FrameDeallocator, OffsetPageTable, PageTable, PageTableFlags, PhysFrame, Size4KiB},
     PhysAddr, VirtAddr,
 };
 
+/// Addresses at or above this are not in the lower half, and so are not user space.
+pub const USER_SPACE_END: u64 = 0x0000_8000_0000_0000;
+
 pub unsafe fn init_kernel_page_table(phys_offset: VirtAddr) -> OffsetPageTable<'static>
 {
     unsafe
@@ -30,6 +34,254 @@ pub unsafe fn active_level_4_table(phys_offset: VirtAddr) -> &'static mut PageTa
     return unsafe { &mut *page_table_ptr };
 }
 
+/// Whether an address can be read without faulting, in the active address
+/// space. This doesn't wait on any lock, so it can be used while panicking.
+/// Before paging has been set up everything is assumed to be mapped.
+pub fn is_mapped(addr: u64) -> bool
+{
+    use x86_64::structures::paging::Translate;
+
+    let phys_offset = match KERNEL_MEM_INFO.try_lock()
+    {
+        Some(info) => info.phys_offset,
+        None => return false,
+    };
+    if phys_offset.as_u64() == 0 { return true; }
+
+    let addr = match VirtAddr::try_new(addr)
+    {
+        Ok(addr) => addr,
+        Err(_) => return false,  // Not canonical
+    };
+
+    let mapper = unsafe { OffsetPageTable::new(active_level_4_table(phys_offset), phys_offset) };
+    return mapper.translate_addr(addr).is_some();
+}
+
+/// Pointer to an address of the active address space through the physical
+/// memory mapping, so that it can be written even if its page is read only
+/// (e.g. to put breakpoints in code). None if it isn't mapped. Doesn't wait on any lock.
+pub fn physical_ptr(addr: u64) -> Option<*mut u8>
+{
+    use x86_64::structures::paging::Translate;
+
+    let phys_offset = KERNEL_MEM_INFO.try_lock()?.phys_offset;
+    if phys_offset.as_u64() == 0 { return None; }
+
+    let addr = VirtAddr::try_new(addr).ok()?;
+    let mapper = unsafe { OffsetPageTable::new(active_level_4_table(phys_offset), phys_offset) };
+    let phys = mapper.translate_addr(addr)?;
+    return Some((phys_offset + phys.as_u64()).as_mut_ptr());
+}
+
+/// Virtual memory mapped to contiguous physical memory with pages of the same size and flags.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Mapping
+{
+    pub virt: u64,
+    pub phys: u64,
+    pub size: u64,
+    /// 4 KiB, 2 MiB or 1 GiB.
+    pub page_size: u64,
+    /// Effective flags: writable and user accessible only if every
+    /// level allows it, not executable if any level forbids it.
+    pub flags: PageTableFlags,
+}
+
+impl Mapping
+{
+    pub fn end(&self) -> u64
+    {
+        return self.virt.wrapping_add(self.size);
+    }
+}
+
+/// E.g. "0x5000000-0x5002000 r-x user 4K".
+impl fmt::Display for Mapping
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let page_size = match self.page_size
+        {
+            0x1000 => "4K",
+            0x20_0000 => "2M",
+            _ => "1G",
+        };
+
+        return write!(f, "{:#x}-{:#x} r{}{} {} {}", self.virt, self.end(),
+                      if self.flags.contains(PageTableFlags::WRITABLE) { "w" } else { "-" },
+                      if self.flags.contains(PageTableFlags::NO_EXECUTE) { "-" } else { "x" },
+                      if self.flags.contains(PageTableFlags::USER_ACCESSIBLE) { "user" } else { "kernel" },
+                      page_size);
+    }
+}
+
+// Flags that change just by using the memory
+const TRANSIENT_FLAGS: PageTableFlags = PageTableFlags::ACCESSED.union(PageTableFlags::DIRTY);
+// Flags that only apply if every level has them
+const INHERITED_FLAGS: PageTableFlags = PageTableFlags::WRITABLE.union(PageTableFlags::USER_ACCESSIBLE);
+
+#[derive(Clone, Copy)]
+struct WalkLevel
+{
+    table: PhysAddr,
+    base: u64,
+    next_idx: usize,
+    flags: PageTableFlags,
+}
+
+/// Iterator over the mappings of a page table, which walks all four levels.
+/// Adjacent pages mapping adjacent frames with the same flags are merged.
+pub struct Mappings
+{
+    phys_offset: VirtAddr,
+    start: u64,
+    end: u64,
+    // Tables being walked, from level 4 down
+    levels: [WalkLevel; 4],
+    depth: usize,
+    pending: Option<Mapping>,
+}
+
+/// The mappings of the page table at `l4_phys` in the virtual range [start, end).
+pub fn mappings(l4_phys: PhysAddr, phys_offset: VirtAddr, start: u64, end: u64) -> Mappings
+{
+    let top = WalkLevel { table: l4_phys, base: 0, next_idx: 0, flags: INHERITED_FLAGS };
+    return Mappings { phys_offset, start, end, levels: [top; 4], depth: 1, pending: None };
+}
+
+impl Mappings
+{
+    // Next page (or huge page) that's mapped
+    fn next_page(&mut self) -> Option<Mapping>
+    {
+        while self.depth > 0
+        {
+            let level_num = 5 - self.depth as u8;
+            let level = &mut self.levels[self.depth - 1];
+            if level.next_idx == 512 { self.depth -= 1; continue; }
+
+            let idx = level.next_idx;
+            level.next_idx += 1;
+
+            let table = unsafe { &*(self.phys_offset + level.table.as_u64()).as_ptr::<PageTable>() };
+            let entry = &table[idx];
+            let entry_size: u64 = 4096 << (9 * (level_num as u64 - 1));
+
+            let mut virt = level.base + idx as u64 * entry_size;
+            // The upper half of the address space is sign extended
+            if level_num == 4 && idx >= 256 { virt |= 0xFFFF_0000_0000_0000; }
+
+            if virt.saturating_add(entry_size) <= self.start || virt >= self.end { continue; }
+            if !entry.flags().contains(PageTableFlags::PRESENT) { continue; }
+
+            let flags = entry.flags() - TRANSIENT_FLAGS;
+            let effective = (flags - INHERITED_FLAGS) | (flags & level.flags & INHERITED_FLAGS)
+                          | (level.flags & PageTableFlags::NO_EXECUTE);
+
+            if level_num == 1 || flags.contains(PageTableFlags::HUGE_PAGE)
+            {
+                return Some(Mapping {
+                    virt,
+                    phys: entry.addr().as_u64(),
+                    size: entry_size,
+                    page_size: entry_size,
+                    flags: effective - PageTableFlags::HUGE_PAGE,
+                });
+            }
+
+            self.levels[self.depth] = WalkLevel { table: entry.addr(), base: virt, next_idx: 0, flags: effective };
+            self.depth += 1;
+        }
+
+        return None;
+    }
+}
+
+impl Iterator for Mappings
+{
+    type Item = Mapping;
+
+    fn next(&mut self) -> Option<Mapping>
+    {
+        while let Some(page) = self.next_page()
+        {
+            match self.pending.as_mut()
+            {
+                Some(last) if last.end() == page.virt && last.phys + last.size == page.phys
+                              && last.flags == page.flags && last.page_size == page.page_size =>
+                {
+                    last.size += page.size;
+                }
+                _ =>
+                {
+                    let res = self.pending.replace(page);
+                    if res.is_some() { return res; }
+                }
+            }
+        }
+
+        return self.pending.take();
+    }
+}
+
+/// Whether [start, start + len) is in user space and mapped user accessible
+/// (and writable if asked) in the active page table, so that the kernel can
+/// access it for the task without faulting.
+pub fn is_user_range(start: u64, len: u64, writable: bool) -> bool
+{
+    let end = match start.checked_add(len)
+    {
+        Some(end) if end <= USER_SPACE_END => end,
+        _ => return false,
+    };
+    if len == 0 { return true; }
+
+    let mut needed = PageTableFlags::USER_ACCESSIBLE;
+    if writable { needed |= PageTableFlags::WRITABLE; }
+
+    let phys_offset = KERNEL_MEM_INFO.lock().phys_offset;
+    let page_table = unsafe { active_level_4_table_addr() };
+    let mut covered = start;
+    for mapping in mappings(page_table, phys_offset, start, end)
+    {
+        if mapping.virt > covered || !mapping.flags.contains(needed) { return false; }
+        covered = mapping.end();
+        if covered >= end { return true; }
+    }
+    return false;
+}
+
+// Entries returned by the memory map syscall.
+// NOTE: These should be kept up to date along with their
+// counterparts in the usercode library.
+pub const MAP_READ:  u64 = 1 << 0;
+pub const MAP_WRITE: u64 = 1 << 1;
+pub const MAP_EXEC:  u64 = 1 << 2;
+pub const MAP_USER:  u64 = 1 << 3;
+
+#[derive(Default, Clone, Copy, Debug)]
+#[repr(C)]
+pub struct MemoryMapEntry
+{
+    pub start: u64,
+    pub end: u64,
+    pub flags: u64,
+    pub page_size: u64,
+}
+
+impl From<Mapping> for MemoryMapEntry
+{
+    fn from(mapping: Mapping) -> Self
+    {
+        let mut flags = MAP_READ;
+        if mapping.flags.contains(PageTableFlags::WRITABLE)        { flags |= MAP_WRITE; }
+        if !mapping.flags.contains(PageTableFlags::NO_EXECUTE)     { flags |= MAP_EXEC; }
+        if mapping.flags.contains(PageTableFlags::USER_ACCESSIBLE) { flags |= MAP_USER; }
+        return MemoryMapEntry { start: mapping.virt, end: mapping.end(), flags, page_size: mapping.page_size };
+    }
+}
+
 pub unsafe fn active_level_4_table_addr() -> PhysAddr
 {
     use x86_64::registers::control::Cr3;
@@ -45,6 +297,14 @@ pub unsafe fn clone_page_table(phys_addr: PhysAddr, phys_offset: VirtAddr) -> Op
     return unsafe { clone_page_table_rec(phys_addr, phys_offset, 4) };
 }
 
+// Index of the level 4 entry covering the kernel heap. Clones share its
+// level 3 table instead of copying it, so that the pages mapped when the
+// heap grows show up in every address space.
+fn kernel_heap_l4_index() -> usize
+{
+    return (crate::allocator::KERNEL_HEAP_START >> 39) & 511;
+}
+
 unsafe fn clone_page_table_rec(phys_addr: PhysAddr, phys_offset: VirtAddr, level: u8) -> Option<PhysAddr>
 {
     if level <= 0 || level > 4 { panic!("Invalid level"); }
@@ -66,7 +326,8 @@ unsafe fn clone_page_table_rec(phys_addr: PhysAddr, phys_offset: VirtAddr, level
 
         let entry_phys_addr = entry.addr();
 
-        if entry.flags().contains(PageTableFlags::HUGE_PAGE) || level == 1
+        let shared = level == 4 && i == kernel_heap_l4_index();
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) || level == 1 || shared
         {
             new_table[i] = entry.clone();
         }
@@ -83,6 +344,45 @@ unsafe fn clone_page_table_rec(phys_addr: PhysAddr, phys_offset: VirtAddr, level
     return Some(new_table_phys_addr);
 }
 
+/// Gives back the frames of a page table made by clone_page_table, along
+/// with the user pages mapped in it. The kernel mappings it shares with
+/// the original (leaves, huge pages and the heap's table) are left alone.
+///
+/// # Safety
+/// The page table must not be active or used afterwards.
+pub unsafe fn free_page_table(phys_addr: PhysAddr, phys_offset: VirtAddr)
+{
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    unsafe { free_page_table_rec(phys_addr, phys_offset, 4, &mut frame_allocator) };
+}
+
+unsafe fn free_page_table_rec(phys_addr: PhysAddr, phys_offset: VirtAddr, level: u8, frame_allocator: &mut BootInfoFrameAllocator)
+{
+    let table = unsafe { &*(phys_offset + phys_addr.as_u64()).as_ptr::<PageTable>() };
+    for (i, entry) in table.iter().enumerate()
+    {
+        if entry.is_unused() { continue; }
+        if !entry.flags().contains(PageTableFlags::PRESENT) { continue; }
+
+        let shared = level == 4 && i == kernel_heap_l4_index();
+        if entry.flags().contains(PageTableFlags::HUGE_PAGE) || shared { continue; }
+
+        if level == 1
+        {
+            // Only user pages belong to the task
+            if entry.flags().contains(PageTableFlags::USER_ACCESSIBLE) {
+                unsafe { frame_allocator.deallocate_frame(PhysFrame::containing_address(entry.addr())) };
+            }
+        }
+        else
+        {
+            unsafe { free_page_table_rec(entry.addr(), phys_offset, level - 1, frame_allocator) };
+        }
+    }
+
+    unsafe { frame_allocator.deallocate_frame(PhysFrame::containing_address(phys_addr)) };
+}
+
 pub unsafe fn activate_page_table(page_table_phys: PhysAddr)
 {
     use x86_64::registers::control::{Cr3, Cr3Flags};
@@ -113,6 +413,11 @@ pub struct BootInfoFrameAllocator
 {
     memory_map: Option<&'static MemoryMap>,
     next: usize,
+    // Frames that were given back. Each one holds the physical
+    // address of the next one, reached through the physical memory mapping.
+    free_list: Option<PhysFrame>,
+    num_free: usize,
+    phys_offset: VirtAddr,
 }
 
 pub static FRAME_ALLOCATOR: spin::Mutex<BootInfoFrameAllocator> = spin::Mutex::new(BootInfoFrameAllocator::new());
@@ -124,17 +429,43 @@ impl BootInfoFrameAllocator
         return BootInfoFrameAllocator {
             memory_map: None,
             next: 0,
+            free_list: None,
+            num_free: 0,
+            phys_offset: VirtAddr::zero(),
         }
     }
 
-    pub unsafe fn init(memory_map: &'static MemoryMap) -> Self
+    pub unsafe fn init(memory_map: &'static MemoryMap, phys_offset: VirtAddr) -> Self
     {
         BootInfoFrameAllocator {
             memory_map: Some(memory_map),
             next: 0,
+            free_list: None,
+            num_free: 0,
+            phys_offset,
         }
     }
 
+    pub fn memory_map(&self) -> Option<&'static MemoryMap>
+    {
+        return self.memory_map;
+    }
+
+    /// Number of frames in use, and the total number of usable frames.
+    pub fn stats(&self) -> (usize, usize)
+    {
+        let total = match self.memory_map
+        {
+            Some(memory_map) => memory_map.iter()
+                .filter(|r| r.region_type == MemoryRegionType::Usable)
+                .map(|r| ((r.range.end_addr() - r.range.start_addr()) / 4096) as usize)
+                .sum(),
+            None => 0,
+        };
+
+        return (core::cmp::min(self.next, total) - self.num_free, total);
+    }
+
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame>
     {
         // get usable regions from memory map
@@ -153,12 +484,31 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator
 {
     fn allocate_frame(&mut self) -> Option<PhysFrame>
     {
+        if let Some(frame) = self.free_list.take()
+        {
+            let next = unsafe { *(self.phys_offset + frame.start_address().as_u64()).as_ptr::<u64>() };
+            self.free_list = if next == u64::MAX { None } else { Some(PhysFrame::containing_address(PhysAddr::new(next))) };
+            self.num_free -= 1;
+            return Some(frame);
+        }
+
         let frame = self.usable_frames().nth(self.next);
         self.next += 1;
         return frame;
     }
 }
 
+impl FrameDeallocator<Size4KiB> for BootInfoFrameAllocator
+{
+    unsafe fn deallocate_frame(&mut self, frame: PhysFrame)
+    {
+        let next = self.free_list.map_or(u64::MAX, |next| next.start_address().as_u64());
+        unsafe { *(self.phys_offset + frame.start_address().as_u64()).as_mut_ptr::<u64>() = next };
+        self.free_list = Some(frame);
+        self.num_free += 1;
+    }
+}
+
 #[derive(Clone, Copy)]
 pub struct KernelMemInfo
 {
@@ -180,3 +530,132 @@ impl KernelMemInfo
 lazy_static! {
     pub static ref KERNEL_MEM_INFO: spin::Mutex<KernelMemInfo> = spin::Mutex::new(KernelMemInfo::new());
 }
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use x86_64::structures::paging::{Mapper, Page, Translate};
+
+    fn page_table_at(phys_addr: PhysAddr, phys_offset: VirtAddr) -> OffsetPageTable<'static>
+    {
+        let table = unsafe { &mut *(phys_offset + phys_addr.as_u64()).as_mut_ptr::<PageTable>() };
+        return unsafe { OffsetPageTable::new(table, phys_offset) };
+    }
+
+    #[test_case]
+    fn clone_keeps_kernel_mappings()
+    {
+        let phys_offset = KERNEL_MEM_INFO.lock().phys_offset;
+        let original_addr = unsafe { active_level_4_table_addr() };
+        let clone_addr = unsafe { clone_page_table(original_addr, phys_offset) }.unwrap();
+        assert_ne!(original_addr, clone_addr);
+
+        let original = page_table_at(original_addr, phys_offset);
+        let clone = page_table_at(clone_addr, phys_offset);
+
+        static SOME_STATIC: u64 = 42;
+        let addresses = [
+            VirtAddr::from_ptr(&SOME_STATIC),
+            VirtAddr::new(crate::allocator::KERNEL_HEAP_START as u64),
+            VirtAddr::new(clone_page_table as usize as u64),
+        ];
+        for addr in addresses {
+            assert_eq!(original.translate_addr(addr), clone.translate_addr(addr));
+        }
+    }
+
+    #[test_case]
+    fn clone_is_independent()
+    {
+        let phys_offset = KERNEL_MEM_INFO.lock().phys_offset;
+        let original_addr = unsafe { active_level_4_table_addr() };
+        let clone_addr = unsafe { clone_page_table(original_addr, phys_offset) }.unwrap();
+
+        let original = page_table_at(original_addr, phys_offset);
+        let mut clone = page_table_at(clone_addr, phys_offset);
+
+        let page = Page::<Size4KiB>::containing_address(VirtAddr::new(0x5555_0000_0000));
+        assert!(original.translate_addr(page.start_address()).is_none());
+
+        let frame = FRAME_ALLOCATOR.lock().allocate_frame().unwrap();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE;
+        unsafe { clone.map_to(page, frame, flags, &mut *FRAME_ALLOCATOR.lock()) }.unwrap().ignore();
+
+        assert_eq!(clone.translate_addr(page.start_address()), Some(frame.start_address()));
+        assert!(original.translate_addr(page.start_address()).is_none());
+    }
+
+    #[test_case]
+    fn clone_sees_heap_growth()
+    {
+        let phys_offset = KERNEL_MEM_INFO.lock().phys_offset;
+        let original_addr = unsafe { active_level_4_table_addr() };
+        let clone_addr = unsafe { clone_page_table(original_addr, phys_offset) }.unwrap();
+        let clone = page_table_at(clone_addr, phys_offset);
+
+        // Bigger than the whole initial heap, so it has to grow
+        let big: alloc::vec::Vec<u8> = alloc::vec![1; 2 * crate::allocator::KERNEL_HEAP_SIZE];
+        let addr = VirtAddr::new(&big[big.len() - 1] as *const u8 as u64);
+        assert_eq!(clone.translate_addr(addr), page_table_at(original_addr, phys_offset).translate_addr(addr));
+        assert!(clone.translate_addr(addr).is_some());
+    }
+
+    #[test_case]
+    fn mappings_are_coalesced()
+    {
+        let phys_offset = KERNEL_MEM_INFO.lock().phys_offset;
+        let clone_addr = unsafe { clone_page_table(active_level_4_table_addr(), phys_offset) }.unwrap();
+        let mut clone = page_table_at(clone_addr, phys_offset);
+
+        // Three adjacent pages, a hole, and one more page
+        let start = 0x5555_0000_0000;
+        let base_frame = FRAME_ALLOCATOR.lock().allocate_frame().unwrap().start_address();
+        let flags = PageTableFlags::PRESENT | PageTableFlags::WRITABLE | PageTableFlags::USER_ACCESSIBLE;
+        for i in [0, 1, 2, 4]
+        {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(start + i * 4096));
+            let frame = PhysFrame::containing_address(base_frame + i * 4096);
+            unsafe { clone.map_to(page, frame, flags, &mut *FRAME_ALLOCATOR.lock()) }.unwrap().ignore();
+        }
+
+        let mut mappings = mappings(clone_addr, phys_offset, start, start + 0x10000);
+        let first = mappings.next().unwrap();
+        assert_eq!(first, Mapping { virt: start, phys: base_frame.as_u64(), size: 3 * 4096, page_size: 4096, flags });
+        assert_eq!(alloc::format!("{}", first), "0x555500000000-0x555500003000 rwx user 4K");
+
+        let second = mappings.next().unwrap();
+        assert_eq!((second.virt, second.size), (start + 4 * 4096, 4096));
+        assert!(mappings.next().is_none());
+    }
+
+    #[test_case]
+    fn mappings_agree_with_translation()
+    {
+        let phys_offset = KERNEL_MEM_INFO.lock().phys_offset;
+        let l4_addr = unsafe { active_level_4_table_addr() };
+        let table = page_table_at(l4_addr, phys_offset);
+
+        let mut count = 0;
+        for mapping in mappings(l4_addr, phys_offset, 0, u64::MAX).take(64)
+        {
+            let last_byte = VirtAddr::new(mapping.virt + (mapping.size - 1));
+            assert_eq!(table.translate_addr(VirtAddr::new(mapping.virt)), Some(PhysAddr::new(mapping.phys)));
+            assert_eq!(table.translate_addr(last_byte), Some(PhysAddr::new(mapping.phys + mapping.size - 1)));
+            count += 1;
+        }
+        assert!(count > 0);
+    }
+
+    #[test_case]
+    fn user_range_checks()
+    {
+        // The kernel's own memory isn't user space, even if it's mapped
+        let kernel_addr = crate::allocator::KERNEL_HEAP_START as u64;
+        assert!(!is_user_range(kernel_addr, 8, false));
+        assert!(!is_user_range(USER_SPACE_END - 8, 16, false));
+        assert!(!is_user_range(u64::MAX - 8, 16, false));
+        assert!(!is_user_range(0x1000, 8, false));
+        assert!(is_user_range(0x1000, 0, true));
+    }
+}
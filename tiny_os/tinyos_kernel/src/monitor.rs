@@ -0,0 +1,301 @@
+// Debug monitor. Stops everything and reads commands from the keyboard
+// and the serial port to look at the state of the kernel. It's entered
+// with Alt+SysRq (Alt+PrintScreen), and after a panic. Nothing here
+// waits on a lock that the interrupted code might be holding.
+
+use crate::{allocator, memory, panic, process, slab};
+use crate::panic::PanicWriter;
+use core::fmt::Write;
+use core::sync::atomic::{AtomicBool, Ordering};
+use pc_keyboard::{layouts, DecodedKey, HandleControl, Keyboard, ScancodeSet1};
+use x86_64::instructions::port::Port;
+use x86_64::PhysAddr;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Entry
+{
+    SysRq,
+    Panic,
+}
+
+static IN_MONITOR: AtomicBool = AtomicBool::new(false);
+
+const MAX_LINE_LEN: usize = 80;
+const MAX_DUMP_LEN: u64 = 4096;
+
+/// Runs the monitor until it's told to continue. Interrupts stay disabled meanwhile.
+pub fn run(entry: Entry)
+{
+    if IN_MONITOR.swap(true, Ordering::SeqCst) { return; }
+    let interrupts_were_enabled = x86_64::instructions::interrupts::are_enabled();
+    x86_64::instructions::interrupts::disable();
+
+    let mut out = PanicWriter::new();
+    let mut input = Input::new();
+    let _ = writeln!(out, "\nEntering the debug monitor. Type help for a list of commands.");
+
+    loop
+    {
+        let _ = write!(out, "monitor> ");
+        let mut line: [u8; MAX_LINE_LEN] = [0; MAX_LINE_LEN];
+        let len = input.read_line(&mut out, &mut line);
+        let line = core::str::from_utf8(&line[..len]).unwrap_or("");
+
+        let mut args = line.split_whitespace();
+        let command = match args.next()
+        {
+            Some(command) => command,
+            None => continue,
+        };
+
+        match command
+        {
+            "help" =>
+            {
+                let _ = writeln!(out, "  tasks              tasks and their saved registers");
+                let _ = writeln!(out, "  pt <pid> [all]     page table mappings of a task (only user space without all)");
+                let _ = writeln!(out, "  mem                frame allocator, heap and slab cache usage");
+                let _ = writeln!(out, "  memmap             memory map from the boot loader");
+                let _ = writeln!(out, "  phys <addr> [len]  dump physical memory");
+                let _ = writeln!(out, "  continue           leave the monitor");
+            }
+            "tasks" => cmd_tasks(&mut out),
+            "pt" => cmd_page_table(&mut out, args.next().and_then(parse_number), args.next() == Some("all")),
+            "mem" => cmd_mem(&mut out),
+            "memmap" => cmd_memmap(&mut out),
+            "phys" => cmd_phys(&mut out, args.next().and_then(parse_number), args.next().and_then(parse_number).unwrap_or(64)),
+            "continue" | "c" =>
+            {
+                if entry == Entry::Panic {
+                    let _ = writeln!(out, "Can't continue after a panic, halting.");
+                }
+                break;
+            }
+            _ => { let _ = writeln!(out, "Unknown command: {}", command); }
+        }
+    }
+
+    IN_MONITOR.store(false, Ordering::SeqCst);
+    if interrupts_were_enabled {
+        x86_64::instructions::interrupts::enable();
+    }
+}
+
+// Commands
+
+fn cmd_tasks(out: &mut PanicWriter)
+{
+    let current = process::SCHEDULER.try_current_task().map(|(pid, _)| pid);
+    let found = process::SCHEDULER.try_for_each_task(|task| {
+        let _ = writeln!(out, "{} {} ({:?}, console {}){}", task.pid, task.name, task.state, task.console + 1,
+                         if Some(task.pid) == current { ", current" } else { "" });
+        let _ = panic::write_context(out, &task.ctx);
+    });
+
+    if !found {
+        let _ = writeln!(out, "The scheduler is locked.");
+    }
+}
+
+fn cmd_page_table(out: &mut PanicWriter, pid: Option<u64>, all: bool)
+{
+    let pid = match pid
+    {
+        Some(pid) => pid,
+        None => { let _ = writeln!(out, "Usage: pt <pid> [all]"); return; }
+    };
+
+    let mut page_table: Option<PhysAddr> = None;
+    process::SCHEDULER.try_for_each_task(|task| {
+        if task.pid == pid { page_table = Some(task.page_table); }
+    });
+
+    let (page_table, phys_offset) = match (page_table, memory::KERNEL_MEM_INFO.try_lock())
+    {
+        (Some(page_table), Some(info)) => (page_table, info.phys_offset),
+        (None, _) => { let _ = writeln!(out, "No task {} (or the scheduler is locked).", pid); return; }
+        (_, None) => { let _ = writeln!(out, "Memory info is locked."); return; }
+    };
+
+    let end = if all { u64::MAX } else { memory::USER_SPACE_END };
+    for mapping in memory::mappings(page_table, phys_offset, 0, end) {
+        let _ = writeln!(out, "  {} -> {:#x}", mapping, mapping.phys);
+    }
+}
+
+fn cmd_mem(out: &mut PanicWriter)
+{
+    match memory::FRAME_ALLOCATOR.try_lock()
+    {
+        Some(frame_allocator) =>
+        {
+            let (used, total) = frame_allocator.stats();
+            let _ = writeln!(out, "Frames: {} of {} used ({} KiB of {} KiB)", used, total, used * 4, total * 4);
+        }
+        None => { let _ = writeln!(out, "The frame allocator is locked."); }
+    }
+
+    match allocator::try_heap_stats()
+    {
+        Some(stats) =>
+        {
+            let _ = writeln!(out, "Heap: {} of {} bytes allocated ({} taken), grown {} times, limit {} bytes",
+                             stats.allocated, stats.total, stats.actual, stats.grows, stats.limit);
+        }
+        None => { let _ = writeln!(out, "The heap is locked."); }
+    }
+
+    let _ = writeln!(out, "Slab caches (size, slabs, objects in use, capacity, allocations):");
+    slab::try_for_each_cache(|stats| {
+        let _ = writeln!(out, "  {:>5} {:>5} {:>7} {:>7} {:>9}", stats.object_size, stats.slabs, stats.in_use, stats.capacity, stats.allocs);
+    });
+}
+
+fn cmd_memmap(out: &mut PanicWriter)
+{
+    let memory_map = memory::FRAME_ALLOCATOR.try_lock().and_then(|frame_allocator| frame_allocator.memory_map());
+    let memory_map = match memory_map
+    {
+        Some(memory_map) => memory_map,
+        None => { let _ = writeln!(out, "No memory map (or the frame allocator is locked)."); return; }
+    };
+
+    for region in memory_map.iter()
+    {
+        let _ = writeln!(out, "  {:#014x}-{:#014x}  {:?}", region.range.start_addr(), region.range.end_addr() - 1, region.region_type);
+    }
+}
+
+fn cmd_phys(out: &mut PanicWriter, addr: Option<u64>, len: u64)
+{
+    let addr = match addr
+    {
+        Some(addr) => addr,
+        None => { let _ = writeln!(out, "Usage: phys <addr> [len]"); return; }
+    };
+    let len = core::cmp::min(len, MAX_DUMP_LEN);
+
+    // Only what the boot loader told us about is mapped
+    let memory_map = memory::FRAME_ALLOCATOR.try_lock().and_then(|frame_allocator| frame_allocator.memory_map());
+    let phys_end = memory_map.map(|m| m.iter().map(|r| r.range.end_addr()).max().unwrap_or(0)).unwrap_or(0);
+    let phys_offset = memory::KERNEL_MEM_INFO.try_lock().map(|info| info.phys_offset);
+    let phys_offset = match phys_offset
+    {
+        Some(phys_offset) if addr.saturating_add(len) <= phys_end => phys_offset,
+        _ => { let _ = writeln!(out, "Can't read {:#x}..{:#x}.", addr, addr.saturating_add(len)); return; }
+    };
+
+    let mut line_addr = addr;
+    while line_addr < addr + len
+    {
+        let line_len = core::cmp::min(16, addr + len - line_addr);
+        let mut bytes: [u8; 16] = [0; 16];
+        for (i, byte) in bytes[..line_len as usize].iter_mut().enumerate()
+        {
+            *byte = unsafe { (phys_offset + line_addr + i as u64).as_ptr::<u8>().read_volatile() };
+        }
+
+        let _ = write!(out, "  {:#014x}  ", line_addr);
+        for (i, byte) in bytes.iter().enumerate()
+        {
+            if i < line_len as usize { let _ = write!(out, "{:02x} ", byte); }
+            else { let _ = write!(out, "   "); }
+        }
+        for &byte in &bytes[..line_len as usize] {
+            let _ = write!(out, "{}", if byte.is_ascii_graphic() || byte == b' ' { byte as char } else { '.' });
+        }
+        let _ = writeln!(out);
+
+        line_addr += line_len;
+    }
+}
+
+// Decimal, or hex with 0x
+fn parse_number(s: &str) -> Option<u64>
+{
+    if let Some(hex) = s.strip_prefix("0x") {
+        return u64::from_str_radix(hex, 16).ok();
+    }
+    return s.parse().ok();
+}
+
+// Input. The keyboard controller and the serial port are polled directly,
+// since their interrupts don't get through while the monitor runs.
+
+struct Input
+{
+    keyboard: Keyboard<layouts::Us104Key, ScancodeSet1>,
+}
+
+impl Input
+{
+    fn new() -> Self
+    {
+        return Input {
+            keyboard: Keyboard::new(ScancodeSet1::new(), layouts::Us104Key, HandleControl::Ignore),
+        };
+    }
+
+    fn poll_keyboard(&mut self) -> Option<u8>
+    {
+        const OUTPUT_FULL: u8 = 1 << 0;
+        const FROM_MOUSE:  u8 = 1 << 5;
+        let mut status: Port<u8> = Port::new(0x64);
+        let mut data: Port<u8> = Port::new(0x60);
+
+        let status = unsafe { status.read() };
+        if status & OUTPUT_FULL == 0 { return None; }
+        let scancode = unsafe { data.read() };
+        if status & FROM_MOUSE != 0 { return None; }
+
+        let event = self.keyboard.add_byte(scancode).ok()??;
+        return match self.keyboard.process_keyevent(event)?
+        {
+            DecodedKey::Unicode(c) if c.is_ascii() => Some(c as u8),
+            _ => None,
+        };
+    }
+
+    fn poll_serial(&mut self) -> Option<u8>
+    {
+        const DATA_READY: u8 = 1 << 0;
+        let mut line_status: Port<u8> = Port::new(crate::serial::COM1_BASE + 5);
+        let mut data: Port<u8> = Port::new(crate::serial::COM1_BASE);
+
+        unsafe
+        {
+            if line_status.read() & DATA_READY == 0 { return None; }
+            return Some(data.read());
+        }
+    }
+
+    // Reads a line with echo and backspace, returns its length
+    fn read_line(&mut self, out: &mut PanicWriter, line: &mut [u8; MAX_LINE_LEN]) -> usize
+    {
+        let mut len = 0;
+        loop
+        {
+            let byte = match self.poll_keyboard().or_else(|| self.poll_serial())
+            {
+                Some(byte) => byte,
+                None => { core::hint::spin_loop(); continue; }
+            };
+
+            match byte
+            {
+                b'\n' | b'\r' => { let _ = writeln!(out); return len; }
+                0x08 | 0x7F =>
+                {
+                    if len > 0 { len -= 1; let _ = write!(out, "\x08 \x08"); }
+                }
+                0x20..=0x7E if len < MAX_LINE_LEN =>
+                {
+                    line[len] = byte;
+                    len += 1;
+                    let _ = write!(out, "{}", byte as char);
+                }
+                _ => {},
+            }
+        }
+    }
+}
@@ -0,0 +1,115 @@
+// What happens when the kernel panics. The report goes to the serial
+// port first, since that's what's captured when running under QEMU
+// without a screen, and then to the screen. Nothing here waits on a
+// lock that the panicking code might be holding.
+
+use crate::{backtrace, console, exit_qemu, hlt_loop, monitor, process, serial, vga_buffer, QemuExitCode};
+use core::fmt::{self, Write};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicBool, Ordering};
+use uart_16550::SerialPort;
+
+static PANICKING: AtomicBool = AtomicBool::new(false);
+static EXIT_QEMU_ON_PANIC: AtomicBool = AtomicBool::new(false);
+
+/// Makes panics exit QEMU with a failure code instead of halting,
+/// so that automated runs don't hang until they time out.
+pub fn set_exit_qemu_on_panic(exit: bool)
+{
+    EXIT_QEMU_ON_PANIC.store(exit, Ordering::Relaxed);
+}
+
+// Writes to both the serial port and the screen
+pub(crate) struct PanicWriter
+{
+    serial: SerialPort,
+}
+
+impl PanicWriter
+{
+    /// Doesn't wait on the serial port's lock or the screen's. If the
+    /// screen is locked, the output only goes to the serial port.
+    pub(crate) fn new() -> Self
+    {
+        return PanicWriter { serial: unsafe { SerialPort::new(serial::COM1_BASE) } };
+    }
+}
+
+impl fmt::Write for PanicWriter
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result
+    {
+        for byte in s.bytes()
+        {
+            if byte == b'\n' { self.serial.send_raw(b'\r'); }
+            self.serial.send_raw(byte);
+        }
+
+        if serial::console_mode() != serial::ConsoleMode::Redirect
+        {
+            if let Some(mut writer) = vga_buffer::WRITERS[console::active()].try_lock() {
+                let _ = writer.write_str(s);
+            }
+        }
+        return Ok(());
+    }
+}
+
+pub fn handle_panic(info: &PanicInfo) -> !
+{
+    x86_64::instructions::interrupts::disable();
+
+    // Don't use the serial port's lock, whoever holds it isn't going to release it
+    let mut serial = unsafe { SerialPort::new(serial::COM1_BASE) };
+
+    if PANICKING.swap(true, Ordering::SeqCst)
+    {
+        // Panicked while reporting a panic, say as little as possible
+        let _ = serial.write_str("\r\nPanicked again while handling a panic.\r\n");
+        finish();
+    }
+
+    unsafe { vga_buffer::WRITERS[console::active()].force_unlock() };
+    let mut out = PanicWriter { serial };
+
+    let _ = writeln!(out, "\nKERNEL PANIC: {}", info);
+
+    match process::SCHEDULER.try_current_task()
+    {
+        Some((pid, ctx)) =>
+        {
+            let _ = writeln!(out, "Current task: {}", pid);
+            let _ = writeln!(out, "Saved context:");
+            let _ = write_context(&mut out, &ctx);
+        }
+        None => { let _ = writeln!(out, "No current task (or the scheduler is locked)."); }
+    }
+
+    let _ = writeln!(out, "Backtrace:");
+    let _ = backtrace::write_backtrace(&mut out, backtrace::current_frame_pointer());
+
+    // Automated runs have no one to type into the monitor
+    if !EXIT_QEMU_ON_PANIC.load(Ordering::Relaxed) {
+        monitor::run(monitor::Entry::Panic);
+    }
+
+    finish();
+}
+
+pub(crate) fn write_context(out: &mut impl Write, ctx: &process::Context) -> fmt::Result
+{
+    writeln!(out, "  rip {:#018x}  rsp {:#018x}  rbp {:#018x}  rflags {:#x}", ctx.rip, ctx.rsp, ctx.rbp, ctx.rflags)?;
+    writeln!(out, "  rax {:#018x}  rbx {:#018x}  rcx {:#018x}  rdx {:#018x}", ctx.rax, ctx.rbx, ctx.rcx, ctx.rdx)?;
+    writeln!(out, "  rsi {:#018x}  rdi {:#018x}  r8  {:#018x}  r9  {:#018x}", ctx.rsi, ctx.rdi, ctx.r8, ctx.r9)?;
+    writeln!(out, "  r10 {:#018x}  r11 {:#018x}  r12 {:#018x}  r13 {:#018x}", ctx.r10, ctx.r11, ctx.r12, ctx.r13)?;
+    writeln!(out, "  r14 {:#018x}  r15 {:#018x}  cs {:#x}  ss {:#x}", ctx.r14, ctx.r15, ctx.cs, ctx.ss)?;
+    return Ok(());
+}
+
+fn finish() -> !
+{
+    if EXIT_QEMU_ON_PANIC.load(Ordering::Relaxed) {
+        exit_qemu(QemuExitCode::Failed);
+    }
+    hlt_loop();
+}
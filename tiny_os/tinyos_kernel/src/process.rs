@@ -3,6 +3,7 @@ pub const USER_PROGRAM_SHELL: &[u8] = include_bytes!("shell");
 //pub const USER_PROGRAM_REC_FIB: &[u8] = include_bytes!("rec_fib");
 
 use crate::println;
+use crate::{debug, error, trace, warn};
 use crate::print;
 use crate::base::*;
 use crate::memory;
@@ -10,8 +11,13 @@ use crate::interrupts;
 use core::mem::{size_of};
 use core::arch::asm;
 use core::{pin::Pin};
-use alloc::{boxed::Box, vec::Vec};
+use alloc::{boxed::Box, string::String, vec::Vec};
 use crate::allocator;
+use crate::sched::{self, SchedulingPolicy};
+use crate::time;
+use crate::console::{self, NUM_CONSOLES};
+use crate::signal::{self, Disposition, SignalState};
+use core::sync::atomic::{AtomicBool, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
 
@@ -63,8 +69,10 @@ use x86_64::
 
 pub const USER_STACK_START: u64 = 0x800000;
 pub const USER_STACK_NUM_PAGES: u64 = 50;
+/// How big the heap of a task (see UserHeap) can grow.
+pub const USER_HEAP_MAX_SIZE: u64 = 64 * 1024 * 1024;  // 64 MiB
 
-pub fn create_task(blob: &[u8], phys_offset: VirtAddr, kernel_pagetable_phys_addr: PhysAddr, arg0: u64) -> Option<Task>
+pub fn create_task(name: &str, blob: &[u8], phys_offset: VirtAddr, kernel_pagetable_phys_addr: PhysAddr, arg0: u64) -> Option<Task>
 {
     let elf_header = parse_elf_binary(blob);
     if elf_header.is_none() { return None; }
@@ -72,19 +80,19 @@ pub fn create_task(blob: &[u8], phys_offset: VirtAddr, kernel_pagetable_phys_add
 
     if elf_header.is_64_bits != 2
     {
-        println!("32 bits format is not supported.");
+        error!("32 bits format is not supported.");
         return None;
     }
 
     if elf_header.endianness != 1
     {
-        println!("Big endian format is not supported.");
+        error!("Big endian format is not supported.");
         return None;
     }
 
     if elf_header.bin_type != 2
     {
-        println!("Unsupported binary type used. Only executables are supported.");
+        error!("Unsupported binary type used. Only executables are supported.");
         return None;
     }
 
@@ -92,6 +100,8 @@ pub fn create_task(blob: &[u8], phys_offset: VirtAddr, kernel_pagetable_phys_add
     let pt_virt = phys_offset + pt.as_u64();
     let pt_ptr: *mut PageTable = pt_virt.as_mut_ptr();
     let mut process_mapper = unsafe { OffsetPageTable::new(&mut *pt_ptr, phys_offset) };
+    // The heap goes after the last page of the segments
+    let mut segments_end: u64 = 0;
 
     for i in 0..elf_header.pht_num_entries
     {
@@ -108,6 +118,7 @@ pub fn create_task(blob: &[u8], phys_offset: VirtAddr, kernel_pagetable_phys_add
             let end_page:   Page = Page::containing_address(VirtAddr::new(vaddr+size_mem));
             let page_range = Page::range_inclusive(start_page, end_page);
             assert!(size_file <= size_mem);
+            segments_end = core::cmp::max(segments_end, end_page.start_address().as_u64() + 4096);
 
             //println!("{}", size_mem);
 
@@ -157,7 +168,7 @@ pub fn create_task(blob: &[u8], phys_offset: VirtAddr, kernel_pagetable_phys_add
         }
         else if program_header.segment_type == 2  // PT_DYNAMIC segment
         {
-            println!("There is a segment that requires dynamic linking, which is not yet supported.");
+            error!("There is a segment that requires dynamic linking, which is not yet supported.");
             return None;
         }
         else
@@ -170,6 +181,8 @@ pub fn create_task(blob: &[u8], phys_offset: VirtAddr, kernel_pagetable_phys_add
     for i in 0..USER_STACK_NUM_PAGES
     {
         let stack_phys_frame = memory::FRAME_ALLOCATOR.lock().allocate_frame().unwrap();
+        // Frames can come from tasks that exited, don't show what they had there
+        unsafe { core::ptr::write_bytes((phys_offset + stack_phys_frame.start_address().as_u64()).as_mut_ptr::<u8>(), 0, 4096) };
         let stack_virt_page = Page::containing_address(VirtAddr::new(USER_STACK_START + i * 4096));
         let stack_flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE;
         unsafe {
@@ -178,12 +191,21 @@ pub fn create_task(blob: &[u8], phys_offset: VirtAddr, kernel_pagetable_phys_add
     }
 
     return Some(Task {
+        pid: 0,  // Assigned by the scheduler
+        parent: None,
+        name: String::from(name),
+        state: TaskState::Ready,
         started: false,
         ctx: Default::default(),
         start_instr: VirtAddr::new(elf_header.entry_vaddr),
         stack_end: VirtAddr::new(USER_STACK_START + USER_STACK_NUM_PAGES * 4096 - 1),
         page_table: pt,
         arg0,
+        console: 0,
+        stats: TaskStats::default(),
+        signals: SignalState::default(),
+        trace_syscalls: false,
+        heap: UserHeap::new(segments_end),
     });
 }
 
@@ -207,7 +229,7 @@ pub fn parse_elf_binary(blob: &[u8]) -> Option<ElfHeader>
 
     if magic_bytes != "\u{7F}ELF"
     {
-        println!("The supplied binary is not ELF.");
+        error!("The supplied binary is not ELF.");
         return None;
     }
 
@@ -249,8 +271,53 @@ pub fn parse_elf_program_header(blob: &[u8]) -> ProgramHeader
 
 // Scheduler
 
+pub type Pid = u64;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum TaskState
+{
+    Ready,
+    Running,
+    Blocked(WaitReason),
+    Stopped,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum WaitReason
+{
+    Input(usize),  // Waiting on the tty of a console
+    Child(Pid),
+}
+
+// Status reported by the wait syscall, encoded like POSIX does.
+// NOTE: This should be kept up to date along with its
+// counterpart in the usercode library.
+pub fn exited_status(code: u64) -> u64    { return (code & 0xFF) << 8; }
+pub fn signaled_status(sig: u32) -> u64   { return sig as u64; }
+pub fn stopped_status(sig: u32) -> u64    { return ((sig as u64) << 8) | 0x7F; }
+
+/// Returned by wait when the child hasn't changed state yet.
+pub const WAIT_PENDING: u64 = u64::MAX - 1;
+/// Returned by wait when there's no such child.
+pub const WAIT_NO_CHILD: u64 = u64::MAX;
+/// Option of wait, to return WAIT_PENDING instead of blocking.
+pub const WAIT_NO_HANG: u64 = 1 << 0;
+
+#[derive(Default, Clone, Copy, Debug)]
+pub struct TaskStats
+{
+    pub user_ticks: u64,
+    pub kernel_cycles: u64,  // Time spent in syscalls, measured with the TSC
+    pub context_switches: u64,
+    pub start_tick: u64,
+}
+
 pub struct Task
 {
+    pub pid: Pid,
+    pub parent: Option<Pid>,
+    pub name: String,
+    pub state: TaskState,
     pub started: bool,
 
     pub ctx: Context,
@@ -258,19 +325,295 @@ pub struct Task
     pub start_instr: VirtAddr,
     pub stack_end:   VirtAddr,
     pub page_table:  PhysAddr,
-    pub arg0:        u64
+    pub arg0:        u64,
+    /// Virtual console the task reads from and prints to.
+    pub console:     usize,
+
+    pub stats: TaskStats,
+    pub signals: SignalState,
+    /// Whether its syscalls are logged, see strace.
+    pub trace_syscalls: bool,
+    pub heap: UserHeap,
+}
+
+/// Memory the task gets with brk and sbrk, which starts right after its
+/// segments. Pages are mapped as the break goes up, and stay mapped when it
+/// goes down until the task exits, so that they're reused if it goes up again.
+#[derive(Clone, Copy)]
+pub struct UserHeap
+{
+    pub start: u64,
+    /// End of the heap.
+    pub brk: u64,
+    mapped_end: u64,
+}
+
+impl UserHeap
+{
+    pub fn new(start: u64) -> Self
+    {
+        return UserHeap { start, brk: start, mapped_end: start };
+    }
+
+    /// Moves the break, mapping pages in the active page table as needed.
+    /// Returns false (and leaves it alone) if it would go below the start
+    /// or past USER_HEAP_MAX_SIZE, or if there's no memory left.
+    pub fn set_break(&mut self, new_brk: u64, phys_offset: VirtAddr) -> bool
+    {
+        if new_brk < self.start || new_brk - self.start > USER_HEAP_MAX_SIZE { return false; }
+
+        let new_mapped_end = (new_brk + 4095) & !4095;
+        let mut mapper = unsafe { OffsetPageTable::new(memory::active_level_4_table(phys_offset), phys_offset) };
+        let old_mapped_end = self.mapped_end;
+        while self.mapped_end < new_mapped_end
+        {
+            let page = Page::<Size4KiB>::containing_address(VirtAddr::new(self.mapped_end));
+            let frame = match memory::FRAME_ALLOCATOR.lock().allocate_frame()
+            {
+                Some(frame) => frame,
+                None => return false,
+            };
+            let flags = PageTableFlags::PRESENT | PageTableFlags::USER_ACCESSIBLE | PageTableFlags::WRITABLE | PageTableFlags::NO_EXECUTE;
+            unsafe { core::ptr::write_bytes((phys_offset + frame.start_address().as_u64()).as_mut_ptr::<u8>(), 0, 4096) };
+            match unsafe { mapper.map_to(page, frame, flags, &mut *memory::FRAME_ALLOCATOR.lock()) }
+            {
+                Ok(flush) => flush.flush(),
+                Err(_) => return false,
+            }
+            self.mapped_end += 4096;
+        }
+
+        // Memory that was given back and is taken again starts out zeroed too
+        if new_brk > self.brk && self.brk < old_mapped_end
+        {
+            let reused_end = core::cmp::min(new_brk, old_mapped_end);
+            unsafe { core::ptr::write_bytes(self.brk as *mut u8, 0, (reused_end - self.brk) as usize) };
+        }
+
+        self.brk = new_brk;
+        return true;
+    }
 }
 
 impl Drop for Task
 {
-    fn drop(self: &mut Self) {}  // TODO
+    // Gives back its page table and user memory
+    fn drop(&mut self)
+    {
+        let (phys_offset, kernel_page_table) = {
+            let mem_info = memory::KERNEL_MEM_INFO.lock();
+            (mem_info.phys_offset, mem_info.kernel_page_table_phys_addr)
+        };
+
+        unsafe
+        {
+            // A task that exits is still running on its page table
+            if memory::active_level_4_table_addr() == self.page_table {
+                memory::activate_page_table(kernel_page_table);
+            }
+            memory::free_page_table(self.page_table, phys_offset);
+        }
+    }
 }
 
-#[derive(Default)]
 pub struct Scheduler
 {
-    tasks: Mutex<Vec<Task>>,
-    cur_task: Mutex<Option<usize>>,
+    inner: Mutex<SchedulerInner>,
+    // Set while run_next_task is waiting for a task to become ready,
+    // so that the timer interrupt knows it has nothing to switch away from.
+    idle: AtomicBool,
+}
+
+struct SchedulerInner
+{
+    tasks: Vec<Task>,
+    cur_task: Option<Pid>,
+    next_pid: Pid,
+    policy: Box<dyn SchedulingPolicy>,
+    kernel_entry_tsc: u64,  // When the current syscall started
+
+    // The task that receives Ctrl-C and Ctrl-Z from the keyboard, for each console.
+    foreground: [Option<Pid>; NUM_CONSOLES],
+    // State changes of children that their parent hasn't waited for yet.
+    child_statuses: Vec<ChildStatus>,
+}
+
+struct ChildStatus
+{
+    pid: Pid,
+    parent: Pid,
+    status: u64,
+}
+
+impl SchedulerInner
+{
+    fn task_mut(&mut self, pid: Pid) -> Option<&mut Task>
+    {
+        return self.tasks.iter_mut().find(|t| t.pid == pid);
+    }
+
+    // Charges the time spent since enter_kernel to the current task.
+    fn charge_kernel_time(&mut self)
+    {
+        if self.kernel_entry_tsc == 0 { return; }
+
+        let elapsed = time::read_tsc().saturating_sub(self.kernel_entry_tsc);
+        self.kernel_entry_tsc = 0;
+        if let Some(cur_task) = self.cur_task
+        {
+            if let Some(task) = self.task_mut(cur_task) {
+                task.stats.kernel_cycles += elapsed;
+            }
+        }
+    }
+
+    // Acts on the pending signals of a task that's about to return to
+    // user mode with the given context, which is modified if a handler
+    // has to run. The address space of the task must be active.
+    fn process_signals(&mut self, pid: Pid, ctx: &mut Context, started: bool) -> SignalOutcome
+    {
+        let task = match self.task_mut(pid)
+        {
+            Some(task) => task,
+            None => return SignalOutcome::Resume,
+        };
+
+        while let Some(sig) = task.signals.next_deliverable()
+        {
+            let disposition = task.signals.disposition(sig);
+
+            // Handlers can only interrupt a task that's already running user code.
+            if let Disposition::Handler(_) = disposition {
+                if !started { return SignalOutcome::Resume; }
+            }
+
+            task.signals.pending &= !signal::sig_bit(sig);
+            match disposition
+            {
+                Disposition::Ignore | Disposition::Continue => {},
+                Disposition::Terminate => return SignalOutcome::Terminated(sig),
+                Disposition::Stop      => return SignalOutcome::Stopped(sig),
+                Disposition::Handler(handler) =>
+                {
+                    let saved_blocked = task.signals.blocked;
+                    task.signals.blocked |= signal::sig_bit(sig);
+                    // A stack the frame can't go on is fatal, like on Unix
+                    if !unsafe { signal::push_signal_frame(ctx, sig, handler, task.signals.restorer, saved_blocked) } {
+                        return SignalOutcome::Terminated(signal::SIGSEGV);
+                    }
+                    return SignalOutcome::Resume;
+                }
+            }
+        }
+
+        return SignalOutcome::Resume;
+    }
+
+    fn remove_task(&mut self, pid: Pid, status: u64)
+    {
+        #[cfg(feature = "user_tests")]
+        crate::user_tests::task_removed(pid, status);
+
+        self.report_status(pid, status);
+        self.tasks.retain(|t| t.pid != pid);
+        self.policy.task_removed(pid);
+        if self.cur_task == Some(pid) {
+            self.cur_task = None;
+        }
+
+        // Statuses of its own children won't be collected by anyone
+        self.child_statuses.retain(|s| s.parent != pid);
+    }
+
+    fn stop_task(&mut self, pid: Pid, sig: u32)
+    {
+        if let Some(task) = self.task_mut(pid) {
+            task.state = TaskState::Stopped;
+        }
+        self.policy.task_removed(pid);
+        if self.cur_task == Some(pid) {
+            self.cur_task = None;
+        }
+
+        self.report_status(pid, stopped_status(sig));
+    }
+
+    // Records a state change for the parent to pick up with wait,
+    // and gives the console back to the parent if needed.
+    fn report_status(&mut self, pid: Pid, status: u64)
+    {
+        let parent = self.tasks.iter().find(|t| t.pid == pid).and_then(|t| t.parent);
+        let parent = parent.filter(|&parent| self.tasks.iter().any(|t| t.pid == parent));
+
+        for foreground in self.foreground.iter_mut()
+        {
+            if *foreground == Some(pid) {
+                *foreground = parent;
+            }
+        }
+
+        if let Some(parent) = parent
+        {
+            self.child_statuses.retain(|s| s.pid != pid);
+            self.child_statuses.push(ChildStatus { pid, parent, status });
+
+            let parent_task = self.task_mut(parent).unwrap();
+            if parent_task.state == TaskState::Blocked(WaitReason::Child(pid))
+            {
+                parent_task.state = TaskState::Ready;
+                self.policy.task_ready(parent);
+            }
+        }
+    }
+}
+
+enum SignalOutcome
+{
+    Resume,
+    Terminated(u32),
+    Stopped(u32),
+}
+
+/// Process information as returned by the list_processes syscall.
+// NOTE: This should be kept up to date along with its
+// counterpart in the usercode library.
+#[derive(Default, Clone, Copy, Debug)]
+#[repr(C)]
+pub struct ProcessInfo
+{
+    pub pid: u64,
+    pub state: u64,
+    pub user_ticks: u64,
+    pub kernel_ticks: u64,
+    pub context_switches: u64,
+    pub start_tick: u64,
+    pub console: u64,
+    pub name: [u8; PROCESS_NAME_LEN],
+}
+
+pub const PROCESS_NAME_LEN: usize = 16;
+
+#[repr(u64)]
+pub enum ProcessState
+{
+    Ready = 0,
+    Running = 1,
+    Blocked = 2,
+    Stopped = 3,
+}
+
+impl TaskState
+{
+    pub fn as_process_state(self) -> ProcessState
+    {
+        return match self
+        {
+            TaskState::Ready => ProcessState::Ready,
+            TaskState::Running => ProcessState::Running,
+            TaskState::Blocked(_) => ProcessState::Blocked,
+            TaskState::Stopped => ProcessState::Stopped,
+        };
+    }
 }
 
 lazy_static! {
@@ -281,110 +624,520 @@ impl Scheduler
 {
     pub fn new() -> Self
     {
-        return Scheduler::default();
+        return Scheduler {
+            inner: Mutex::new(SchedulerInner {
+                tasks: Vec::new(),
+                cur_task: None,
+                next_pid: 1,
+                policy: Box::new(sched::RoundRobin::new()),
+                kernel_entry_tsc: 0,
+                foreground: [None; NUM_CONSOLES],
+                child_statuses: Vec::new(),
+            }),
+            idle: AtomicBool::new(false),
+        };
+    }
+
+    /// Replaces the scheduling policy. Tasks that are already
+    /// waiting to run are handed over to the new policy.
+    pub fn set_policy(&self, policy: Box<dyn SchedulingPolicy>)
+    {
+        let mut inner = self.inner.lock();
+        inner.policy = policy;
+
+        let ready: Vec<Pid> = inner.tasks.iter().filter(|t| t.state == TaskState::Ready).map(|t| t.pid).collect();
+        for pid in ready {
+            inner.policy.task_ready(pid);
+        }
+    }
+
+    pub fn policy_name(&self) -> &'static str
+    {
+        return self.inner.lock().policy.name();
+    }
+
+    pub fn schedule_task(&self, mut task: Task) -> Pid
+    {
+        let mut inner = self.inner.lock();
+        let pid = inner.next_pid;
+        inner.next_pid += 1;
+
+        task.pid = pid;
+        task.parent = inner.cur_task;
+        task.state = TaskState::Ready;
+        task.stats.start_tick = time::ticks();
+
+        // Tasks stay on the console they were launched from,
+        // and tasks created by traced ones are traced too
+        if let Some(cur_task) = inner.cur_task
+        {
+            if let Some(parent) = inner.task_mut(cur_task) {
+                task.console = parent.console;
+                task.trace_syscalls |= parent.trace_syscalls;
+            }
+        }
+
+        let console = task.console;
+        debug!("Scheduled task {} ({}) on console {}", pid, task.name, console + 1);
+        inner.tasks.push(task);
+        inner.policy.task_ready(pid);
+
+        if inner.foreground[console].is_none() {
+            inner.foreground[console] = Some(pid);
+        }
+        return pid;
+    }
+
+    pub fn foreground(&self, console: usize) -> Option<Pid>
+    {
+        return self.inner.lock().foreground[console];
+    }
+
+    /// Gives the console of a task to it. Returns false if there's no such task.
+    pub fn set_foreground(&self, pid: Pid) -> bool
+    {
+        let mut inner = self.inner.lock();
+        let console = match inner.task_mut(pid)
+        {
+            Some(task) => task.console,
+            None => return false,
+        };
+
+        inner.foreground[console] = Some(pid);
+        return true;
+    }
+
+    /// Returns the status of the child if it has exited, was killed
+    /// or stopped since the last call, WAIT_PENDING if it's still
+    /// running and WAIT_NO_CHILD if the current task has no such child.
+    pub fn take_child_status(&self, pid: Pid) -> u64
+    {
+        let mut inner = self.inner.lock();
+        let cur_task = match inner.cur_task
+        {
+            Some(cur_task) => cur_task,
+            None => return WAIT_NO_CHILD,
+        };
+
+        if let Some(idx) = inner.child_statuses.iter().position(|s| s.pid == pid && s.parent == cur_task) {
+            return inner.child_statuses.remove(idx).status;
+        }
+
+        let is_child = inner.tasks.iter().any(|t| t.pid == pid && t.parent == Some(cur_task));
+        return if is_child { WAIT_PENDING } else { WAIT_NO_CHILD };
+    }
+
+    pub fn current_pid(&self) -> Option<Pid>
+    {
+        return self.inner.lock().cur_task;
+    }
+
+    /// Like current_pid, but returns None if the scheduler is locked.
+    pub fn try_current_pid(&self) -> Option<Pid>
+    {
+        return self.inner.try_lock()?.cur_task;
+    }
+
+    /// The pid of the current task if its syscalls are traced.
+    pub fn current_traced_pid(&self) -> Option<Pid>
+    {
+        let mut inner = self.inner.lock();
+        let cur_task = inner.cur_task?;
+        return inner.task_mut(cur_task).filter(|task| task.trace_syscalls).map(|task| task.pid);
+    }
+
+    /// Turns syscall tracing on or off. Returns false if there's no such task.
+    pub fn set_tracing(&self, pid: Pid, enable: bool) -> bool
+    {
+        let mut inner = self.inner.lock();
+        return match inner.task_mut(pid)
+        {
+            Some(task) => { task.trace_syscalls = enable; true }
+            None => false,
+        };
     }
 
-    pub fn schedule_task(&self, task: Task)
+    /// The current task and the context it was last switched out with. Doesn't
+    /// wait for the lock (returns None if it's taken), so it can be used while panicking.
+    pub fn try_current_task(&self) -> Option<(Pid, Context)>
     {
-        println!("About to schedule task");
-        self.tasks.lock().push(task);
+        let inner = self.inner.try_lock()?;
+        let pid = inner.cur_task?;
+        let task = inner.tasks.iter().find(|t| t.pid == pid)?;
+        return Some((pid, task.ctx));
+    }
+
+    /// Calls `f` on every task. Doesn't wait for the lock, so it can be
+    /// used while debugging or panicking. Returns false if it's taken.
+    pub fn try_for_each_task(&self, mut f: impl FnMut(&Task)) -> bool
+    {
+        let inner = match self.inner.try_lock()
+        {
+            Some(inner) => inner,
+            None => return false,
+        };
+
+        for task in inner.tasks.iter() {
+            f(task);
+        }
+        return true;
     }
 
     pub fn get_current_task_arg0(&self) -> u64
     {
-        let tasks = self.tasks.lock();
-        let cur_task_opt = self.cur_task.lock();
-        if let Some(cur_task) = *cur_task_opt {
-            return tasks[cur_task].arg0;
+        let mut inner = self.inner.lock();
+        if let Some(cur_task) = inner.cur_task
+        {
+            if let Some(task) = inner.task_mut(cur_task) {
+                return task.arg0;
+            }
         }
 
         return 0;
     }
 
-    pub fn remove_current_task(&self)
+    pub fn process_list(&self) -> Vec<ProcessInfo>
+    {
+        let inner = self.inner.lock();
+        let mut res = Vec::with_capacity(inner.tasks.len());
+        for task in inner.tasks.iter()
+        {
+            let mut info = ProcessInfo {
+                pid: task.pid,
+                state: task.state.as_process_state() as u64,
+                user_ticks: task.stats.user_ticks,
+                kernel_ticks: time::tsc_to_ticks(task.stats.kernel_cycles),
+                context_switches: task.stats.context_switches,
+                start_tick: task.stats.start_tick,
+                console: task.console as u64,
+                name: [0; PROCESS_NAME_LEN],
+            };
+
+            let name_len = core::cmp::min(task.name.len(), PROCESS_NAME_LEN);
+            info.name[..name_len].copy_from_slice(&task.name.as_bytes()[..name_len]);
+            res.push(info);
+        }
+
+        return res;
+    }
+
+    /// Marks the start of a syscall, for CPU time accounting.
+    pub fn enter_kernel(&self)
     {
-        let mut tasks = self.tasks.lock();
-        let mut cur_task_opt = self.cur_task.lock();
+        self.inner.lock().kernel_entry_tsc = time::read_tsc();
+    }
 
-        if let Some(cur_task) = *cur_task_opt
+    /// Marks the end of a syscall, for CPU time accounting.
+    pub fn leave_kernel(&self)
+    {
+        self.inner.lock().charge_kernel_time();
+    }
+
+    pub fn remove_current_task(&self, exit_code: u64)
+    {
+        let mut inner = self.inner.lock();
+
+        if let Some(cur_task) = inner.cur_task {
+            inner.remove_task(cur_task, exited_status(exit_code));
+        }
+    }
+
+    /// Marks a signal as pending for the task. Returns false if there's no
+    /// such task. Signal 0 only checks whether the task exists.
+    pub fn send_signal(&self, pid: Pid, sig: u32) -> bool
+    {
+        if sig >= signal::NUM_SIGNALS { return false; }
+
+        let mut inner = self.inner.lock();
+        let inner = &mut *inner;
+        let is_current = inner.cur_task == Some(pid);
+        let task = match inner.tasks.iter_mut().find(|t| t.pid == pid)
+        {
+            Some(task) => task,
+            None => return false,
+        };
+
+        if sig == 0 { return true; }
+
+        // Stopping and continuing cancel each other out
+        let stop_signals = signal::sig_bit(signal::SIGSTOP) | signal::sig_bit(signal::SIGTSTP);
+        if sig == signal::SIGCONT
+        {
+            task.signals.pending &= !stop_signals;
+            if task.state == TaskState::Stopped
+            {
+                task.state = TaskState::Ready;
+                inner.policy.task_ready(pid);
+
+                // The parent shouldn't see it as stopped anymore
+                inner.child_statuses.retain(|s| s.pid != pid || s.status & 0xFF != 0x7F);
+            }
+        }
+        else if signal::sig_bit(sig) & stop_signals != 0
+        {
+            task.signals.pending &= !signal::sig_bit(signal::SIGCONT);
+        }
+
+        task.signals.pending |= signal::sig_bit(sig);
+
+        // The current task will see the signal on its way back to user
+        // mode. Others might need to be woken up to see it at all.
+        if !is_current && task.signals.next_deliverable().is_some()
+        {
+            let wake = match task.state
+            {
+                TaskState::Blocked(_) => true,
+                TaskState::Stopped => sig == signal::SIGKILL,
+                _ => false,
+            };
+
+            if wake
+            {
+                task.state = TaskState::Ready;
+                inner.policy.task_ready(pid);
+            }
+        }
+
+        return true;
+    }
+
+    pub fn current_has_pending_signals(&self) -> bool
+    {
+        let mut inner = self.inner.lock();
+        if let Some(cur_task) = inner.cur_task
         {
-            if cur_task < tasks.len()
+            if let Some(task) = inner.task_mut(cur_task) {
+                return task.signals.next_deliverable().is_some();
+            }
+        }
+
+        return false;
+    }
+
+    /// Calls `f` on the signal state of the current task.
+    pub fn with_current_signals<T>(&self, f: impl FnOnce(&mut SignalState) -> T) -> Option<T>
+    {
+        let mut inner = self.inner.lock();
+        let cur_task = inner.cur_task?;
+        let task = inner.task_mut(cur_task)?;
+        return Some(f(&mut task.signals));
+    }
+
+    /// Calls `f` on the heap of the current task.
+    pub fn with_current_heap<T>(&self, f: impl FnOnce(&mut UserHeap) -> T) -> Option<T>
+    {
+        let mut inner = self.inner.lock();
+        let cur_task = inner.cur_task?;
+        let task = inner.task_mut(cur_task)?;
+        return Some(f(&mut task.heap));
+    }
+
+    /// Called at the end of every syscall, acts on the pending
+    /// signals of the current task. Returns only if the task
+    /// should keep running, possibly in a signal handler.
+    pub unsafe fn deliver_signals(&self, ctx: *mut Context)
+    {
+        let outcome = {
+            let mut inner = self.inner.lock();
+            let cur_task = match inner.cur_task
             {
-                tasks.remove(cur_task);
+                Some(cur_task) => cur_task,
+                None => return,
+            };
 
-                if tasks.is_empty()
+            let outcome = inner.process_signals(cur_task, unsafe { &mut *ctx }, true);
+            match outcome
+            {
+                SignalOutcome::Resume => {},
+                SignalOutcome::Terminated(sig) => inner.remove_task(cur_task, signaled_status(sig)),
+                SignalOutcome::Stopped(sig) =>
                 {
-                    *cur_task_opt = None;
+                    if let Some(task) = inner.task_mut(cur_task) {
+                        task.ctx = unsafe { *ctx };
+                    }
+                    inner.stop_task(cur_task, sig);
                 }
-                else
+            }
+
+            outcome
+        };
+
+        if let SignalOutcome::Resume = outcome { return; }
+        unsafe { self.run_next_task() };
+    }
+
+    /// Called by the timer interrupt. Lets the policy decide whether the
+    /// current task keeps running; if it doesn't, its context is saved
+    /// and the next task is started. Returns only if the interrupted
+    /// code should be resumed.
+    pub unsafe fn on_timer_tick(&self, ctx: *const Context)
+    {
+        // We interrupted the idle loop in run_next_task, nothing to do here.
+        if self.idle.load(Ordering::SeqCst) { return; }
+
+        let preempt = {
+            let mut inner = self.inner.lock();
+            match inner.cur_task
+            {
+                None => true,
+                Some(cur_task) =>
                 {
-                    // Adjust the current task index to be
-                    // the removed task's previous index.
-                    let new_index = if cur_task == 0 {
-                        tasks.len() - 1
-                    } else {
-                        cur_task - 1
-                    };
-                    *cur_task_opt = Some(new_index);
+                    // Only user mode ticks can land here, syscalls
+                    // run with interrupts disabled.
+                    if let Some(task) = inner.task_mut(cur_task) {
+                        task.stats.user_ticks += 1;
+                    }
+
+                    let preempt = inner.policy.tick(cur_task);
+                    if preempt
+                    {
+                        if let Some(task) = inner.task_mut(cur_task)
+                        {
+                            task.started = true;
+                            task.ctx = unsafe { *ctx };
+                            task.state = TaskState::Ready;
+                        }
+                        inner.policy.task_preempted(cur_task);
+                    }
+
+                    preempt
                 }
             }
+        };
+
+        if preempt {
+            unsafe { self.run_next_task() };
         }
     }
 
-    pub unsafe fn save_current_context(&self, ctx: *const Context)
+    /// Puts the current task to sleep until `wake_tasks` is called with
+    /// the same reason, then runs something else. `ctx` is the context
+    /// the task will be resumed with.
+    pub unsafe fn block_current_task(&self, ctx: *const Context, reason: WaitReason)
     {
-        let ctx_ref = unsafe { &*ctx };
+        {
+            let mut inner = self.inner.lock();
+            inner.charge_kernel_time();
+            if let Some(cur_task) = inner.cur_task
+            {
+                if let Some(task) = inner.task_mut(cur_task)
+                {
+                    task.started = true;
+                    task.ctx = unsafe { *ctx };
+                    task.state = TaskState::Blocked(reason);
+                }
+                inner.policy.task_blocked(cur_task);
+            }
+        }
 
-        // NOTE: This is fine for now, but make
-        // sure not to lock cur_task first in some other thread.
-        // A unique lock for these resources would probably be better
-        let mut tasks = self.tasks.lock();
-        let cur_task = *self.cur_task.lock();
+        unsafe { self.run_next_task() };
+    }
 
-        if let Some(cur_task) = cur_task
+    pub fn wake_tasks(&self, reason: WaitReason)
+    {
+        let mut inner = self.inner.lock();
+        let inner = &mut *inner;
+        for task in inner.tasks.iter_mut()
         {
-            if let Some(task) = tasks.get_mut(cur_task)
+            if task.state == TaskState::Blocked(reason)
             {
-                task.started = true;
-                task.ctx = ctx_ref.clone();
+                task.state = TaskState::Ready;
+                inner.policy.task_ready(task.pid);
             }
         }
     }
 
     pub unsafe fn run_next_task(&self)
     {
-        let tasks_len = self.tasks.lock().len();
-        if tasks_len <= 0 { println!("No more tasks to run!"); crate::hlt_loop(); }
-
-        let (started, ctx, start_instr, stack_end, page_table) = {
-            let mut cur_task_opt = self.cur_task.lock();
-            let cur_task = cur_task_opt.get_or_insert(0);
-            let next_task = (*cur_task + 1) % tasks_len;
-            *cur_task = next_task;
-            let task = &self.tasks.lock()[next_task];
-
-            (
-                task.started,
-                task.ctx.clone(),
-                task.start_instr.clone(),
-                task.stack_end.clone(),
-                task.page_table.clone()
-            )
+        loop
+        {
+            #[cfg(feature = "user_tests")]
+            crate::user_tests::poll();
+
+            let next = {
+                let mut inner = self.inner.lock();
+                if inner.tasks.is_empty() { warn!("No more tasks to run!"); crate::hlt_loop(); }
+
+                inner.cur_task = None;
+                match inner.policy.pick_next()
+                {
+                    None => NextTask::Idle,
+                    Some(pid) => inner.prepare_to_run(pid),
+                }
+            };
+
+            match next
+            {
+                NextTask::Run { started, ctx, start_instr, stack_end } =>
+                {
+                    if !started {
+                        unsafe { init_and_jump_to_usercode(start_instr, stack_end) };
+                    } else {
+                        unsafe { restore_context_and_return_from_interrupt(&ctx) };
+                    }
+                }
+                NextTask::Retry => continue,
+                NextTask::Idle => {},
+            }
+
+            // Nothing is ready to run, wait for an interrupt
+            // (e.g. a key press) to wake some task up.
+            self.idle.store(true, Ordering::SeqCst);
+            x86_64::instructions::interrupts::enable_and_hlt();
+            x86_64::instructions::interrupts::disable();
+            self.idle.store(false, Ordering::SeqCst);
+        }
+    }
+}
+
+enum NextTask
+{
+    Run { started: bool, ctx: Context, start_instr: VirtAddr, stack_end: VirtAddr },
+    Retry,  // The picked task didn't survive its pending signals
+    Idle,
+}
+
+impl SchedulerInner
+{
+    // Switches to the address space of the task picked by the
+    // policy and handles its pending signals.
+    fn prepare_to_run(&mut self, pid: Pid) -> NextTask
+    {
+        let (started, mut ctx, page_table) = match self.task_mut(pid)
+        {
+            Some(task) => (task.started, task.ctx, task.page_table),
+            None => return NextTask::Retry,
         };
 
+        // Signal frames are written to the user stack, so this has to happen first.
         unsafe { memory::activate_page_table(page_table) };
 
-        if !started {
-            unsafe { init_and_jump_to_usercode(start_instr, stack_end) };
-        } else {
-            unsafe { restore_context_and_return_from_interrupt(&ctx) };
+        match self.process_signals(pid, &mut ctx, started)
+        {
+            SignalOutcome::Terminated(sig) => { self.remove_task(pid, signaled_status(sig)); return NextTask::Retry; }
+            SignalOutcome::Stopped(sig)    => { self.stop_task(pid, sig); return NextTask::Retry; }
+            SignalOutcome::Resume => {},
         }
+
+        self.cur_task = Some(pid);
+        let task = self.task_mut(pid).unwrap();
+        task.state = TaskState::Running;
+        console::set_current(task.console);
+        task.stats.context_switches += 1;
+        task.ctx = ctx;
+        return NextTask::Run {
+            started: task.started,
+            ctx: task.ctx,
+            start_instr: task.start_instr,
+            stack_end: task.stack_end,
+        };
     }
 }
 
 // Context switching
 
 #[derive(Default, Clone, Copy, Debug)]
+#[repr(C)]
 pub struct Context
 {
     pub rbp: u64,
@@ -479,7 +1232,7 @@ pub unsafe fn init_and_jump_to_usercode(code: VirtAddr, stack_end: VirtAddr)
 
 pub fn print_elf_header(header: ElfHeader)
 {
-    println!("is_64_bits:{}, endianness:{}, version:{}, os_abi:{}, bin_type:{}, isa:{}, elf_version:{}, entry_vaddr:{}, pht_offset:{}, sht_offset:{}, flags:{}, header_size:{}, pht_entry_size:{}, pht_num_entries:{}, sht_entry_size:{}, sht_num_entries:{}, section_names_index:{}",
+    trace!("is_64_bits:{}, endianness:{}, version:{}, os_abi:{}, bin_type:{}, isa:{}, elf_version:{}, entry_vaddr:{}, pht_offset:{}, sht_offset:{}, flags:{}, header_size:{}, pht_entry_size:{}, pht_num_entries:{}, sht_entry_size:{}, sht_num_entries:{}, section_names_index:{}",
             header.is_64_bits, header.endianness,
             header.elf_header_version, header.os_abi,
             header.bin_type, header.isa,
@@ -493,7 +1246,7 @@ pub fn print_elf_header(header: ElfHeader)
 
 pub fn print_elf_program_header(header: ProgramHeader)
 {
-    println!("segment_type: {}, flags: {}, offset: {}, vaddr: {}, paddr: {}, size_in_file: {}, size_in_memory: {}",
+    trace!("segment_type: {}, flags: {}, offset: {}, vaddr: {}, paddr: {}, size_in_file: {}, size_in_memory: {}",
         header.segment_type, header.flags, header.offset,
         header.vaddr, header.paddr,
         header.size_in_file, header.size_in_memory,
@@ -534,3 +1287,98 @@ pub fn print_page_flags(flags: PageTableFlags)
 
     println!("");
 }
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    fn shell_task() -> Task
+    {
+        let (phys_offset, kernel_page_table) = {
+            let mem_info = memory::KERNEL_MEM_INFO.lock();
+            (mem_info.phys_offset, mem_info.kernel_page_table_phys_addr)
+        };
+        return create_task("shell", USER_PROGRAM_SHELL, phys_offset, kernel_page_table, 0).expect("Could not load the shell");
+    }
+
+    #[test_case]
+    fn parse_elf()
+    {
+        let header = parse_elf_binary(USER_PROGRAM_SHELL).expect("Could not parse the shell");
+        assert_eq!(header.is_64_bits, 2);
+        assert_eq!(header.bin_type, 2);
+        assert!(header.entry_vaddr != 0 && header.entry_vaddr < memory::USER_SPACE_END);
+    }
+
+    #[test_case]
+    fn reject_invalid_elf()
+    {
+        assert!(parse_elf_binary(b"#!/bin/sh\necho this is not an ELF binary\n").is_none());
+    }
+
+    #[test_case]
+    fn load_elf()
+    {
+        let task = shell_task();
+        let header = parse_elf_binary(USER_PROGRAM_SHELL).unwrap();
+        assert_eq!(task.start_instr.as_u64(), header.entry_vaddr);
+        assert_ne!(task.page_table, memory::KERNEL_MEM_INFO.lock().kernel_page_table_phys_addr);
+    }
+
+    #[test_case]
+    fn dropped_tasks_give_frames_back()
+    {
+        // The first one might grow the kernel heap or the slabs
+        drop(shell_task());
+        let used_before = memory::FRAME_ALLOCATOR.lock().stats().0;
+        drop(shell_task());
+        assert_eq!(memory::FRAME_ALLOCATOR.lock().stats().0, used_before);
+    }
+
+    #[test_case]
+    fn user_heap()
+    {
+        let mut task = shell_task();
+        let phys_offset = memory::KERNEL_MEM_INFO.lock().phys_offset;
+        assert_eq!(task.heap.start % 4096, 0);
+        assert_eq!(task.heap.brk, task.heap.start);
+
+        // set_break maps pages in the active page table
+        let kernel_page_table = unsafe { memory::active_level_4_table_addr() };
+        unsafe { memory::activate_page_table(task.page_table) };
+        let start = task.heap.start;
+        let grown = task.heap.set_break(start + 10000, phys_offset);
+        let zeroed = grown && unsafe { core::slice::from_raw_parts(start as *const u8, 10000) }.iter().all(|&b| b == 0);
+        if grown { unsafe { (start as *mut u8).add(9999).write(42) }; }
+        let shrunk = task.heap.set_break(start + 100, phys_offset);
+        let regrown = task.heap.set_break(start + 10000, phys_offset);
+        let rezeroed = unsafe { (start as *const u8).add(9999).read() } == 0;
+        let too_low = task.heap.set_break(start - 1, phys_offset);
+        let too_high = task.heap.set_break(start + USER_HEAP_MAX_SIZE + 1, phys_offset);
+        unsafe { memory::activate_page_table(kernel_page_table) };
+
+        assert!(grown && zeroed && shrunk && regrown && rezeroed);
+        assert!(!too_low && !too_high);
+        assert_eq!(task.heap.brk, start + 10000);
+    }
+
+    #[test_case]
+    fn schedule_tasks()
+    {
+        let scheduler = Scheduler::new();
+        let first  = scheduler.schedule_task(shell_task());
+        let second = scheduler.schedule_task(shell_task());
+        assert_ne!(first, second);
+
+        let list = scheduler.process_list();
+        assert_eq!(list.len(), 2);
+        assert_eq!(list[0].pid, first);
+        assert_eq!(list[1].pid, second);
+        assert_eq!(list[0].state, ProcessState::Ready as u64);
+        assert_eq!(&list[0].name[..5], b"shell");
+
+        // The first task on a console gets it
+        assert_eq!(scheduler.foreground(0), Some(first));
+    }
+}
@@ -0,0 +1,200 @@
+// Sampling profiler. While it's running, every timer interrupt records
+// where the CPU was and which task was running. The histogram of the
+// samples is written to the serial port: kernel samples are grouped by
+// function, user samples by task and address (user programs have no
+// symbols here, so they have to be looked up offline).
+
+use crate::{process, symbols};
+use crate::backtrace::Symbolized;
+use alloc::collections::BTreeMap;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+
+// Commands of the profile syscall
+// NOTE: This should be kept up to date along with its
+// counterpart in the usercode library.
+pub const PROFILE_START: u64 = 0;
+pub const PROFILE_STOP:  u64 = 1;
+pub const PROFILE_DUMP:  u64 = 2;
+
+// About 15 minutes of samples at 18.2 Hz
+const MAX_SAMPLES: usize = 16 * 1024;
+
+#[derive(Clone, Copy)]
+struct Sample
+{
+    rip: u64,
+    // 0 when no task was running
+    pid: process::Pid,
+    user: bool,
+}
+
+struct Profile
+{
+    samples: [Sample; MAX_SAMPLES],
+    len: usize,
+    // Samples that didn't fit
+    dropped: u64,
+}
+
+static RUNNING: AtomicBool = AtomicBool::new(false);
+static PROFILE: Mutex<Profile> = Mutex::new(Profile {
+    samples: [Sample { rip: 0, pid: 0, user: false }; MAX_SAMPLES],
+    len: 0,
+    dropped: 0,
+});
+
+/// Throws away the previous samples and starts recording.
+pub fn start()
+{
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut profile = PROFILE.lock();
+        profile.len = 0;
+        profile.dropped = 0;
+    });
+    RUNNING.store(true, Ordering::SeqCst);
+}
+
+pub fn stop()
+{
+    RUNNING.store(false, Ordering::SeqCst);
+}
+
+pub fn is_running() -> bool
+{
+    return RUNNING.load(Ordering::Relaxed);
+}
+
+pub fn num_samples() -> usize
+{
+    return x86_64::instructions::interrupts::without_interrupts(|| PROFILE.lock().len);
+}
+
+/// Called on every timer interrupt with the interrupted context.
+pub fn on_timer_tick(ctx: &process::Context)
+{
+    if !is_running() { return; }
+
+    let pid = process::SCHEDULER.try_current_pid().unwrap_or(0);
+    record(Sample { rip: ctx.rip, pid, user: ctx.cs & 3 == 3 });
+}
+
+fn record(sample: Sample)
+{
+    // Only skipped while the samples are being reset or dumped
+    let mut profile = match PROFILE.try_lock()
+    {
+        Some(profile) => profile,
+        None => return,
+    };
+
+    if profile.len == MAX_SAMPLES {
+        profile.dropped += 1;
+        return;
+    }
+
+    let len = profile.len;
+    profile.samples[len] = sample;
+    profile.len += 1;
+}
+
+/// Counts of the samples, grouped like they're shown by dump.
+struct Histogram
+{
+    // By the start of the function (or the address if there are no symbols)
+    kernel: BTreeMap<u64, u64>,
+    // By task and address
+    user: BTreeMap<(process::Pid, u64), u64>,
+    dropped: u64,
+}
+
+fn histogram() -> Histogram
+{
+    let mut res = Histogram { kernel: BTreeMap::new(), user: BTreeMap::new(), dropped: 0 };
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let profile = PROFILE.lock();
+        for sample in &profile.samples[..profile.len]
+        {
+            if sample.user {
+                *res.user.entry((sample.pid, sample.rip)).or_insert(0) += 1;
+            } else {
+                let func = symbols::lookup(sample.rip).map_or(sample.rip, |(_, offset)| sample.rip - offset);
+                *res.kernel.entry(func).or_insert(0) += 1;
+            }
+        }
+        res.dropped = profile.dropped;
+    });
+    return res;
+}
+
+/// Writes the histogram of the samples, most frequent first.
+pub fn dump(out: &mut impl Write) -> fmt::Result
+{
+    let histogram = histogram();
+    let kernel_total: u64 = histogram.kernel.values().sum();
+    let user_total: u64 = histogram.user.values().sum();
+    let total = kernel_total + user_total;
+
+    writeln!(out, "Profile: {} samples ({} kernel, {} user, {} dropped)", total, kernel_total, user_total, histogram.dropped)?;
+    if total == 0 { return Ok(()); }
+
+    writeln!(out, "Kernel:")?;
+    let mut kernel: Vec<(u64, u64)> = histogram.kernel.into_iter().collect();
+    kernel.sort_by(|a, b| b.1.cmp(&a.1));
+    for (func, count) in kernel {
+        writeln!(out, "  {:>6} {:>6}  {}", count, Percent(count, total), Symbolized(func))?;
+    }
+
+    writeln!(out, "User:")?;
+    let mut user: Vec<((process::Pid, u64), u64)> = histogram.user.into_iter().collect();
+    user.sort_by(|a, b| a.0.0.cmp(&b.0.0).then(b.1.cmp(&a.1)));
+    for ((pid, rip), count) in user {
+        writeln!(out, "  {:>6} {:>6}  pid {} {:#018x}", count, Percent(count, total), pid, rip)?;
+    }
+    return Ok(());
+}
+
+// Like "12.5%"
+struct Percent(u64, u64);
+
+impl fmt::Display for Percent
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let tenths = self.0 * 1000 / self.1;
+        return f.pad(&alloc::format!("{}.{}%", tenths / 10, tenths % 10));
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test_case]
+    fn histogram_groups_samples()
+    {
+        start();
+        stop();
+        record(Sample { rip: 0x1000, pid: 3, user: true });
+        record(Sample { rip: 0x1000, pid: 3, user: true });
+        record(Sample { rip: 0x1000, pid: 4, user: true });
+        record(Sample { rip: 0x2000, pid: 3, user: true });
+        let here = start as *const () as u64;
+        record(Sample { rip: here, pid: 0, user: false });
+        record(Sample { rip: here + 1, pid: 3, user: false });
+
+        let histogram = histogram();
+        assert_eq!(histogram.user.get(&(3, 0x1000)), Some(&2));
+        assert_eq!(histogram.user.get(&(4, 0x1000)), Some(&1));
+        assert_eq!(histogram.user.get(&(3, 0x2000)), Some(&1));
+        if symbols::is_available() {
+            assert_eq!(histogram.kernel.get(&here), Some(&2));
+        }
+        assert_eq!(histogram.kernel.values().sum::<u64>(), 2);
+        start();
+        stop();
+    }
+}
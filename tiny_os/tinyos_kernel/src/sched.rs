@@ -0,0 +1,307 @@
+// Scheduling policies. The scheduler in process.rs owns the tasks
+// and does the actual context switching, while a policy only decides
+// which ready task runs next and for how long.
+
+use crate::process::Pid;
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
+
+pub trait SchedulingPolicy: Send
+{
+    fn name(&self) -> &'static str;
+
+    /// A task became runnable, either because it was just created or
+    /// because whatever it was waiting for happened.
+    fn task_ready(&mut self, pid: Pid);
+
+    /// The running task gave up the CPU before its time slice ran out
+    /// (e.g. it's waiting for keyboard input).
+    fn task_blocked(&mut self, pid: Pid);
+
+    /// The running task was preempted and should go back in the ready queue.
+    fn task_preempted(&mut self, pid: Pid);
+
+    /// The task doesn't exist anymore, forget about it.
+    fn task_removed(&mut self, pid: Pid);
+
+    /// Called on every timer tick while a task is running. Returns true
+    /// if the running task has used up its time slice and should be preempted.
+    fn tick(&mut self, pid: Pid) -> bool;
+
+    /// Removes the next task to run from the ready queue.
+    fn pick_next(&mut self) -> Option<Pid>;
+}
+
+pub fn policy_by_name(name: &str) -> Option<Box<dyn SchedulingPolicy>>
+{
+    return match name
+    {
+        "round_robin" => Some(Box::new(RoundRobin::new())),
+        "mlfq"        => Some(Box::new(Mlfq::new())),
+        _ => None,
+    };
+}
+
+// Round robin
+
+/// Every task runs for a single tick, then goes to the back of the queue.
+pub struct RoundRobin
+{
+    queue: VecDeque<Pid>,
+}
+
+impl RoundRobin
+{
+    pub fn new() -> Self
+    {
+        return RoundRobin { queue: VecDeque::new() };
+    }
+}
+
+impl Default for RoundRobin
+{
+    fn default() -> Self
+    {
+        return Self::new();
+    }
+}
+
+impl SchedulingPolicy for RoundRobin
+{
+    fn name(&self) -> &'static str { return "round_robin"; }
+
+    fn task_ready(&mut self, pid: Pid)
+    {
+        self.queue.push_back(pid);
+    }
+
+    fn task_blocked(&mut self, pid: Pid) {}
+
+    fn task_preempted(&mut self, pid: Pid)
+    {
+        self.queue.push_back(pid);
+    }
+
+    fn task_removed(&mut self, pid: Pid)
+    {
+        self.queue.retain(|&p| p != pid);
+    }
+
+    fn tick(&mut self, pid: Pid) -> bool
+    {
+        return true;
+    }
+
+    fn pick_next(&mut self) -> Option<Pid>
+    {
+        return self.queue.pop_front();
+    }
+}
+
+// Multilevel feedback queue
+
+pub const MLFQ_NUM_LEVELS: usize = 3;
+/// Time slice of each level, in timer ticks. Level 0 is the highest priority.
+pub const MLFQ_QUANTUM: [u32; MLFQ_NUM_LEVELS] = [1, 2, 4];
+/// Every this many ticks all tasks are moved back to the highest priority,
+/// so that compute bound tasks can't be starved forever.
+pub const MLFQ_BOOST_INTERVAL: u64 = 100;
+
+struct MlfqEntry
+{
+    pid: Pid,
+    level: usize,
+    used: u32,  // Ticks used in the current time slice
+}
+
+/// Tasks that use their whole time slice are demoted to a lower priority
+/// level with a longer time slice, while tasks that block (which is what
+/// interactive tasks spend most of their time doing) go back to the top.
+pub struct Mlfq
+{
+    queues: [VecDeque<Pid>; MLFQ_NUM_LEVELS],
+    entries: Vec<MlfqEntry>,
+    ticks_since_boost: u64,
+}
+
+impl Mlfq
+{
+    pub fn new() -> Self
+    {
+        return Mlfq {
+            queues: Default::default(),
+            entries: Vec::new(),
+            ticks_since_boost: 0,
+        };
+    }
+
+    fn entry(&mut self, pid: Pid) -> &mut MlfqEntry
+    {
+        if let Some(idx) = self.entries.iter().position(|e| e.pid == pid) {
+            return &mut self.entries[idx];
+        }
+
+        self.entries.push(MlfqEntry { pid, level: 0, used: 0 });
+        return self.entries.last_mut().unwrap();
+    }
+
+    fn enqueue(&mut self, pid: Pid)
+    {
+        let level = self.entry(pid).level;
+        self.queues[level].push_back(pid);
+    }
+
+    fn boost_all(&mut self)
+    {
+        for level in 1..MLFQ_NUM_LEVELS
+        {
+            while let Some(pid) = self.queues[level].pop_front() {
+                self.queues[0].push_back(pid);
+            }
+        }
+
+        for entry in self.entries.iter_mut()
+        {
+            entry.level = 0;
+            entry.used = 0;
+        }
+    }
+}
+
+impl Default for Mlfq
+{
+    fn default() -> Self
+    {
+        return Self::new();
+    }
+}
+
+impl SchedulingPolicy for Mlfq
+{
+    fn name(&self) -> &'static str { return "mlfq"; }
+
+    fn task_ready(&mut self, pid: Pid)
+    {
+        self.enqueue(pid);
+    }
+
+    fn task_blocked(&mut self, pid: Pid)
+    {
+        let entry = self.entry(pid);
+        entry.level = 0;
+        entry.used = 0;
+    }
+
+    fn task_preempted(&mut self, pid: Pid)
+    {
+        self.enqueue(pid);
+    }
+
+    fn task_removed(&mut self, pid: Pid)
+    {
+        for queue in self.queues.iter_mut() {
+            queue.retain(|&p| p != pid);
+        }
+        self.entries.retain(|e| e.pid != pid);
+    }
+
+    fn tick(&mut self, pid: Pid) -> bool
+    {
+        self.ticks_since_boost += 1;
+        if self.ticks_since_boost >= MLFQ_BOOST_INTERVAL
+        {
+            self.ticks_since_boost = 0;
+            self.boost_all();
+            return true;
+        }
+
+        let entry = self.entry(pid);
+        entry.used += 1;
+        if entry.used < MLFQ_QUANTUM[entry.level] { return false; }
+
+        entry.used = 0;
+        if entry.level < MLFQ_NUM_LEVELS - 1 {
+            entry.level += 1;
+        }
+        return true;
+    }
+
+    fn pick_next(&mut self) -> Option<Pid>
+    {
+        for queue in self.queues.iter_mut()
+        {
+            if let Some(pid) = queue.pop_front() {
+                return Some(pid);
+            }
+        }
+
+        return None;
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+
+    #[test_case]
+    fn round_robin_order()
+    {
+        let mut policy = RoundRobin::new();
+        policy.task_ready(1);
+        policy.task_ready(2);
+        policy.task_ready(3);
+
+        assert_eq!(policy.pick_next(), Some(1));
+        assert!(policy.tick(1));
+        policy.task_preempted(1);
+        assert_eq!(policy.pick_next(), Some(2));
+        policy.task_removed(3);
+        assert_eq!(policy.pick_next(), Some(1));
+        assert_eq!(policy.pick_next(), None);
+    }
+
+    #[test_case]
+    fn mlfq_demotes_cpu_bound_tasks()
+    {
+        let mut policy = Mlfq::new();
+        policy.task_ready(1);
+        policy.task_ready(2);
+
+        // Task 1 uses its whole time slice and drops to level 1
+        assert_eq!(policy.pick_next(), Some(1));
+        assert!(policy.tick(1));
+        policy.task_preempted(1);
+        assert_eq!(policy.pick_next(), Some(2));
+
+        // Task 2 blocks, so it stays at the top
+        policy.task_blocked(2);
+        policy.task_ready(2);
+        assert_eq!(policy.pick_next(), Some(2));
+        policy.task_blocked(2);
+
+        // The lower level has a longer time slice
+        assert_eq!(policy.pick_next(), Some(1));
+        for _ in 1..MLFQ_QUANTUM[1] {
+            assert!(!policy.tick(1));
+        }
+        assert!(policy.tick(1));
+    }
+
+    #[test_case]
+    fn mlfq_boost()
+    {
+        let mut policy = Mlfq::new();
+        policy.task_ready(1);
+        assert_eq!(policy.pick_next(), Some(1));
+        assert!(policy.tick(1));
+        policy.task_preempted(1);
+
+        // After the boost interval everything is back at level 0
+        policy.ticks_since_boost = MLFQ_BOOST_INTERVAL - 1;
+        policy.task_ready(2);
+        assert!(policy.tick(2));
+        assert_eq!(policy.entry(1).level, 0);
+        assert_eq!(policy.pick_next(), Some(2));
+        assert_eq!(policy.pick_next(), Some(1));
+    }
+}
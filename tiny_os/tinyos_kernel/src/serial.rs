@@ -1,17 +1,130 @@
+use crate::tty;
+use core::fmt;
+use core::sync::atomic::{AtomicU8, Ordering};
 use lazy_static::lazy_static;
 use spin::Mutex;
 use uart_16550::SerialPort;
+use x86_64::instructions::port::Port;
+
+pub const COM1_BASE: u16 = 0x3F8;
 
 lazy_static!
 {
     pub static ref SERIAL1: Mutex<SerialPort> =
     {
-        let mut serial_port = unsafe { SerialPort::new(0x3F8) };
+        // This also enables the "data received" interrupt
+        let mut serial_port = unsafe { SerialPort::new(COM1_BASE) };
         serial_port.init();
         Mutex::new(serial_port)
     };
 }
 
+pub fn init()
+{
+    lazy_static::initialize(&SERIAL1);
+}
+
+// Serial console
+
+/// What the serial port shows of the console output. Input from the
+/// serial line always goes to the active console, like the keyboard's.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum ConsoleMode
+{
+    /// Only serial_print! goes to the serial port.
+    Off = 0,
+    /// The output of the active console goes to both the screen and the serial port.
+    Mirror = 1,
+    /// The output of the active console only goes to the serial port,
+    /// for running without a screen (e.g. QEMU's -nographic).
+    Redirect = 2,
+}
+
+static CONSOLE_MODE: AtomicU8 = AtomicU8::new(ConsoleMode::Off as u8);
+
+pub fn console_mode() -> ConsoleMode
+{
+    return match CONSOLE_MODE.load(Ordering::Relaxed)
+    {
+        1 => ConsoleMode::Mirror,
+        2 => ConsoleMode::Redirect,
+        _ => ConsoleMode::Off,
+    };
+}
+
+pub fn set_console_mode(mode: ConsoleMode)
+{
+    CONSOLE_MODE.store(mode as u8, Ordering::Relaxed);
+}
+
+pub fn console_mode_by_name(name: &str) -> Option<ConsoleMode>
+{
+    return match name
+    {
+        "off"      => Some(ConsoleMode::Off),
+        "mirror"   => Some(ConsoleMode::Mirror),
+        "redirect" => Some(ConsoleMode::Redirect),
+        _ => None,
+    };
+}
+
+// Console output as a terminal expects it
+struct SerialConsole<'a>
+{
+    port: &'a mut SerialPort,
+}
+
+impl fmt::Write for SerialConsole<'_>
+{
+    fn write_str(&mut self, s: &str) -> fmt::Result
+    {
+        for byte in s.bytes()
+        {
+            if byte == b'\n' { self.port.send_raw(b'\r'); }
+            // send() would turn backspace into an erase, but
+            // the console only uses it to move the cursor.
+            self.port.send_raw(byte);
+        }
+        return Ok(());
+    }
+}
+
+/// Writes console output to the serial port. Interrupts must be disabled.
+pub fn write_console(args: fmt::Arguments)
+{
+    use core::fmt::Write;
+
+    let mut port = SERIAL1.lock();
+    let _ = SerialConsole { port: &mut port }.write_fmt(args);
+}
+
+// Reads a byte from the serial line if there's one
+fn try_receive() -> Option<u8>
+{
+    const DATA_READY: u8 = 1 << 0;
+    let mut line_status: Port<u8> = Port::new(COM1_BASE + 5);
+    let mut data: Port<u8> = Port::new(COM1_BASE);
+
+    let _port = SERIAL1.lock();
+    unsafe
+    {
+        if line_status.read() & DATA_READY == 0 { return None; }
+        return Some(data.read());
+    }
+}
+
+/// Called on the COM1 interrupt. Whatever was received is handled like keyboard input.
+pub fn handle_interrupt()
+{
+    while let Some(byte) = try_receive()
+    {
+        // Terminals send a carriage return for Enter
+        let byte = if byte == b'\r' { b'\n' } else { byte };
+        tty::input_byte(byte);
+    }
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments)
 {
@@ -26,6 +139,18 @@ pub fn _print(args: ::core::fmt::Arguments)
     });
 }
 
+/// Writes to the serial port, for code that takes a `fmt::Write`.
+pub struct SerialWriter;
+
+impl core::fmt::Write for SerialWriter
+{
+    fn write_str(&mut self, s: &str) -> core::fmt::Result
+    {
+        _print(format_args!("{}", s));
+        return Ok(());
+    }
+}
+
 /// Prints to the host through the serial interface.
 #[macro_export]
 macro_rules! serial_print {
@@ -0,0 +1,204 @@
+// POSIX-style signals. Signals are recorded as pending on the target
+// task and acted upon the next time it's about to return to user mode,
+// either at the end of a syscall or when the scheduler switches to it.
+
+use crate::process::Context;
+
+// NOTE: These should be kept up to date along with their
+// counterparts in the usercode library.
+pub const NUM_SIGNALS: u32 = 32;
+pub const SIGINT:  u32 = 2;
+pub const SIGKILL: u32 = 9;
+pub const SIGUSR1: u32 = 10;
+pub const SIGSEGV: u32 = 11;
+pub const SIGUSR2: u32 = 12;
+pub const SIGTERM: u32 = 15;
+pub const SIGCHLD: u32 = 17;
+pub const SIGCONT: u32 = 18;
+pub const SIGSTOP: u32 = 19;
+pub const SIGTSTP: u32 = 20;
+
+/// Handler values with a special meaning.
+pub const SIG_DFL: u64 = 0;
+pub const SIG_IGN: u64 = 1;
+
+/// "how" argument of the sigprocmask syscall.
+pub const SIG_BLOCK:   u64 = 0;
+pub const SIG_UNBLOCK: u64 = 1;
+pub const SIG_SETMASK: u64 = 2;
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Disposition
+{
+    Terminate,
+    Ignore,
+    Stop,
+    Continue,
+    Handler(u64),
+}
+
+pub fn default_disposition(sig: u32) -> Disposition
+{
+    return match sig
+    {
+        SIGCHLD => Disposition::Ignore,
+        SIGCONT => Disposition::Continue,
+        SIGSTOP | SIGTSTP => Disposition::Stop,
+        _ => Disposition::Terminate,
+    };
+}
+
+pub fn sig_bit(sig: u32) -> u32
+{
+    return 1 << sig;
+}
+
+// These can't be caught, blocked or ignored.
+const UNBLOCKABLE: u32 = (1 << SIGKILL) | (1 << SIGSTOP);
+
+#[derive(Clone, Copy, Debug)]
+pub struct SignalState
+{
+    pub pending: u32,
+    pub blocked: u32,
+    pub handlers: [u64; NUM_SIGNALS as usize],
+    /// Where handlers return to. It's provided by the user library,
+    /// and it just calls sigreturn.
+    pub restorer: u64,
+}
+
+impl Default for SignalState
+{
+    fn default() -> Self
+    {
+        return SignalState {
+            pending: 0,
+            blocked: 0,
+            handlers: [SIG_DFL; NUM_SIGNALS as usize],
+            restorer: 0,
+        };
+    }
+}
+
+impl SignalState
+{
+    pub fn disposition(&self, sig: u32) -> Disposition
+    {
+        if sig_bit(sig) & UNBLOCKABLE != 0 { return default_disposition(sig); }
+
+        return match self.handlers[sig as usize]
+        {
+            SIG_DFL => default_disposition(sig),
+            SIG_IGN => Disposition::Ignore,
+            handler => Disposition::Handler(handler),
+        };
+    }
+
+    /// Lowest numbered signal that is pending and not blocked.
+    pub fn next_deliverable(&self) -> Option<u32>
+    {
+        let deliverable = self.pending & (!self.blocked | UNBLOCKABLE);
+        if deliverable == 0 { return None; }
+        return Some(deliverable.trailing_zeros());
+    }
+
+    /// Installs a new handler and returns the old one.
+    /// Returns None if the signal can't be caught.
+    pub fn set_handler(&mut self, sig: u32, handler: u64, restorer: u64) -> Option<u64>
+    {
+        if sig == 0 || sig >= NUM_SIGNALS || sig_bit(sig) & UNBLOCKABLE != 0 { return None; }
+
+        let old = self.handlers[sig as usize];
+        self.handlers[sig as usize] = handler;
+        if restorer != 0 { self.restorer = restorer; }
+        return Some(old);
+    }
+
+    pub fn set_blocked(&mut self, how: u64, set: u32) -> Option<u32>
+    {
+        let old = self.blocked;
+        match how
+        {
+            SIG_BLOCK   => self.blocked |= set,
+            SIG_UNBLOCK => self.blocked &= !set,
+            SIG_SETMASK => self.blocked = set,
+            _ => return None,
+        }
+
+        self.blocked &= !UNBLOCKABLE;
+        return Some(old);
+    }
+}
+
+/// What's pushed on the user stack when a handler is invoked. The
+/// handler returns into the restorer, which calls sigreturn with the
+/// stack pointer pointing right at this.
+#[derive(Clone, Copy, Debug)]
+#[repr(C)]
+pub struct SignalFrame
+{
+    pub sig: u64,
+    pub saved_blocked: u64,
+    pub ctx: Context,
+}
+
+// Space below the interrupted stack pointer that the
+// System V ABI allows functions to use without reserving it.
+const RED_ZONE_SIZE: u64 = 128;
+
+/// Pushes a signal frame on the user stack and redirects the context
+/// to the handler. The address space of the task must be active.
+/// Returns false, and leaves the context alone, if the stack pointer
+/// doesn't point to writable user memory with room for the frame.
+pub unsafe fn push_signal_frame(ctx: &mut Context, sig: u32, handler: u64, restorer: u64, saved_blocked: u32) -> bool
+{
+    let frame = SignalFrame {
+        sig: sig as u64,
+        saved_blocked: saved_blocked as u64,
+        ctx: *ctx,
+    };
+
+    let frame_size = core::mem::size_of::<SignalFrame>() as u64;
+    let frame_addr = match ctx.rsp.checked_sub(RED_ZONE_SIZE + frame_size + 8)
+    {
+        Some(addr) => (addr + 8) & !0xF,
+        None => return false,
+    };
+    // The restorer is the return address, so the handler
+    // is entered as if it had just been called.
+    let ret_addr = frame_addr - 8;
+    if !crate::memory::is_user_range(ret_addr, frame_addr + frame_size - ret_addr, true) { return false; }
+
+    unsafe
+    {
+        core::ptr::write(frame_addr as *mut SignalFrame, frame);
+        core::ptr::write(ret_addr as *mut u64, restorer);
+    }
+
+    ctx.rsp = ret_addr;
+    ctx.rip = handler;
+    ctx.rdi = sig as u64;
+    return true;
+}
+
+/// Restores the context saved by push_signal_frame. `frame_addr` is the
+/// user stack pointer at the time of the sigreturn syscall. Returns the
+/// blocked mask to restore.
+pub unsafe fn pop_signal_frame(ctx: &mut Context, frame_addr: u64) -> Option<u32>
+{
+    let frame_size = core::mem::size_of::<SignalFrame>() as u64;
+    if !crate::memory::is_user_range(frame_addr, frame_size, false) { return None; }
+
+    let frame = unsafe { core::ptr::read(frame_addr as *const SignalFrame) };
+
+    // Don't let user code change its privilege level or the
+    // interrupt flag through a forged frame.
+    const USER_RFLAGS_MASK: u64 = 0xCD5;  // CF, PF, AF, ZF, SF, TF, DF, OF
+    let (cs, ss, rflags) = (ctx.cs, ctx.ss, ctx.rflags);
+    *ctx = frame.ctx;
+    ctx.cs = cs;
+    ctx.ss = ss;
+    ctx.rflags = (rflags & !USER_RFLAGS_MASK) | (frame.ctx.rflags & USER_RFLAGS_MASK);
+
+    return Some(frame.saved_blocked as u32);
+}
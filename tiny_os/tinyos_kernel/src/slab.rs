@@ -0,0 +1,287 @@
+// Slab caches for small kernel objects. A slab is a frame from the frame
+// allocator, used through the physical memory mapping, that's cut into
+// objects of one size. The header of the slab is at the start of the frame
+// and links together the free objects. Slabs are never given back to the
+// frame allocator, even when all of their objects are free.
+
+use crate::memory;
+use alloc::alloc::Layout;
+use core::ptr::null_mut;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::structures::paging::FrameAllocator;
+
+const SLAB_SIZE: usize = 4096;
+pub const NUM_CACHES: usize = 7;
+/// Object sizes of the caches. Bigger allocations go to the heap.
+pub const SIZE_CLASSES: [usize; NUM_CACHES] = [16, 32, 64, 128, 256, 512, 1024];
+
+// Where the frames are mapped, set by init
+static PHYS_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+#[repr(C)]
+struct SlabHeader
+{
+    // Next slab with free objects
+    next: *mut SlabHeader,
+    free: *mut FreeObject,
+    in_use: usize,
+}
+
+struct FreeObject
+{
+    next: *mut FreeObject,
+}
+
+pub struct SlabCache
+{
+    object_size: usize,
+    // Slabs with free objects. Full slabs aren't linked anywhere.
+    partial: *mut SlabHeader,
+    slabs: usize,
+    in_use: usize,
+    allocs: u64,
+}
+
+// The slabs are only reached through the cache's lock
+unsafe impl Send for SlabCache {}
+
+static CACHES: [Mutex<SlabCache>; NUM_CACHES] = [
+    Mutex::new(SlabCache::new(SIZE_CLASSES[0])),
+    Mutex::new(SlabCache::new(SIZE_CLASSES[1])),
+    Mutex::new(SlabCache::new(SIZE_CLASSES[2])),
+    Mutex::new(SlabCache::new(SIZE_CLASSES[3])),
+    Mutex::new(SlabCache::new(SIZE_CLASSES[4])),
+    Mutex::new(SlabCache::new(SIZE_CLASSES[5])),
+    Mutex::new(SlabCache::new(SIZE_CLASSES[6])),
+];
+
+impl SlabCache
+{
+    const fn new(object_size: usize) -> Self
+    {
+        return SlabCache { object_size, partial: null_mut(), slabs: 0, in_use: 0, allocs: 0 };
+    }
+
+    // Objects are aligned to their size, so the first one goes after the header
+    fn first_object_offset(&self) -> usize
+    {
+        let header = core::mem::size_of::<SlabHeader>();
+        return (header + self.object_size - 1) & !(self.object_size - 1);
+    }
+
+    fn objects_per_slab(&self) -> usize
+    {
+        return (SLAB_SIZE - self.first_object_offset()) / self.object_size;
+    }
+
+    fn alloc(&mut self) -> *mut u8
+    {
+        if self.partial.is_null() && !self.add_slab() {
+            return null_mut();
+        }
+
+        unsafe
+        {
+            let slab = &mut *self.partial;
+            let object = slab.free;
+            slab.free = (*object).next;
+            slab.in_use += 1;
+            if slab.free.is_null() {
+                self.partial = slab.next;
+            }
+
+            self.in_use += 1;
+            self.allocs += 1;
+            return object as *mut u8;
+        }
+    }
+
+    unsafe fn dealloc(&mut self, ptr: *mut u8)
+    {
+        unsafe
+        {
+            let slab = &mut *((ptr as usize & !(SLAB_SIZE - 1)) as *mut SlabHeader);
+            let was_full = slab.free.is_null();
+
+            let object = ptr as *mut FreeObject;
+            (*object).next = slab.free;
+            slab.free = object;
+            slab.in_use -= 1;
+            self.in_use -= 1;
+
+            if was_full
+            {
+                slab.next = self.partial;
+                self.partial = slab;
+            }
+        }
+    }
+
+    // Takes a frame for a new slab. This runs in the middle of an allocation,
+    // so it gives up instead of waiting for the frame allocator's lock.
+    fn add_slab(&mut self) -> bool
+    {
+        let phys_offset = PHYS_OFFSET.load(Ordering::SeqCst);
+        if phys_offset == 0 { return false; }
+
+        let frame = match memory::FRAME_ALLOCATOR.try_lock().and_then(|mut frame_allocator| frame_allocator.allocate_frame())
+        {
+            Some(frame) => frame,
+            None => return false,
+        };
+
+        let start = (phys_offset + frame.start_address().as_u64()) as usize;
+        unsafe
+        {
+            let mut free: *mut FreeObject = null_mut();
+            for i in (0..self.objects_per_slab()).rev()
+            {
+                let object = (start + self.first_object_offset() + i * self.object_size) as *mut FreeObject;
+                (*object).next = free;
+                free = object;
+            }
+
+            let slab = start as *mut SlabHeader;
+            *slab = SlabHeader { next: self.partial, free, in_use: 0 };
+            self.partial = slab;
+        }
+
+        self.slabs += 1;
+        return true;
+    }
+}
+
+/// Lets the caches take frames. Until then every allocation goes to the heap.
+pub fn init(phys_offset: x86_64::VirtAddr)
+{
+    PHYS_OFFSET.store(phys_offset.as_u64(), Ordering::SeqCst);
+}
+
+// The cache for allocations with this layout, if they're small enough
+fn cache_index(layout: &Layout) -> Option<usize>
+{
+    let size = core::cmp::max(layout.size(), layout.align());
+    return SIZE_CLASSES.iter().position(|&class| size <= class);
+}
+
+/// Allocates from the cache for the layout. Returns null if it's too big
+/// for the caches, or if there are no free objects and no frames for a new slab.
+pub fn alloc(layout: &Layout) -> *mut u8
+{
+    return match cache_index(layout)
+    {
+        Some(idx) => CACHES[idx].lock().alloc(),
+        None => null_mut(),
+    };
+}
+
+/// Whether the memory was allocated by the caches.
+pub fn owns(ptr: *mut u8) -> bool
+{
+    // Slabs are the only allocations in the physical memory mapping
+    let phys_offset = PHYS_OFFSET.load(Ordering::SeqCst);
+    return phys_offset != 0 && ptr as u64 >= phys_offset;
+}
+
+/// Frees memory allocated by alloc with the same layout.
+pub unsafe fn dealloc(ptr: *mut u8, layout: &Layout)
+{
+    let idx = cache_index(layout).expect("slab dealloc with a layout that's too big");
+    unsafe { CACHES[idx].lock().dealloc(ptr) };
+}
+
+pub struct SlabStats
+{
+    pub object_size: usize,
+    pub slabs: usize,
+    /// Objects that are allocated.
+    pub in_use: usize,
+    /// Objects in all the slabs of the cache.
+    pub capacity: usize,
+    /// Allocations since boot.
+    pub allocs: u64,
+}
+
+/// Calls `f` with the statistics of every cache. Caches that are locked are
+/// skipped, so it can be used while debugging or panicking.
+pub fn try_for_each_cache(mut f: impl FnMut(SlabStats))
+{
+    for cache in CACHES.iter()
+    {
+        if let Some(cache) = cache.try_lock()
+        {
+            f(SlabStats {
+                object_size: cache.object_size,
+                slabs: cache.slabs,
+                in_use: cache.in_use,
+                capacity: cache.slabs * cache.objects_per_slab(),
+                allocs: cache.allocs,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use alloc::{boxed::Box, vec::Vec};
+
+    fn stats_of(object_size: usize) -> SlabStats
+    {
+        let mut res = None;
+        try_for_each_cache(|stats| if stats.object_size == object_size { res = Some(stats); });
+        return res.unwrap();
+    }
+
+    #[test_case]
+    fn small_objects_use_slabs()
+    {
+        let x = Box::new(42u64);
+        assert!(owns(&*x as *const u64 as *mut u8));
+
+        let big: Vec<u8> = Vec::with_capacity(8192);
+        assert!(!owns(big.as_ptr() as *mut u8));
+    }
+
+    #[test_case]
+    fn objects_are_aligned_and_distinct()
+    {
+        let layout = Layout::from_size_align(48, 64).unwrap();
+        let mut objects = Vec::new();
+        for _ in 0..200
+        {
+            let ptr = alloc(&layout);
+            assert!(!ptr.is_null());
+            assert_eq!(ptr as usize % 64, 0);
+            assert!(!objects.contains(&ptr));
+            objects.push(ptr);
+        }
+
+        for ptr in objects {
+            unsafe { dealloc(ptr, &layout) };
+        }
+    }
+
+    #[test_case]
+    fn freed_objects_are_reused()
+    {
+        let layout = Layout::from_size_align(200, 8).unwrap();
+        let before = stats_of(256);
+
+        let objects: Vec<*mut u8> = (0..100).map(|_| alloc(&layout)).collect();
+        assert_eq!(stats_of(256).in_use, before.in_use + 100);
+        for &ptr in &objects {
+            unsafe { dealloc(ptr, &layout) };
+        }
+
+        let after = stats_of(256);
+        assert_eq!(after.in_use, before.in_use);
+        let objects: Vec<*mut u8> = (0..100).map(|_| alloc(&layout)).collect();
+        assert_eq!(stats_of(256).slabs, after.slabs);
+        for &ptr in &objects {
+            unsafe { dealloc(ptr, &layout) };
+        }
+    }
+}
@@ -0,0 +1,203 @@
+// Syscall tracing, like strace. Every syscall made by a traced task is
+// logged with its arguments, what it returned and how long it took. Tasks
+// are traced when created with CREATE_TRACED or with the trace syscall,
+// and the tasks they create are traced too.
+
+use crate::{info, memory, time};
+use crate::interrupts::{Syscall, READ_PENDING};
+use crate::process;
+use core::fmt;
+
+// Longest string argument that's shown in full
+const MAX_STRING_LEN: usize = 32;
+
+pub fn syscall_name(syscall: u64) -> &'static str
+{
+    return match syscall
+    {
+        x if x == Syscall::Print as u64 => "print",
+        x if x == Syscall::PrintNum as u64 => "print_num",
+        x if x == Syscall::PrintChar as u64 => "print_char",
+        x if x == Syscall::ReadChar as u64 => "read_char",
+        x if x == Syscall::CreateTask as u64 => "create_task",
+        x if x == Syscall::GetArg0 as u64 => "get_arg0",
+        x if x == Syscall::Exit as u64 => "exit",
+        x if x == Syscall::Shutdown as u64 => "shutdown",
+        x if x == Syscall::ListProcesses as u64 => "list_processes",
+        x if x == Syscall::Kill as u64 => "kill",
+        x if x == Syscall::Signal as u64 => "signal",
+        x if x == Syscall::SigProcMask as u64 => "sigprocmask",
+        x if x == Syscall::SigReturn as u64 => "sigreturn",
+        x if x == Syscall::GetPid as u64 => "get_pid",
+        x if x == Syscall::Wait as u64 => "wait",
+        x if x == Syscall::SetForeground as u64 => "set_foreground",
+        x if x == Syscall::Read as u64 => "read",
+        x if x == Syscall::Ioctl as u64 => "ioctl",
+        x if x == Syscall::SetKeymap as u64 => "set_keymap",
+        x if x == Syscall::GetKeymap as u64 => "get_keymap",
+        x if x == Syscall::Dmesg as u64 => "dmesg",
+        x if x == Syscall::TestReport as u64 => "test_report",
+        x if x == Syscall::MemoryMap as u64 => "memory_map",
+        x if x == Syscall::Trace as u64 => "trace",
+        x if x == Syscall::Profile as u64 => "profile",
+        x if x == Syscall::Brk as u64 => "brk",
+        x if x == Syscall::Sbrk as u64 => "sbrk",
+        _ => "unknown",
+    };
+}
+
+/// Whether the syscall doesn't return to where it was made, so it
+/// has to be logged before it runs.
+pub fn never_returns(syscall: u64) -> bool
+{
+    return syscall == Syscall::Exit as u64 || syscall == Syscall::SigReturn as u64;
+}
+
+/// Logs a syscall that has returned. `cycles` is how long it took in TSC cycles.
+pub fn log_syscall(pid: process::Pid, syscall: u64, args: [u64; 4], retval: u64, cycles: u64)
+{
+    info!("[{}] {} = {} <{}us>", pid, Call { syscall, args }, Return { syscall, retval }, time::tsc_to_micros(cycles));
+}
+
+/// Logs a syscall that won't return (see never_returns).
+pub fn log_syscall_entry(pid: process::Pid, syscall: u64, args: [u64; 4])
+{
+    info!("[{}] {} = ?", pid, Call { syscall, args });
+}
+
+struct Call
+{
+    syscall: u64,
+    args: [u64; 4],
+}
+
+impl fmt::Display for Call
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let [a0, a1, a2, _] = self.args;
+        write!(f, "{}(", syscall_name(self.syscall))?;
+        match self.syscall
+        {
+            x if x == Syscall::Print as u64 => write!(f, "{}, {}", UserStr(a0, a1), a1)?,
+            x if x == Syscall::PrintNum as u64 => write!(f, "{}", a0)?,
+            x if x == Syscall::PrintChar as u64 => write!(f, "{}", Char(a0))?,
+            x if x == Syscall::CreateTask as u64 => write!(f, "{}, {:#x}", UserStr(a0, a1), a2)?,
+            x if x == Syscall::Exit as u64 => write!(f, "{}", a0)?,
+            x if x == Syscall::ListProcesses as u64 => write!(f, "{:#x}, {}", a0, a1)?,
+            x if x == Syscall::Kill as u64 => write!(f, "{}, {}", a0, Sig(a1))?,
+            x if x == Syscall::Signal as u64 => write!(f, "{}, {:#x}, {:#x}", Sig(a0), a1, a2)?,
+            x if x == Syscall::SigProcMask as u64 => write!(f, "{}, {:#x}", a0, a1)?,
+            x if x == Syscall::Wait as u64 => write!(f, "{}, {:#x}", a0, a1)?,
+            x if x == Syscall::SetForeground as u64 => write!(f, "{}", a0)?,
+            x if x == Syscall::Read as u64 => write!(f, "{:#x}, {}", a0, a1)?,
+            x if x == Syscall::Ioctl as u64 => write!(f, "{}, {:#x}", a0, a1)?,
+            x if x == Syscall::SetKeymap as u64 => write!(f, "{}", UserStr(a0, a1))?,
+            x if x == Syscall::GetKeymap as u64 => write!(f, "{:#x}, {}", a0, a1)?,
+            x if x == Syscall::Dmesg as u64 => write!(f, "{:#x}, {}", a0, a1)?,
+            x if x == Syscall::TestReport as u64 => write!(f, "{}, {}", UserStr(a0, a1), a2 != 0)?,
+            x if x == Syscall::MemoryMap as u64 => write!(f, "{:#x}, {}", a0, a1)?,
+            x if x == Syscall::Trace as u64 => write!(f, "{}, {}", a0, a1 != 0)?,
+            x if x == Syscall::Profile as u64 => write!(f, "{}", a0)?,
+            x if x == Syscall::Brk as u64 => write!(f, "{:#x}", a0)?,
+            x if x == Syscall::Sbrk as u64 => write!(f, "{}", a0 as i64)?,
+            x if syscall_name(x) == "unknown" => write!(f, "#{}", x)?,
+            _ => {},
+        }
+        return write!(f, ")");
+    }
+}
+
+struct Return
+{
+    syscall: u64,
+    retval: u64,
+}
+
+impl fmt::Display for Return
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let (syscall, retval) = (self.syscall, self.retval);
+        return match syscall
+        {
+            x if x == Syscall::ReadChar as u64 && retval == 0 => write!(f, "0 (blocked)"),
+            x if x == Syscall::ReadChar as u64 => write!(f, "{}", Char(retval)),
+            x if x == Syscall::Read as u64 && retval == READ_PENDING => write!(f, "READ_PENDING (blocked)"),
+            x if x == Syscall::Wait as u64 && retval == process::WAIT_PENDING => write!(f, "WAIT_PENDING (blocked)"),
+            x if x == Syscall::Signal as u64 || x == Syscall::SigProcMask as u64 => write!(f, "{:#x}", retval),
+            x if x == Syscall::Brk as u64 || x == Syscall::Sbrk as u64 => write!(f, "{:#x}", retval),
+            _ => write!(f, "{}", retval as i64),
+        };
+    }
+}
+
+// A string in user space, quoted and cut short if it's long
+struct UserStr(u64, u64);
+
+impl fmt::Display for UserStr
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        let (ptr, len) = (self.0, self.1 as usize);
+        let shown = core::cmp::min(len, MAX_STRING_LEN);
+        if !memory::is_user_range(ptr, shown as u64, false) {
+            return write!(f, "{:#x}", ptr);
+        }
+
+        let bytes = unsafe { core::slice::from_raw_parts(ptr as *const u8, shown) };
+        write!(f, "\"")?;
+        for chunk in bytes.utf8_chunks()
+        {
+            write!(f, "{}", chunk.valid().escape_debug())?;
+            for byte in chunk.invalid() {
+                write!(f, "\\x{:02x}", byte)?;
+            }
+        }
+        write!(f, "\"")?;
+        if len > MAX_STRING_LEN {
+            write!(f, "...")?;
+        }
+        return Ok(());
+    }
+}
+
+struct Char(u64);
+
+impl fmt::Display for Char
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        return match char::from_u32(self.0 as u32)
+        {
+            Some(c) if self.0 <= u32::MAX as u64 => write!(f, "'{}'", c.escape_debug()),
+            _ => write!(f, "{:#x}", self.0),
+        };
+    }
+}
+
+// A signal number, with its name if it has one
+struct Sig(u64);
+
+impl fmt::Display for Sig
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        use crate::signal::*;
+        let name = match self.0
+        {
+            x if x == SIGINT as u64 => "SIGINT",
+            x if x == SIGKILL as u64 => "SIGKILL",
+            x if x == SIGUSR1 as u64 => "SIGUSR1",
+            x if x == SIGUSR2 as u64 => "SIGUSR2",
+            x if x == SIGSEGV as u64 => "SIGSEGV",
+            x if x == SIGTERM as u64 => "SIGTERM",
+            x if x == SIGCHLD as u64 => "SIGCHLD",
+            x if x == SIGCONT as u64 => "SIGCONT",
+            x if x == SIGSTOP as u64 => "SIGSTOP",
+            x if x == SIGTSTP as u64 => "SIGTSTP",
+            x => return write!(f, "{}", x),
+        };
+        return write!(f, "{}", name);
+    }
+}
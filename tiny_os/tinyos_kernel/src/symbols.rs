@@ -0,0 +1,86 @@
+// Kernel symbol table, used to show function names in backtraces.
+// The kernel can't read its own ELF file, so the table is stored in the
+// .ksyms section, which is empty when the kernel is compiled and gets
+// filled in afterwards by tools/embed_symbols.py (cargo run and cargo test
+// do that through tools/runner.py). Without that step backtraces just show
+// addresses.
+//
+// Layout (little endian):
+//   "KSYM", number of symbols (u32),
+//   one entry per symbol sorted by address: address (u64), size (u32), name offset (u32), name length (u32),
+//   the names, with offsets relative to the end of the entries.
+
+// Debug builds need a bit over 300 KiB
+pub const KSYMS_SIZE: usize = 1024 * 1024;
+const MAGIC: &[u8; 4] = b"KSYM";
+const HEADER_SIZE: usize = 8;
+const ENTRY_SIZE: usize = 20;
+
+#[used]
+#[unsafe(link_section = ".ksyms")]
+static KSYMS: [u8; KSYMS_SIZE] = [0; KSYMS_SIZE];
+
+fn table() -> &'static [u8]
+{
+    // The contents are patched after compilation, so the
+    // compiler must not assume they're all zeros.
+    let ptr = core::hint::black_box(KSYMS.as_ptr());
+    return unsafe { core::slice::from_raw_parts(ptr, KSYMS_SIZE) };
+}
+
+fn read_u32(data: &[u8], offset: usize) -> u32
+{
+    return u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64
+{
+    return u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap());
+}
+
+fn num_symbols(data: &[u8]) -> usize
+{
+    if &data[..4] != MAGIC { return 0; }
+
+    let count = read_u32(data, 4) as usize;
+    if HEADER_SIZE + count * ENTRY_SIZE > data.len() { return 0; }
+    return count;
+}
+
+pub fn is_available() -> bool
+{
+    return num_symbols(table()) > 0;
+}
+
+/// Finds the function containing `addr`. Returns its name and the offset of `addr` into it.
+pub fn lookup(addr: u64) -> Option<(&'static str, u64)>
+{
+    let data = table();
+    let count = num_symbols(data);
+    if count == 0 { return None; }
+
+    let entry_addr = |idx: usize| read_u64(data, HEADER_SIZE + idx * ENTRY_SIZE);
+
+    // Last symbol at or before addr
+    let (mut lo, mut hi) = (0, count);
+    while lo < hi
+    {
+        let mid = (lo + hi) / 2;
+        if entry_addr(mid) <= addr { lo = mid + 1; } else { hi = mid; }
+    }
+    if lo == 0 { return None; }
+    let idx = lo - 1;
+
+    // Past the end of the function, e.g. in padding or past the last one
+    let entry = HEADER_SIZE + idx * ENTRY_SIZE;
+    let offset = addr - entry_addr(idx);
+    if offset >= read_u32(data, entry + 8) as u64 { return None; }
+
+    let names_start = HEADER_SIZE + count * ENTRY_SIZE;
+    let name_start = names_start + read_u32(data, entry + 12) as usize;
+    let name_end = name_start + read_u32(data, entry + 16) as usize;
+    if name_end > data.len() { return None; }
+
+    let name = core::str::from_utf8(&data[name_start..name_end]).ok()?;
+    return Some((name, offset));
+}
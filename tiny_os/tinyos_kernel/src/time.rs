@@ -0,0 +1,59 @@
+// Timekeeping. The timer interrupt is the kernel's only clock, so
+// everything is measured in ticks. The time stamp counter is used for
+// intervals shorter than a tick, and it's calibrated against the timer.
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+static TICKS: AtomicU64 = AtomicU64::new(0);
+static LAST_TICK_TSC: AtomicU64 = AtomicU64::new(0);
+static TSC_PER_TICK: AtomicU64 = AtomicU64::new(0);
+
+/// Number of timer interrupts since boot.
+pub fn ticks() -> u64
+{
+    return TICKS.load(Ordering::Relaxed);
+}
+
+pub fn read_tsc() -> u64
+{
+    return unsafe { core::arch::x86_64::_rdtsc() };
+}
+
+/// Must be called on every timer interrupt.
+pub fn on_timer_tick()
+{
+    TICKS.fetch_add(1, Ordering::Relaxed);
+
+    let now = read_tsc();
+    let last = LAST_TICK_TSC.swap(now, Ordering::Relaxed);
+    if last != 0 && now > last
+    {
+        // Smooth out the measurement a bit
+        let delta = now - last;
+        let prev = TSC_PER_TICK.load(Ordering::Relaxed);
+        let new = if prev == 0 { delta } else { (prev * 7 + delta) / 8 };
+        TSC_PER_TICK.store(new, Ordering::Relaxed);
+    }
+}
+
+/// Converts a number of TSC cycles to timer ticks.
+/// Returns 0 until the TSC has been calibrated.
+pub fn tsc_to_ticks(cycles: u64) -> u64
+{
+    let tsc_per_tick = TSC_PER_TICK.load(Ordering::Relaxed);
+    if tsc_per_tick == 0 { return 0; }
+    return cycles / tsc_per_tick;
+}
+
+// The PIT isn't reprogrammed, so it runs at its default
+// rate of 1193182 / 65536 Hz: a tick every 54925 us.
+pub const MICROS_PER_TICK: u64 = 54925;
+
+/// Converts a number of TSC cycles to microseconds.
+/// Returns 0 until the TSC has been calibrated.
+pub fn tsc_to_micros(cycles: u64) -> u64
+{
+    let tsc_per_tick = TSC_PER_TICK.load(Ordering::Relaxed);
+    if tsc_per_tick == 0 { return 0; }
+    return (cycles as u128 * MICROS_PER_TICK as u128 / tsc_per_tick as u128) as u64;
+}
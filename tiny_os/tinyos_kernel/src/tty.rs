@@ -0,0 +1,318 @@
+// Line discipline between the keyboard and the programs reading from it.
+// In canonical mode input is collected a line at a time, with echo and
+// basic line editing. In raw mode every byte goes straight to the reader.
+
+use crate::{console::{self, NUM_CONSOLES}, process, signal, vga_buffer};
+use alloc::{collections::VecDeque, vec::Vec};
+use core::fmt;
+use lazy_static::lazy_static;
+use spin::Mutex;
+
+// Mode flags.
+// NOTE: These should be kept up to date along with their
+// counterparts in the usercode library.
+/// Input is line buffered and can be edited before it's sent.
+pub const TTY_CANONICAL: u64 = 1 << 0;
+/// Input is echoed to the screen.
+pub const TTY_ECHO:      u64 = 1 << 1;
+/// Ctrl-C and Ctrl-Z send signals to the foreground task.
+pub const TTY_SIGNALS:   u64 = 1 << 2;
+
+pub const TTY_DEFAULT_MODE: u64 = TTY_CANONICAL | TTY_ECHO | TTY_SIGNALS;
+
+// Requests of the ioctl syscall
+pub const TTY_GET_MODE: u64 = 0;
+pub const TTY_SET_MODE: u64 = 1;
+
+// Special characters
+const CTRL_C:    u8 = 0x03;  // Interrupt
+const CTRL_D:    u8 = 0x04;  // End of file
+const BACKSPACE: u8 = 0x08;  // Erase
+const CTRL_U:    u8 = 0x15;  // Kill line
+const CTRL_Z:    u8 = 0x1A;  // Suspend
+const DELETE:    u8 = 0x7F;  // Erase
+
+pub struct Tty
+{
+    // Console this tty reads from and echoes to
+    console: usize,
+    mode: u64,
+    // Line being edited in canonical mode
+    line: Vec<u8>,
+    // Bytes ready to be read, where None is an end of file
+    // (Ctrl-D on an empty line), in the order they were typed
+    input: VecDeque<Option<u8>>,
+    // Input is UTF-8, so characters are echoed only once all of their bytes arrived
+    echo_buf: [u8; 4],
+    echo_len: usize,
+}
+
+lazy_static! {
+    // One for each virtual console
+    pub static ref TTYS: [Mutex<Tty>; NUM_CONSOLES] = core::array::from_fn(|i| Mutex::new(Tty::new(i)));
+}
+
+/// The tty of the console the running task is bound to.
+pub fn current() -> &'static Mutex<Tty>
+{
+    return &TTYS[console::current()];
+}
+
+impl Tty
+{
+    pub fn new(console: usize) -> Self
+    {
+        return Tty {
+            console,
+            mode: TTY_DEFAULT_MODE,
+            line: Vec::new(),
+            input: VecDeque::new(),
+            echo_buf: [0; 4],
+            echo_len: 0,
+        };
+    }
+
+    pub fn mode(&self) -> u64
+    {
+        return self.mode;
+    }
+
+    pub fn set_mode(&mut self, mode: u64)
+    {
+        // Whatever was being edited becomes readable right away
+        if self.mode & TTY_CANONICAL != 0 && mode & TTY_CANONICAL == 0 {
+            self.input.extend(self.line.drain(..).map(Some));
+        }
+
+        self.mode = mode;
+    }
+
+    /// Handles a byte coming from the keyboard.
+    pub fn input_byte(&mut self, byte: u8)
+    {
+        if self.mode & TTY_SIGNALS != 0
+        {
+            match byte
+            {
+                CTRL_C => { self.line.clear(); self.signal_foreground_task(signal::SIGINT, "^C"); return; }
+                CTRL_Z => { self.signal_foreground_task(signal::SIGTSTP, "^Z"); return; }
+                _ => {},
+            }
+        }
+
+        if self.mode & TTY_CANONICAL == 0
+        {
+            self.input.push_back(Some(byte));
+            self.echo(byte);
+            return;
+        }
+
+        match byte
+        {
+            BACKSPACE | DELETE =>
+            {
+                if self.erase_char() {
+                    self.echo(BACKSPACE);
+                }
+            }
+            CTRL_U =>
+            {
+                while self.erase_char() {
+                    self.echo(BACKSPACE);
+                }
+            }
+            CTRL_D =>
+            {
+                // Sends the line as it is, or signals end of file if it's empty
+                if self.line.is_empty() {
+                    self.input.push_back(None);
+                } else {
+                    self.input.extend(self.line.drain(..).map(Some));
+                }
+            }
+            b'\n' =>
+            {
+                self.line.push(b'\n');
+                self.input.extend(self.line.drain(..).map(Some));
+                self.echo(b'\n');
+            }
+            _ =>
+            {
+                self.line.push(byte);
+                self.echo(byte);
+            }
+        }
+    }
+
+    // Removes the last character (not byte) from the line being edited
+    fn erase_char(&mut self) -> bool
+    {
+        while let Some(byte) = self.line.pop()
+        {
+            let is_continuation = byte & 0xC0 == 0x80;
+            if !is_continuation { return true; }
+        }
+
+        return false;
+    }
+
+    fn echo(&mut self, byte: u8)
+    {
+        if self.mode & TTY_ECHO == 0 { return; }
+
+        if byte >= 0x80
+        {
+            // Part of a multi-byte character
+            if self.echo_len < self.echo_buf.len() {
+                self.echo_buf[self.echo_len] = byte;
+                self.echo_len += 1;
+            }
+
+            let expected_len = match self.echo_buf[0]
+            {
+                0xC0..=0xDF => 2,
+                0xE0..=0xEF => 3,
+                _ => 4,
+            };
+
+            if self.echo_len >= expected_len
+            {
+                if let Ok(string) = core::str::from_utf8(&self.echo_buf[..self.echo_len]) {
+                    self.output(format_args!("{}", string));
+                }
+                self.echo_len = 0;
+            }
+            return;
+        }
+
+        self.echo_len = 0;
+        match byte
+        {
+            b'\n' => self.output(format_args!("\n")),
+            BACKSPACE => self.output(format_args!("\x08 \x08")),
+            // Show control characters like ^X
+            0..=0x1F => self.output(format_args!("^{}", (byte + b'@') as char)),
+            _ => self.output(format_args!("{}", byte as char)),
+        }
+    }
+
+    fn output(&self, args: fmt::Arguments)
+    {
+        vga_buffer::print_to(self.console, args);
+    }
+
+    fn signal_foreground_task(&self, sig: u32, echo: &str)
+    {
+        if self.mode & TTY_ECHO != 0 {
+            self.output(format_args!("{}\n", echo));
+        }
+        if let Some(pid) = process::SCHEDULER.foreground(self.console) {
+            process::SCHEDULER.send_signal(pid, sig);
+        }
+    }
+
+    /// Whether a read would return something right now (data or end of file).
+    pub fn can_read(&self) -> bool
+    {
+        return !self.input.is_empty();
+    }
+
+    /// Reads a whole UTF-8 encoded character. Invalid
+    /// sequences are returned as U+FFFD, ends of file are skipped.
+    pub fn read_char(&mut self) -> Option<char>
+    {
+        while self.input.front() == Some(&None) {
+            self.input.pop_front();
+        }
+
+        let first = self.input.pop_front()??;
+        let len = match first
+        {
+            0x00..=0x7F => return Some(first as char),
+            0xC2..=0xDF => 2,
+            0xE0..=0xEF => 3,
+            0xF0..=0xF4 => 4,
+            _ => return Some(char::REPLACEMENT_CHARACTER),
+        };
+
+        let mut bytes: [u8; 4] = [first, 0, 0, 0];
+        for slot in bytes[1..len].iter_mut()
+        {
+            match self.input.front()
+            {
+                Some(&Some(byte)) if byte & 0xC0 == 0x80 => { *slot = byte; self.input.pop_front(); }
+                _ => return Some(char::REPLACEMENT_CHARACTER),
+            }
+        }
+
+        let decoded = core::str::from_utf8(&bytes[..len]).ok().and_then(|s| s.chars().next());
+        return Some(decoded.unwrap_or(char::REPLACEMENT_CHARACTER));
+    }
+
+    /// Reads into `buf`. In canonical mode this reads at most a line.
+    /// Returns 0 on end of file.
+    pub fn read(&mut self, buf: &mut [u8]) -> usize
+    {
+        let mut count = 0;
+        while count < buf.len()
+        {
+            let byte = match self.input.front()
+            {
+                Some(&Some(byte)) => byte,
+                // An end of file is read on its own, after whatever came before it
+                Some(None) =>
+                {
+                    if count == 0 { self.input.pop_front(); }
+                    break;
+                }
+                None => break,
+            };
+
+            self.input.pop_front();
+            buf[count] = byte;
+            count += 1;
+            if byte == b'\n' && self.mode & TTY_CANONICAL != 0 { break; }
+        }
+
+        return count;
+    }
+}
+
+/// Feeds a byte coming from an input device to the tty of the
+/// active console and wakes up whoever is waiting for input there.
+pub fn input_byte(byte: u8)
+{
+    let console = console::active();
+    let mut tty = TTYS[console].lock();
+    tty.input_byte(byte);
+    if tty.can_read() {
+        process::SCHEDULER.wake_tasks(process::WaitReason::Input(console));
+    }
+}
+
+#[cfg(test)]
+mod tests
+{
+    use super::*;
+    use vga_buffer::{BUFFER_HEIGHT, BUFFER_WIDTH};
+
+    #[test_case]
+    fn canonical_echo_once_per_key()
+    {
+        // A console nothing else prints to
+        let console = NUM_CONSOLES - 1;
+        let mut tty = Tty::new(console);
+        vga_buffer::print_to(console, format_args!("\n"));
+        for &byte in b"ab\n" {
+            tty.input_byte(byte);
+        }
+
+        let mut expected = [b' '; BUFFER_WIDTH];
+        expected[..2].copy_from_slice(b"ab");
+        assert_eq!(vga_buffer::screen_row(console, BUFFER_HEIGHT - 2), expected);
+
+        let mut buf = [0u8; 8];
+        assert_eq!(tty.read(&mut buf), 3);
+        assert_eq!(&buf[..3], b"ab\n");
+    }
+}
@@ -0,0 +1,172 @@
+// Runner for the user space test programs (see user_programs/src/test_*.rs).
+// When the kernel is built with the "user_tests" feature it runs them one
+// at a time instead of starting the shells. The programs report each check
+// with the test_report syscall, and the results go to the serial port. Once
+// they're all done QEMU exits with success if nothing failed.
+
+use crate::{exit_qemu, memory, process, serial_println, tty, QemuExitCode};
+use crate::process::Pid;
+use spin::Mutex;
+
+pub struct TestProgram
+{
+    pub name: &'static str,
+    pub blob: &'static [u8],
+    /// Typed into the console before the program starts.
+    pub input: &'static [u8],
+}
+
+// NOTE: The binaries are built and copied here by build_and_copy_user_programs.
+#[cfg(feature = "user_tests")]
+pub const PROGRAMS: &[TestProgram] = &[
+    TestProgram { name: "test_syscalls", blob: include_bytes!("user_tests/test_syscalls"), input: b"" },
+    TestProgram { name: "test_tasks",    blob: include_bytes!("user_tests/test_tasks"),    input: b"" },
+    TestProgram { name: "test_read",     blob: include_bytes!("user_tests/test_read"),     input: "hello\nxé".as_bytes() },
+];
+
+/// Programs the tests launch with create_task, which aren't tests themselves.
+#[cfg(feature = "user_tests")]
+pub const HELPERS: &[(&str, &[u8])] = &[
+    ("test_child", include_bytes!("user_tests/test_child")),
+];
+
+#[cfg(not(feature = "user_tests"))]
+pub const PROGRAMS: &[TestProgram] = &[];
+#[cfg(not(feature = "user_tests"))]
+pub const HELPERS: &[(&str, &[u8])] = &[];
+
+/// Looks up a test or helper program by name, for create_task.
+pub fn find_program(name: &str) -> Option<&'static [u8]>
+{
+    if let Some(program) = PROGRAMS.iter().find(|p| p.name == name) {
+        return Some(program.blob);
+    }
+
+    return HELPERS.iter().find(|&&(helper, _)| helper == name).map(|&(_, blob)| blob);
+}
+
+struct Runner
+{
+    running: bool,
+    // Index in PROGRAMS of the next program to start
+    next: usize,
+    // Program that's running, and whether any of its checks failed
+    current: Option<Pid>,
+    current_failed: bool,
+    // Set when the current program is gone and the next one should start
+    finished: bool,
+    passed_programs: usize,
+    failed_programs: usize,
+}
+
+static RUNNER: Mutex<Runner> = Mutex::new(Runner {
+    running: false,
+    next: 0,
+    current: None,
+    current_failed: false,
+    finished: false,
+    passed_programs: 0,
+    failed_programs: 0,
+});
+
+/// Starts running the test programs.
+pub fn start()
+{
+    serial_println!("Running {} user programs", PROGRAMS.len());
+    RUNNER.lock().running = true;
+    start_next();
+}
+
+// Starts the next program that can be loaded, or exits when there are no more
+fn start_next()
+{
+    loop
+    {
+        let idx = RUNNER.lock().next;
+        if idx >= PROGRAMS.len() { finish(); }
+        RUNNER.lock().next += 1;
+
+        let program = &PROGRAMS[idx];
+        serial_println!("{}:", program.name);
+
+        let task = {
+            let kern_mem_info = memory::KERNEL_MEM_INFO.lock();
+            process::create_task(program.name, program.blob, kern_mem_info.phys_offset, kern_mem_info.kernel_page_table_phys_addr, 0)
+        };
+
+        let task = match task
+        {
+            Some(task) => task,
+            None =>
+            {
+                serial_println!("{}...\t[failed] (could not load)", program.name);
+                RUNNER.lock().failed_programs += 1;
+                continue;
+            }
+        };
+
+        for &byte in program.input {
+            tty::input_byte(byte);
+        }
+
+        // Interrupts are off, so it can't run (and exit) before we know its pid
+        let pid = process::SCHEDULER.schedule_task(task);
+        let mut runner = RUNNER.lock();
+        runner.current = Some(pid);
+        runner.current_failed = false;
+        return;
+    }
+}
+
+fn finish() -> !
+{
+    let (passed, failed) = {
+        let runner = RUNNER.lock();
+        (runner.passed_programs, runner.failed_programs)
+    };
+
+    serial_println!("{} passed, {} failed", passed, failed);
+    exit_qemu(if failed == 0 { QemuExitCode::Success } else { QemuExitCode::Failed });
+    crate::hlt_loop();
+}
+
+/// Result of a single check, reported by a test program.
+pub fn report(name: &str, passed: bool)
+{
+    serial_println!("    {}...\t{}", name, if passed { "[ok]" } else { "[failed]" });
+    if !passed {
+        RUNNER.lock().current_failed = true;
+    }
+}
+
+/// Called by the scheduler when a task is removed, with its wait status.
+/// The scheduler is locked, so the next program is started by poll.
+pub fn task_removed(pid: Pid, status: u64)
+{
+    let mut runner = RUNNER.lock();
+    if runner.current != Some(pid) { return; }
+
+    let ok = status == process::exited_status(0) && !runner.current_failed;
+    let name = PROGRAMS[runner.next - 1].name;
+    serial_println!("{}...\t{}", name, if ok { "[ok]" } else { "[failed]" });
+    if ok { runner.passed_programs += 1; } else { runner.failed_programs += 1; }
+
+    runner.current = None;
+    runner.finished = true;
+}
+
+/// Starts the next program once the previous one is gone. Called by the
+/// scheduler before picking a task, while it isn't locked.
+pub fn poll()
+{
+    let start = {
+        let mut runner = RUNNER.lock();
+        let start = runner.running && runner.finished;
+        runner.finished = false;
+        start
+    };
+
+    if start {
+        start_next();
+    }
+}
@@ -1,4 +1,6 @@
-
+use crate::ansi::{self, Action, CsiCommand};
+use crate::console::{self, NUM_CONSOLES};
+use crate::serial::{self, ConsoleMode};
 use core::fmt;
 use lazy_static::lazy_static;
 use spin::Mutex;
@@ -6,14 +8,18 @@ use volatile::Volatile;
 
 lazy_static!
 {
-    // Used by print! and println! macros
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::White, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+    // One for each virtual console. Used by print! and println! macros.
+    // The writer of the active console is the one holding the VGA buffer.
+    pub static ref WRITERS: [Mutex<Writer>; NUM_CONSOLES] = core::array::from_fn(|i| {
+        let buffer = if i == 0 { Some(unsafe { &mut *(0xb8000 as *mut Buffer) }) } else { None };
+        let lines = unsafe { &mut (*core::ptr::addr_of_mut!(CONSOLE_LINES))[i] };
+        return Mutex::new(Writer::new(buffer, lines));
     });
 }
 
+// This is too big to be built on the stack, so it's statically allocated
+static mut CONSOLE_LINES: [ConsoleLines; NUM_CONSOLES] = [const { ConsoleLines::new() }; NUM_CONSOLES];
+
 /// The standard color palette in VGA text mode.
 #[allow(dead_code)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -45,12 +51,26 @@ struct ColorCode(u8);
 
 impl ColorCode
 {
-    fn new(foreground: Color, background: Color) -> ColorCode
+    const fn new(foreground: Color, background: Color) -> ColorCode
     {
         return ColorCode((background as u8) << 4 | (foreground as u8))
     }
 }
 
+// Colors used by SGR sequences, in ANSI order (black, red, green, yellow, blue, magenta, cyan, white)
+const ANSI_COLORS: [Color; 8] = [
+    Color::Black, Color::Red, Color::Green, Color::Brown,
+    Color::Blue, Color::Magenta, Color::Cyan, Color::LightGray,
+];
+const ANSI_BRIGHT_COLORS: [Color; 8] = [
+    Color::DarkGray, Color::LightRed, Color::LightGreen, Color::Yellow,
+    Color::LightBlue, Color::Pink, Color::LightCyan, Color::White,
+];
+
+const DEFAULT_FOREGROUND: Color = Color::White;
+const DEFAULT_BACKGROUND: Color = Color::Black;
+const TAB_WIDTH: usize = 8;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[repr(C)]
 struct ScreenChar
@@ -59,109 +79,494 @@ struct ScreenChar
     color_code: ColorCode,
 }
 
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+// All zeros (so that the console lines end up in .bss). It's black on black,
+// which would hide the cursor, so it's shown as BLANK instead.
+const EMPTY: ScreenChar = ScreenChar {
+    ascii_character: 0,
+    color_code: ColorCode(0),
+};
+
+const BLANK: ScreenChar = ScreenChar {
+    ascii_character: b' ',
+    color_code: ColorCode::new(DEFAULT_FOREGROUND, DEFAULT_BACKGROUND),
+};
+
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
 
 #[repr(transparent)]
-struct Buffer
+pub struct Buffer
 {
     chars: [[Volatile<ScreenChar>; BUFFER_WIDTH]; BUFFER_HEIGHT],
 }
 
-// Allows writing ASCII bytes and strings to an underlying buffer
+/// Number of lines kept after they scroll off the top of the screen.
+pub const SCROLLBACK_LINES: usize = 500;
+
+type Line = [ScreenChar; BUFFER_WIDTH];
+
+/// What's on the console, including the lines that scrolled off the top.
+/// The VGA buffer only shows part of this when scrolling back.
+pub struct ConsoleLines
+{
+    screen: [Line; BUFFER_HEIGHT],
+    // Ring buffer of old lines
+    scrollback: [Line; SCROLLBACK_LINES],
+    scrollback_start: usize,
+    scrollback_count: usize,
+}
+
+impl ConsoleLines
+{
+    pub const fn new() -> Self
+    {
+        return ConsoleLines {
+            screen: [[EMPTY; BUFFER_WIDTH]; BUFFER_HEIGHT],
+            scrollback: [[EMPTY; BUFFER_WIDTH]; SCROLLBACK_LINES],
+            scrollback_start: 0,
+            scrollback_count: 0,
+        };
+    }
+
+    fn push_scrollback(&mut self, line: Line)
+    {
+        let idx = (self.scrollback_start + self.scrollback_count) % SCROLLBACK_LINES;
+        self.scrollback[idx] = line;
+        if self.scrollback_count < SCROLLBACK_LINES {
+            self.scrollback_count += 1;
+        } else {
+            self.scrollback_start = (self.scrollback_start + 1) % SCROLLBACK_LINES;
+        }
+    }
+
+    // Line shown at `row` when the view is scrolled back by `offset` lines
+    fn visible_line(&self, row: usize, offset: usize) -> &Line
+    {
+        if row >= offset { return &self.screen[row - offset]; }
+
+        // 1 is the most recent line in the scrollback
+        let age = offset - row;
+        let idx = (self.scrollback_start + self.scrollback_count - age) % SCROLLBACK_LINES;
+        return &self.scrollback[idx];
+    }
+}
+
+impl Default for ConsoleLines
+{
+    fn default() -> Self
+    {
+        return Self::new();
+    }
+}
+
+// Allows writing strings to an underlying buffer, like a terminal would.
+// Characters other than ASCII are shown with the code page 437 glyphs
+// built into the VGA, and ANSI escape sequences are used to move the
+// cursor around and change colors.
 pub struct Writer
 {
     column_position: usize,
+    row_position: usize,
+    // Attributes set with SGR sequences. The foreground is the color
+    // that was set, which bold shows brighter.
+    foreground: Color,
+    background: Color,
+    bold: bool,
+    reverse: bool,
     color_code: ColorCode,
-    buffer: &'static mut Buffer,
+    saved_cursor: (usize, usize),
+    parser: ansi::Parser,
+    lines: &'static mut ConsoleLines,
+    // How many lines the view is scrolled back, 0 when showing the live screen
+    view_offset: usize,
+    // Only set while this console is shown
+    buffer: Option<&'static mut Buffer>,
 }
 
 impl Writer
 {
+    pub fn new(buffer: Option<&'static mut Buffer>, lines: &'static mut ConsoleLines) -> Self
+    {
+        return Writer {
+            column_position: 0,
+            // Output starts at the bottom and scrolls up
+            row_position: BUFFER_HEIGHT - 1,
+            foreground: DEFAULT_FOREGROUND,
+            background: DEFAULT_BACKGROUND,
+            bold: false,
+            reverse: false,
+            color_code: ColorCode::new(DEFAULT_FOREGROUND, DEFAULT_BACKGROUND),
+            saved_cursor: (BUFFER_HEIGHT - 1, 0),
+            parser: ansi::Parser::new(),
+            lines,
+            view_offset: 0,
+            buffer,
+        };
+    }
+
     /// Writes an ASCII byte to the buffer.
     ///
-    /// Wraps lines at `BUFFER_WIDTH`. Supports the `\n` newline character and the backspace character.
+    /// Wraps lines at `BUFFER_WIDTH`. Supports the `\n`, `\r` and `\t` characters and the backspace
+    /// character, which like on a terminal only moves the cursor back (erasing is done with "\x08 \x08").
     pub fn write_byte(&mut self, byte: u8)
     {
         match byte
         {
             b'\n' => self.new_line(),
+            b'\r' => self.column_position = 0,
+            b'\t' =>
+            {
+                let next_stop = (self.column_position / TAB_WIDTH + 1) * TAB_WIDTH;
+                self.column_position = core::cmp::min(next_stop, BUFFER_WIDTH - 1);
+            }
             0x08 =>  // Backspace
             {
                 if self.column_position > 0 {
                     self.column_position -= 1;
-                    let row = BUFFER_HEIGHT - 1;
-                    let col = self.column_position;
-                    let color_code = self.color_code;
-
-                    self.buffer.chars[row][col].write(ScreenChar {
-                        ascii_character: b' ',
-                        color_code,
-                    });
                 }
             }
-            byte =>
+            byte => self.write_glyph(byte),
+        }
+    }
+
+    // Writes a code page 437 character, with no special handling of control characters
+    fn write_glyph(&mut self, glyph: u8)
+    {
+        if self.column_position >= BUFFER_WIDTH {
+            self.new_line();
+        }
+
+        let row = self.row_position;
+        let col = self.column_position;
+
+        let color_code = self.color_code;
+        self.put(row, col, ScreenChar {
+            ascii_character: glyph,
+            color_code,
+        });
+        self.column_position += 1;
+    }
+
+    // Updates the screen and, unless we're looking at the scrollback, the VGA buffer
+    fn put(&mut self, row: usize, col: usize, character: ScreenChar)
+    {
+        self.lines.screen[row][col] = character;
+        if self.view_offset != 0 { return; }
+
+        if let Some(buffer) = &mut self.buffer {
+            buffer.chars[row][col].write(character);
+        }
+    }
+
+    fn write_string(&mut self, s: &str)
+    {
+        // New output brings the view back to the live screen
+        if self.view_offset != 0 { self.scroll_view(-(self.view_offset as isize)); }
+
+        for c in s.chars()
+        {
+            match self.parser.feed(c)
             {
-                if self.column_position >= BUFFER_WIDTH {
-                    self.new_line();
-                }
+                Some(Action::Print(c)) => self.write_char(c),
+                Some(Action::Escape(c)) => self.escape(c),
+                Some(Action::Csi(command)) => self.csi(&command),
+                None => {},
+            }
+        }
 
-                let row = BUFFER_HEIGHT - 1;
-                let col = self.column_position;
+        self.update_cursor();
+    }
 
-                let color_code = self.color_code;
-                self.buffer.chars[row][col].write(ScreenChar {
-                    ascii_character: byte,
-                    color_code,
-                });
-                self.column_position += 1;
+    fn write_char(&mut self, c: char)
+    {
+        match c
+        {
+            '\n' | '\r' | '\t' | '\x08' => self.write_byte(c as u8),
+            // Other control characters
+            '\0'..='\x1f' | '\x7f' => self.write_glyph(0xfe),
+            _ => self.write_glyph(unicode_to_cp437(c)),
+        }
+    }
+
+    fn escape(&mut self, c: char)
+    {
+        match c
+        {
+            '7' => self.saved_cursor = (self.row_position, self.column_position),
+            '8' => (self.row_position, self.column_position) = self.saved_cursor,
+            'c' =>  // Reset
+            {
+                self.set_graphic_rendition(&[0]);
+                self.clear_screen();
+                self.move_cursor(0, 0);
             }
+            _ => {},
         }
     }
 
-    fn write_string(&mut self, s: &str)
+    fn csi(&mut self, command: &CsiCommand)
     {
-        for byte in s.bytes()
+        if command.private { return; }
+
+        let row = self.row_position as isize;
+        let col = core::cmp::min(self.column_position, BUFFER_WIDTH - 1) as isize;
+        let n = command.param(0, 1) as isize;
+
+        match command.final_char
         {
-            match byte
+            'A' => self.move_cursor(row - n, col),
+            'B' => self.move_cursor(row + n, col),
+            'C' => self.move_cursor(row, col + n),
+            'D' => self.move_cursor(row, col - n),
+            'G' => self.move_cursor(row, n - 1),
+            'd' => self.move_cursor(n - 1, col),
+            // Positions are 1-based
+            'H' | 'f' => self.move_cursor(n - 1, command.param(1, 1) as isize - 1),
+            'J' =>
             {
-                // Printable ASCII byte or newline or backspace
-                0x20..=0x7e | b'\n' | 0x08 => self.write_byte(byte),
-                // Non printable
-                _ => self.write_byte(0xfe),
+                let (row, col) = (row as usize, col as usize);
+                match command.param(0, 0)
+                {
+                    0 =>
+                    {
+                        self.clear_range(row, col, BUFFER_WIDTH);
+                        for r in row + 1..BUFFER_HEIGHT { self.clear_row(r); }
+                    }
+                    1 =>
+                    {
+                        for r in 0..row { self.clear_row(r); }
+                        self.clear_range(row, 0, col + 1);
+                    }
+                    _ => self.clear_screen(),
+                }
             }
+            'K' =>
+            {
+                let (row, col) = (row as usize, col as usize);
+                match command.param(0, 0)
+                {
+                    0 => self.clear_range(row, col, BUFFER_WIDTH),
+                    1 => self.clear_range(row, 0, col + 1),
+                    _ => self.clear_row(row),
+                }
+            }
+            'm' => self.set_graphic_rendition(&command.params[..command.num_params]),
+            's' => self.saved_cursor = (self.row_position, self.column_position),
+            'u' => (self.row_position, self.column_position) = self.saved_cursor,
+            _ => {},
         }
     }
 
-    // Shifts all lines up and clears the last row
-    fn new_line(&mut self)
+    // Moves the cursor, clamping it to the screen.
+    fn move_cursor(&mut self, row: isize, col: isize)
     {
-        for row in 1..BUFFER_HEIGHT
+        self.row_position = row.clamp(0, BUFFER_HEIGHT as isize - 1) as usize;
+        self.column_position = col.clamp(0, BUFFER_WIDTH as isize - 1) as usize;
+    }
+
+    fn set_graphic_rendition(&mut self, params: &[u16])
+    {
+        // ESC [ m is the same as ESC [ 0 m
+        let params = if params.is_empty() { &[0][..] } else { params };
+
+        for &param in params
         {
-            for col in 0..BUFFER_WIDTH
+            match param
             {
-                let character = self.buffer.chars[row][col].read();
-                self.buffer.chars[row - 1][col].write(character);
+                0 =>
+                {
+                    self.foreground = DEFAULT_FOREGROUND;
+                    self.background = DEFAULT_BACKGROUND;
+                    self.bold = false;
+                    self.reverse = false;
+                }
+                1 => self.bold = true,
+                22 => self.bold = false,
+                7 => self.reverse = true,
+                27 => self.reverse = false,
+                30..=37 => self.foreground = ANSI_COLORS[(param - 30) as usize],
+                39 => self.foreground = DEFAULT_FOREGROUND,
+                40..=47 => self.background = ANSI_COLORS[(param - 40) as usize],
+                49 => self.background = DEFAULT_BACKGROUND,
+                90..=97 => self.foreground = ANSI_BRIGHT_COLORS[(param - 90) as usize],
+                100..=107 => self.background = ANSI_BRIGHT_COLORS[(param - 100) as usize],
+                _ => {},
             }
         }
 
-        self.clear_row(BUFFER_HEIGHT - 1);
+        // There's no bold font, so bold is shown as a bright color
+        let foreground = if self.bold { bright(self.foreground) } else { self.foreground };
+        self.color_code = if self.reverse {
+            ColorCode::new(self.background, foreground)
+        } else {
+            ColorCode::new(foreground, self.background)
+        };
+    }
+
+    // Moves the blinking hardware cursor to where the next character goes
+    fn update_cursor(&self)
+    {
+        use x86_64::instructions::port::Port;
+
+        if self.buffer.is_none() { return; }
+
+        let col = core::cmp::min(self.column_position, BUFFER_WIDTH - 1);
+        let mut pos = (self.row_position * BUFFER_WIDTH + col) as u16;
+        // Hide the cursor while looking at the scrollback
+        if self.view_offset != 0 { pos = (BUFFER_HEIGHT * BUFFER_WIDTH) as u16; }
+        let mut index_port: Port<u8> = Port::new(0x3D4);
+        let mut data_port:  Port<u8> = Port::new(0x3D5);
+        unsafe
+        {
+            index_port.write(0x0F);
+            data_port.write((pos & 0xFF) as u8);
+            index_port.write(0x0E);
+            data_port.write((pos >> 8) as u8);
+        }
+    }
+
+    // Moves to the next line, shifting all lines up if we're at the bottom
+    fn new_line(&mut self)
+    {
         self.column_position = 0;
+        if self.row_position < BUFFER_HEIGHT - 1
+        {
+            self.row_position += 1;
+            return;
+        }
+
+        let top_line = self.lines.screen[0];
+        self.lines.push_scrollback(top_line);
+        self.lines.screen.copy_within(1.., 0);
+        self.clear_row(BUFFER_HEIGHT - 1);
+        self.redraw();
+    }
+
+    /// Scrolls the view back (positive) or forward (negative) through the scrollback.
+    pub fn scroll_view(&mut self, lines: isize)
+    {
+        let max_offset = self.lines.scrollback_count as isize;
+        let offset = (self.view_offset as isize + lines).clamp(0, max_offset) as usize;
+        if offset == self.view_offset { return; }
+
+        self.view_offset = offset;
+        self.redraw();
+        self.update_cursor();
+    }
+
+    // Copies what should be visible to the VGA buffer
+    fn redraw(&mut self)
+    {
+        let buffer = match &mut self.buffer
+        {
+            Some(buffer) => buffer,
+            None => return,
+        };
+
+        for row in 0..BUFFER_HEIGHT
+        {
+            let line = self.lines.visible_line(row, self.view_offset);
+            for (col, &cell) in line.iter().enumerate()
+            {
+                let character = if cell == EMPTY { BLANK } else { cell };
+                buffer.chars[row][col].write(character);
+            }
+        }
+    }
+
+    fn clear_screen(&mut self)
+    {
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
     }
 
     fn clear_row(&mut self, row: usize)
+    {
+        self.clear_range(row, 0, BUFFER_WIDTH);
+    }
+
+    // Clears columns [start, end) of a row
+    fn clear_range(&mut self, row: usize, start: usize, end: usize)
     {
         let blank = ScreenChar {
             ascii_character: b' ',
             color_code: self.color_code,
         };
 
-        for col in 0..BUFFER_WIDTH {
-            self.buffer.chars[row][col].write(blank);
+        for col in start..core::cmp::min(end, BUFFER_WIDTH) {
+            self.put(row, col, blank);
         }
     }
 }
 
+fn bright(color: Color) -> Color
+{
+    return match ANSI_COLORS.iter().position(|&c| c == color)
+    {
+        Some(idx) => ANSI_BRIGHT_COLORS[idx],
+        None => color,
+    };
+}
+
+// Unicode characters of the upper half of code page 437 (0x80..=0xFF).
+const CP437_UPPER: [char; 128] = [
+    'Ç', 'ü', 'é', 'â', 'ä', 'à', 'å', 'ç', 'ê', 'ë', 'è', 'ï', 'î', 'ì', 'Ä', 'Å',
+    'É', 'æ', 'Æ', 'ô', 'ö', 'ò', 'û', 'ù', 'ÿ', 'Ö', 'Ü', '¢', '£', '¥', '₧', 'ƒ',
+    'á', 'í', 'ó', 'ú', 'ñ', 'Ñ', 'ª', 'º', '¿', '⌐', '¬', '½', '¼', '¡', '«', '»',
+    '░', '▒', '▓', '│', '┤', '╡', '╢', '╖', '╕', '╣', '║', '╗', '╝', '╜', '╛', '┐',
+    '└', '┴', '┬', '├', '─', '┼', '╞', '╟', '╚', '╔', '╩', '╦', '╠', '═', '╬', '╧',
+    '╨', '╤', '╥', '╙', '╘', '╒', '╓', '╫', '╪', '┘', '┌', '█', '▄', '▌', '▐', '▀',
+    'α', 'ß', 'Γ', 'π', 'Σ', 'σ', 'µ', 'τ', 'Φ', 'Θ', 'Ω', 'δ', '∞', 'φ', 'ε', '∩',
+    '≡', '±', '≥', '≤', '⌠', '⌡', '÷', '≈', '°', '∙', '·', '√', 'ⁿ', '²', '■', '\u{a0}',
+];
+
+// The glyphs that code page 437 has in place of the control characters (0x01..=0x1F).
+const CP437_LOWER: [char; 31] = [
+    '☺', '☻', '♥', '♦', '♣', '♠', '•', '◘', '○', '◙', '♂', '♀', '♪', '♫', '☼',
+    '►', '◄', '↕', '‼', '¶', '§', '▬', '↨', '↑', '↓', '→', '←', '∟', '↔', '▲', '▼',
+];
+
+const CP437_NOT_FOUND: u8 = 0xfe;  // ■
+
+/// Maps a character to the code page 437 glyph that looks like it.
+/// Characters without a glyph are shown as a little square.
+pub fn unicode_to_cp437(c: char) -> u8
+{
+    if (' '..='~').contains(&c) { return c as u8; }
+    if c == '⌂' { return 0x7f; }
+
+    if let Some(idx) = CP437_UPPER.iter().position(|&glyph| glyph == c) {
+        return 0x80 + idx as u8;
+    }
+    if let Some(idx) = CP437_LOWER.iter().position(|&glyph| glyph == c) {
+        return 0x01 + idx as u8;
+    }
+
+    // Lookalikes, and accented letters that only have an unaccented glyph
+    let ascii = match c
+    {
+        'β' => return 0xe1,
+        'μ' => return 0xe6,
+        '∅' => return 0xed,
+        '∈' | '€' => return 0xee,
+        'À' | 'Á' | 'Â' | 'Ã' => 'A',
+        'È' | 'Ê' | 'Ë' => 'E',
+        'Ì' | 'Í' | 'Î' | 'Ï' => 'I',
+        'Ò' | 'Ó' | 'Ô' | 'Õ' => 'O',
+        'Ù' | 'Ú' | 'Û' => 'U',
+        'ã' => 'a',
+        'õ' => 'o',
+        'Ý' => 'Y',
+        'ý' => 'y',
+        '‘' | '’' => '\'',
+        '“' | '”' => '"',
+        '–' | '—' => '-',
+        _ => return CP437_NOT_FOUND,
+    };
+    return ascii as u8;
+}
+
 impl fmt::Write for Writer
 {
     fn write_str(&mut self, s: &str) -> fmt::Result
@@ -184,14 +589,60 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
-// Prints the given formatted string to the VGA text buffer
+/// Scrolls the console view by half a screen, back if `up` is true.
+pub fn scroll_half_page(up: bool)
+{
+    let half_page = (BUFFER_HEIGHT / 2) as isize;
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        WRITERS[console::active()].lock().scroll_view(if up { half_page } else { -half_page });
+    });
+}
+
+/// Hands the VGA buffer over from one console to another,
+/// and shows what's on the new one.
+pub fn switch_screen(from: usize, to: usize)
+{
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let buffer = WRITERS[from].lock().buffer.take();
+        let mut writer = WRITERS[to].lock();
+        writer.buffer = buffer;
+        writer.redraw();
+        writer.update_cursor();
+    });
+}
+
+// Prints the given formatted string to the console of the running task
 #[doc(hidden)]
 pub fn _print(args: fmt::Arguments)
+{
+    print_to(console::current(), args);
+}
+
+/// The characters on a row of a console's screen.
+#[cfg(test)]
+pub fn screen_row(console: usize, row: usize) -> [u8; BUFFER_WIDTH]
+{
+    return WRITERS[console].lock().lines.screen[row].map(|c| c.ascii_character);
+}
+
+/// Prints the given formatted string to a specific console.
+pub fn print_to(console: usize, args: fmt::Arguments)
 {
     use core::fmt::Write;
     use x86_64::instructions::interrupts;
 
     interrupts::without_interrupts(|| {
-        WRITER.lock().write_fmt(args).unwrap();
+        // The serial port shows the active console
+        let serial_mode = serial::console_mode();
+        let active = console == console::active();
+        if serial_mode != ConsoleMode::Off && active {
+            serial::write_console(args);
+        }
+
+        // Redirected output only skips the screen of the active console,
+        // the others keep their output for when they're switched to
+        if serial_mode != ConsoleMode::Redirect || !active {
+            WRITERS[console].lock().write_fmt(args).unwrap();
+        }
     });
 }
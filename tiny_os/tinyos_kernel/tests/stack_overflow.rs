@@ -0,0 +1,64 @@
+// Overflows the kernel stack, which should end up in the double fault
+// handler running on its own stack (the IST entry in the TSS) instead of
+// triple faulting. Uses its own IDT so that the handler can report success.
+
+#![no_std]
+#![no_main]
+#![feature(abi_x86_interrupt)]
+
+use core::panic::PanicInfo;
+use lazy_static::lazy_static;
+use tinyos::{exit_qemu, serial_print, serial_println, QemuExitCode};
+use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _start() -> !
+{
+    serial_print!("stack_overflow::stack_overflow...\t");
+
+    tinyos::gdt::init();
+    init_test_idt();
+
+    stack_overflow();
+
+    panic!("Execution continued after stack overflow");
+}
+
+#[allow(unconditional_recursion)]
+fn stack_overflow()
+{
+    stack_overflow();
+    // Prevents tail call optimization
+    volatile::Volatile::new(0).read();
+}
+
+lazy_static!
+{
+    static ref TEST_IDT: InterruptDescriptorTable = {
+        let mut idt = InterruptDescriptorTable::new();
+        unsafe {
+            idt.double_fault
+                .set_handler_fn(test_double_fault_handler)
+                .set_stack_index(tinyos::gdt::DOUBLE_FAULT_IST_INDEX);
+        }
+        idt
+    };
+}
+
+fn init_test_idt()
+{
+    TEST_IDT.load();
+}
+
+extern "x86-interrupt" fn test_double_fault_handler(_stack_frame: InterruptStackFrame, _error_code: u64) -> !
+{
+    serial_println!("[ok]");
+    exit_qemu(QemuExitCode::Success);
+    tinyos::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !
+{
+    tinyos::test_panic_handler(info);
+}
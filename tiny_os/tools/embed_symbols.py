@@ -0,0 +1,126 @@
+#!/usr/bin/env python3
+# Fills the .ksyms section of a kernel binary with its function symbols,
+# so that backtraces can show function names (see tinyos_kernel/src/symbols.rs).
+# cargo run and cargo test do this through tools/runner.py. To do it by hand,
+# run this on the kernel ELF after building it and before making the boot image:
+#
+#   python3 tools/embed_symbols.py target/tinyos_x64_target/debug/tinyos
+
+import re
+import struct
+import sys
+
+MAGIC = b"KSYM"
+STT_FUNC = 2
+SHT_NOBITS = 8
+
+ESCAPES = {
+    "$SP$": "@", "$BP$": "*", "$RF$": "&", "$LT$": "<", "$GT$": ">",
+    "$LP$": "(", "$RP$": ")", "$C$": ",",
+}
+
+def demangle(name):
+    """Demangles legacy Rust symbols, e.g. _ZN6tinyos4main17h0123456789abcdefE -> tinyos::main."""
+    if not name.startswith("_ZN") or not name.endswith("E"):
+        return name
+
+    parts = []
+    rest = name[3:-1]
+    while rest:
+        match = re.match(r"(\d+)", rest)
+        if not match:
+            return name
+        length = int(match.group(1))
+        start = len(match.group(1))
+        parts.append(rest[start:start + length])
+        rest = rest[start + length:]
+
+    # The last part is a hash
+    if parts and re.fullmatch(r"h[0-9a-f]{16}", parts[-1]):
+        parts.pop()
+
+    def unescape(part):
+        if part.startswith("_$"):
+            part = part[1:]
+        for escaped, char in ESCAPES.items():
+            part = part.replace(escaped, char)
+        part = re.sub(r"\$u([0-9a-f]+)\$", lambda m: chr(int(m.group(1), 16)), part)
+        return part.replace("..", "::")
+
+    return "::".join(unescape(part) for part in parts)
+
+def read_sections(elf):
+    if elf[:4] != b"\x7fELF" or elf[4] != 2:
+        sys.exit("Not a 64 bit ELF file.")
+
+    sh_offset, = struct.unpack_from("<Q", elf, 0x28)
+    sh_entry_size, sh_num, sh_str_index = struct.unpack_from("<HHH", elf, 0x3A)
+
+    sections = []
+    for i in range(sh_num):
+        fields = struct.unpack_from("<IIQQQQIIQQ", elf, sh_offset + i * sh_entry_size)
+        sections.append({
+            "name_offset": fields[0], "type": fields[1], "offset": fields[4],
+            "size": fields[5], "link": fields[6], "entry_size": fields[9],
+        })
+
+    names = sections[sh_str_index]
+    for section in sections:
+        start = names["offset"] + section["name_offset"]
+        section["name"] = elf[start:elf.index(b"\0", start)].decode()
+    return sections
+
+def function_symbols(elf, sections):
+    symtab = next((s for s in sections if s["name"] == ".symtab"), None)
+    if symtab is None:
+        sys.exit("The kernel has no symbol table (was it stripped?).")
+    strtab = sections[symtab["link"]]
+
+    symbols = {}
+    for offset in range(symtab["offset"], symtab["offset"] + symtab["size"], symtab["entry_size"]):
+        name_offset, info, _, _, value, size = struct.unpack_from("<IBBHQQ", elf, offset)
+        if info & 0xF != STT_FUNC or value == 0:
+            continue
+        start = strtab["offset"] + name_offset
+        name = elf[start:elf.index(b"\0", start)].decode(errors="replace")
+        symbols[value] = (size, demangle(name))
+    return sorted(symbols.items())
+
+def build_table(symbols):
+    entries = bytearray()
+    names = bytearray()
+    for address, (size, name) in symbols:
+        encoded = name.encode()
+        entries += struct.pack("<QIII", address, size, len(names), len(encoded))
+        names += encoded
+    return MAGIC + struct.pack("<I", len(symbols)) + entries + names
+
+def embed(path):
+    with open(path, "rb") as f:
+        elf = bytearray(f.read())
+
+    sections = read_sections(elf)
+    ksyms = next((s for s in sections if s["name"] == ".ksyms"), None)
+    if ksyms is None or ksyms["type"] == SHT_NOBITS:
+        sys.exit("The kernel has no .ksyms section to fill.")
+
+    symbols = function_symbols(elf, sections)
+    table = build_table(symbols)
+    if len(table) > ksyms["size"]:
+        sys.exit(f"The symbol table takes {len(table)} bytes, but .ksyms only has {ksyms['size']} (see KSYMS_SIZE).")
+
+    table += bytes(ksyms["size"] - len(table))
+    elf[ksyms["offset"]:ksyms["offset"] + ksyms["size"]] = table
+    with open(path, "wb") as f:
+        f.write(elf)
+
+    print(f"Embedded {len(symbols)} symbols in {path}.")
+
+def main():
+    if len(sys.argv) != 2:
+        sys.exit("Usage: embed_symbols.py <kernel ELF>")
+
+    embed(sys.argv[1])
+
+if __name__ == "__main__":
+    main()
@@ -0,0 +1,19 @@
+#!/usr/bin/env python3
+# Cargo runner for the kernel (see .cargo/config.toml). Embeds the symbol
+# table in the kernel ELF, then hands it to bootimage to make the boot
+# image and start QEMU, like "bootimage runner" does on its own.
+
+import subprocess
+import sys
+
+from embed_symbols import embed
+
+def main():
+    if len(sys.argv) < 2:
+        sys.exit("Usage: runner.py <kernel ELF> [args...]")
+
+    embed(sys.argv[1])
+    sys.exit(subprocess.call(["bootimage", "runner"] + sys.argv[1:]))
+
+if __name__ == "__main__":
+    main()
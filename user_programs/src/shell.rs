@@ -33,7 +33,7 @@ pub extern "C" fn _start()
     exit(exit_code);
 }
 
-pub fn run_command(input: &str)
+pub fn run_command(input: &str, jobs: &mut Jobs)
 {
     if input == "" { return; }
 
@@ -42,8 +42,24 @@ pub fn run_command(input: &str)
         println("  -HELP-");
         println("  Here's the list of available commands:");
         println("  help -- this command.");
-        println("  run [task_name] -- launches a new task in parallel.");
+        println("  run [task_name] -- launches a new task and waits for it.");
         println("                     Task names: shell, rec_fib.");
+        println("                     Add '&' at the end to run it in the background.");
+        println("  strace [task_name] -- like run, but the kernel logs the task's syscalls.");
+        println("  trace [pid] on/off -- starts or stops logging the syscalls of a process.");
+        println("  profile start/stop/dump -- samples where the CPU spends its time.");
+        println("                             The histogram is written to the serial port.");
+        println("  fg [pid] -- resumes a stopped task and waits for it.");
+        println("  bg [pid] -- resumes a stopped task in the background.");
+        println("  ps -- lists the running processes and their CPU usage.");
+        println("  clear -- clears the screen.");
+        println("  dmesg -- shows the kernel log.");
+        println("  maps -- shows the memory mapped by the shell.");
+        println("  (Use the arrow keys to move around the line and to go through the history.)");
+        println("  (Alt+F1..F6 switch console, Shift+PageUp/PageDown scroll back.)");
+        println("  (Alt+SysRq enters the kernel debug monitor.)");
+        println("  kill [pid] [signal] -- sends a signal to a process. (SIGTERM by default)");
+        println("  keymap [layout] -- switches the keyboard layout, or shows the current one.");
         println("  quit_shell -- exits this process. (this will leave the scheduler empty!)");
         println("  shutdown -- turns off this PC. (only works for QEMU)");
     }
@@ -52,6 +68,29 @@ pub fn run_command(input: &str)
         println("Shutting down system...");
         shutdown();
     }
+    else if input == "ps"
+    {
+        print_process_list();
+    }
+    else if input == "dmesg"
+    {
+        let mut buf: [u8; 16 * 1024] = [0; 16 * 1024];
+        print(dmesg(&mut buf));
+    }
+    else if input == "maps"
+    {
+        print_memory_map();
+    }
+    else if input == "clear"
+    {
+        print("\x1b[2J\x1b[H");
+    }
+    else if input == "keymap"
+    {
+        let mut buf: [u8; 16] = [0; 16];
+        print("Current layout: "); println(get_keymap(&mut buf));
+        println("Available layouts: us, uk, de, it, fr, dvorak, dvp, colemak, jp");
+    }
     else if input == "quit_shell"
     {
         println("Quitting...");
@@ -59,12 +98,92 @@ pub fn run_command(input: &str)
     }
     else
     {
-        if input.starts_with("run ")
+        if input.starts_with("kill ")
         {
-            let program_name = &input[4..];
-            let ok = create_task(program_name);
-            if ok { println("Successfully launched the task."); }
-            else  { println("Failed to create task."); }
+            let mut args = input[5..].split(' ').filter(|a| !a.is_empty());
+            let pid = args.next().and_then(parse_num);
+            let sig = match args.next() {
+                Some(arg) => parse_num(arg),
+                None => Some(SIGTERM as u64),
+            };
+
+            match (pid, sig)
+            {
+                (Some(pid), Some(sig)) => {
+                    if !kill(pid, sig as u32) { println("No such process."); }
+                }
+                _ => println("Usage: kill [pid] [signal]"),
+            }
+        }
+        else if input.starts_with("keymap ")
+        {
+            if !set_keymap(input[7..].trim()) { println("Unknown layout. Type 'keymap' for a list of layouts."); }
+        }
+        else if input.starts_with("fg ") || input.starts_with("bg ")
+        {
+            match parse_num(input[3..].trim())
+            {
+                None => println("Usage: fg/bg [pid]"),
+                Some(pid) => {
+                    if !kill(pid, SIGCONT) { println("No such process."); }
+                    else if input.starts_with("fg ") { wait_foreground(jobs, pid); }
+                    else { jobs.add(pid); }
+                }
+            }
+        }
+        else if input.starts_with("trace ")
+        {
+            let mut args = input[6..].split(' ').filter(|a| !a.is_empty());
+            let pid = args.next().and_then(parse_num);
+            let enable = match args.next() {
+                Some("on") => Some(true),
+                Some("off") => Some(false),
+                _ => None,
+            };
+
+            match (pid, enable)
+            {
+                (Some(pid), Some(enable)) => {
+                    if !set_tracing(pid, enable) { println("No such process."); }
+                }
+                _ => println("Usage: trace [pid] on/off"),
+            }
+        }
+        else if input.starts_with("profile ")
+        {
+            let command = match input[8..].trim() {
+                "start" => Some(PROFILE_START),
+                "stop" => Some(PROFILE_STOP),
+                "dump" => Some(PROFILE_DUMP),
+                _ => None,
+            };
+
+            match command.and_then(profile)
+            {
+                Some(samples) => { print_num(samples); println(" samples."); }
+                None => println("Usage: profile start/stop/dump"),
+            }
+        }
+        else if input.starts_with("run ") || input.starts_with("strace ")
+        {
+            let traced = input.starts_with("strace ");
+            let mut program_name = input[if traced { 7 } else { 4 }..].trim();
+            let background = program_name.ends_with('&');
+            if background { program_name = program_name[..program_name.len() - 1].trim(); }
+
+            let pid = if traced { create_task_traced(program_name) } else { create_task(program_name) };
+            match pid
+            {
+                None => println("Failed to create task."),
+                Some(pid) => {
+                    if background {
+                        print("["); print_num(pid); println("] Running in the background.");
+                        jobs.add(pid);
+                    } else {
+                        wait_foreground(jobs, pid);
+                    }
+                }
+            }
         }
         else
         {
@@ -74,43 +193,488 @@ pub fn run_command(input: &str)
 }
 
 
+pub fn print_process_list()
+{
+    let mut processes: [ProcessInfo; 32] = [ProcessInfo::default(); 32];
+    let count = list_processes(&mut processes);
+
+    println("  PID  TTY  STATE    USER  KERNEL  SWITCHES  START  NAME");
+    for p in &processes[..count]
+    {
+        print("  "); print_padded(p.pid, 3);
+        print("  "); print_padded(p.console + 1, 3);  // Numbered like the Alt+Fn keys
+        print("  "); print(p.state_name()); print_spaces(8 - p.state_name().len());
+        print(" "); print_padded(p.user_ticks, 4);
+        print("  "); print_padded(p.kernel_ticks, 6);
+        print("  "); print_padded(p.context_switches, 8);
+        print("  "); print_padded(p.start_tick, 5);
+        print("  "); println(p.name());
+    }
+}
+
+// Like "0x400000-0x402000 r-x user 4K"
+pub fn print_memory_map()
+{
+    let mut entries: [MemoryMapEntry; 64] = [MemoryMapEntry::default(); 64];
+    let count = memory_map(&mut entries);
+
+    for entry in &entries[..count]
+    {
+        print("  "); print_hex(entry.start); print("-"); print_hex(entry.end);
+        print(" r");
+        print(if entry.flags & MAP_WRITE != 0 { "w" } else { "-" });
+        print(if entry.flags & MAP_EXEC != 0 { "x" } else { "-" });
+        print(if entry.flags & MAP_USER != 0 { " user " } else { " kernel " });
+        print_num(entry.page_size / 1024); println("K");
+    }
+}
+
+// Gives the console to the task until it exits or stops.
+fn wait_foreground(jobs: &mut Jobs, pid: u64)
+{
+    jobs.remove(pid);
+    set_foreground(pid);
+    let status = wait(pid);
+    set_foreground(get_pid());
+
+    if let Some(WaitStatus::Stopped(_)) = status { jobs.add(pid); }
+    print_status(pid, status);
+}
+
+fn print_status(pid: u64, status: Option<WaitStatus>)
+{
+    print("["); print_num(pid); print("] ");
+    match status
+    {
+        None => println("No such child."),
+        Some(WaitStatus::Exited(code)) => { print("Done, exit code "); print_num(code); println(""); },
+        Some(WaitStatus::Signaled(sig)) => { print("Terminated by signal "); print_num(sig as u64); println(""); },
+        Some(WaitStatus::Stopped(sig)) => { println("Stopped. Use 'fg' or 'bg' to resume it."); },
+    }
+}
+
+fn parse_num(string: &str) -> Option<u64>
+{
+    if string.is_empty() { return None; }
+
+    let mut res: u64 = 0;
+    for c in string.bytes()
+    {
+        if !c.is_ascii_digit() { return None; }
+        res = res.checked_mul(10)?.checked_add((c - b'0') as u64)?;
+    }
+
+    return Some(res);
+}
+
+// Prints a number right-aligned in a field of the given width.
+fn print_padded(num: u64, width: usize)
+{
+    let mut digits = 1;
+    let mut n = num / 10;
+    while n > 0 { digits += 1; n /= 10; }
+
+    if digits < width { print_spaces(width - digits); }
+    print_num(num);
+}
+
+fn print_spaces(count: usize)
+{
+    for _ in 0..count { print(" "); }
+}
+
 pub fn shell_main() -> u64
 {
+    // Ctrl-C and Ctrl-Z are meant for the programs we launch, not for us.
+    signal(SIGINT, SigHandler::Ignore);
+    signal(SIGTSTP, SigHandler::Ignore);
+
     println("Welcome to TinyOS! I'm a user-program \"shell\".");
     println("Type 'help' for a list of available commands.");
-    print("> ");
 
-    let mut buffer: [u8; 512] = [0; 512];
-    let mut buf_idx = 0;
+    let mut history = History::new();
+    let mut jobs = Jobs::new();
+    let mut buffer: [u8; LINE_SIZE] = [0; LINE_SIZE];
     loop
     {
-
-        let input_char = read_char();
-        print_char(input_char);
-        if input_char as u8 == 0x08 // Backspace
+        jobs.reap();
+        print("> ");
+        match edit_line(&mut history, &mut buffer)
         {
-            if buf_idx > 0 {
-                buf_idx -= 1;
+            Some(len) => {
+                if let Ok(input_string) = core::str::from_utf8(&buffer[..len]) {
+                    run_command(input_string.trim(), &mut jobs);
+                }
             }
+            None => println(""),  // Ctrl-D on an empty line
         }
-        else if input_char == '\n'
+    }
+}
+
+// Jobs
+
+const MAX_JOBS: usize = 16;
+
+// Children running in the background or stopped. What happened to them is
+// collected before each prompt, so that the kernel doesn't have to keep it.
+pub struct Jobs
+{
+    pids: [u64; MAX_JOBS],
+    count: usize,
+}
+
+impl Jobs
+{
+    fn new() -> Self
+    {
+        return Jobs { pids: [0; MAX_JOBS], count: 0 };
+    }
+
+    fn add(&mut self, pid: u64)
+    {
+        if self.pids[..self.count].contains(&pid) { return; }
+        if self.count == MAX_JOBS { println("Too many jobs, this one won't be reported."); return; }
+
+        self.pids[self.count] = pid;
+        self.count += 1;
+    }
+
+    fn remove(&mut self, pid: u64)
+    {
+        if let Some(idx) = self.pids[..self.count].iter().position(|&p| p == pid)
         {
-            if let Ok(input_string) = core::str::from_utf8(&buffer[..buf_idx]) {
-                run_command(input_string);
-            }
+            self.count -= 1;
+            self.pids[idx] = self.pids[self.count];
+        }
+    }
 
-            buf_idx = 0;
-            print("> ");
+    // Reports the jobs that finished or stopped since the last prompt
+    fn reap(&mut self)
+    {
+        let mut idx = 0;
+        while idx < self.count
+        {
+            let pid = self.pids[idx];
+            let done = match try_wait(pid)
+            {
+                Some(None) => false,  // Still running
+                Some(Some(WaitStatus::Stopped(sig))) => { print_status(pid, Some(WaitStatus::Stopped(sig))); false }
+                Some(status) => { print_status(pid, status); true }
+                None => true,  // Someone else collected it
+            };
+
+            if done { self.remove(pid); } else { idx += 1; }
         }
-        else
+    }
+}
+
+// Line editing
+
+const LINE_SIZE: usize = 256;
+const HISTORY_SIZE: usize = 16;
+
+struct History
+{
+    lines: [[u8; LINE_SIZE]; HISTORY_SIZE],
+    lens: [usize; HISTORY_SIZE],
+    count: usize,  // Total number of lines ever added
+}
+
+impl History
+{
+    fn new() -> Self
+    {
+        return History { lines: [[0; LINE_SIZE]; HISTORY_SIZE], lens: [0; HISTORY_SIZE], count: 0 };
+    }
+
+    fn push(&mut self, line: &[u8])
+    {
+        if line.is_empty() || self.get(1) == Some(line) { return; }
+
+        let idx = self.count % HISTORY_SIZE;
+        self.lines[idx][..line.len()].copy_from_slice(line);
+        self.lens[idx] = line.len();
+        self.count += 1;
+    }
+
+    // 1 is the most recent line
+    fn get(&self, age: usize) -> Option<&[u8]>
+    {
+        if age == 0 || age > self.count || age > HISTORY_SIZE { return None; }
+
+        let idx = (self.count - age) % HISTORY_SIZE;
+        return Some(&self.lines[idx][..self.lens[idx]]);
+    }
+}
+
+enum Key
+{
+    Char([u8; 4], usize),  // UTF-8 encoded character and its length
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Home,
+    End,
+    Up,
+    Down,
+    Interrupt,
+    Eof,
+    KillLine,
+    Other,
+}
+
+fn read_byte() -> u8
+{
+    let mut byte: [u8; 1] = [0];
+    while read(&mut byte) == 0 {}
+    return byte[0];
+}
+
+fn read_key() -> Key
+{
+    return match read_byte()
+    {
+        b'\n' | b'\r' => Key::Enter,
+        0x08 | 0x7F => Key::Backspace,
+        0x01 => Key::Home,       // Ctrl-A
+        0x03 => Key::Interrupt,  // Ctrl-C
+        0x04 => Key::Eof,        // Ctrl-D
+        0x05 => Key::End,        // Ctrl-E
+        0x15 => Key::KillLine,   // Ctrl-U
+        0x1B => read_escape_sequence(),
+        c @ 0x20..=0x7E => Key::Char([c, 0, 0, 0], 1),
+        c @ 0xC2..=0xF4 => read_utf8_char(c),
+        _ => Key::Other,
+    };
+}
+
+// Reads the rest of a multi-byte character.
+fn read_utf8_char(first: u8) -> Key
+{
+    let len = match first
+    {
+        0xC2..=0xDF => 2,
+        0xE0..=0xEF => 3,
+        _ => 4,
+    };
+
+    let mut bytes: [u8; 4] = [first, 0, 0, 0];
+    for i in 1..len {
+        bytes[i] = read_byte();
+    }
+
+    if core::str::from_utf8(&bytes[..len]).is_err() { return Key::Other; }
+    return Key::Char(bytes, len);
+}
+
+// Parses what comes after ESC, e.g. "[A" for the up arrow or "[3~" for delete.
+fn read_escape_sequence() -> Key
+{
+    match read_byte()
+    {
+        b'[' => {},
+        b'O' => return match read_byte() { b'H' => Key::Home, b'F' => Key::End, _ => Key::Other },
+        _ => return Key::Other,  // Alt+key
+    }
+
+    let mut number: u32 = 0;
+    let mut in_first_param = true;
+    let final_byte = loop
+    {
+        let c = read_byte();
+        match c
+        {
+            b'0'..=b'9' => if in_first_param { number = number * 10 + (c - b'0') as u32; },
+            b';' => in_first_param = false,
+            _ => break c,
+        }
+    };
+
+    return match final_byte
+    {
+        b'A' => Key::Up,
+        b'B' => Key::Down,
+        b'C' => Key::Right,
+        b'D' => Key::Left,
+        b'H' => Key::Home,
+        b'F' => Key::End,
+        b'~' => match number { 1 | 7 => Key::Home, 3 => Key::Delete, 4 | 8 => Key::End, _ => Key::Other },
+        _ => Key::Other,
+    };
+}
+
+fn print_bytes(bytes: &[u8])
+{
+    if let Ok(string) = core::str::from_utf8(bytes) { print(string); }
+}
+
+// The line is kept as UTF-8, but the cursor moves by characters on screen.
+fn is_continuation(byte: u8) -> bool
+{
+    return byte & 0xC0 == 0x80;
+}
+
+fn num_chars(bytes: &[u8]) -> usize
+{
+    return bytes.iter().filter(|&&b| !is_continuation(b)).count();
+}
+
+// Length in bytes of the character that ends at `pos`.
+fn prev_char_len(buf: &[u8], pos: usize) -> usize
+{
+    let mut start = pos - 1;
+    while start > 0 && is_continuation(buf[start]) { start -= 1; }
+    return pos - start;
+}
+
+// Length in bytes of the character that starts at `pos`.
+fn next_char_len(buf: &[u8], pos: usize, len: usize) -> usize
+{
+    let mut end = pos + 1;
+    while end < len && is_continuation(buf[end]) { end += 1; }
+    return end - pos;
+}
+
+// Moves the cursor back over the characters in `bytes`.
+fn cursor_left(bytes: &[u8])
+{
+    for _ in 0..num_chars(bytes) { print("\x08"); }
+}
+
+// Replaces what's on screen (and in the buffer) with another line.
+// The cursor ends up at the end of it.
+fn replace_line(buf: &mut [u8; LINE_SIZE], len: &mut usize, cursor: &mut usize, new_line: &[u8])
+{
+    print_bytes(&buf[*cursor..*len]);
+    for _ in 0..num_chars(&buf[..*len]) { print("\x08 \x08"); }
+
+    buf[..new_line.len()].copy_from_slice(new_line);
+    *len = new_line.len();
+    *cursor = *len;
+    print_bytes(&buf[..*len]);
+}
+
+/// Reads a line with cursor movement and history. Returns the length
+/// of the line written in `buf`, or None on end of file.
+fn edit_line(history: &mut History, buf: &mut [u8; LINE_SIZE]) -> Option<usize>
+{
+    // We do our own echo and editing
+    let old_mode = get_tty_mode();
+    set_tty_mode(0);
+
+    let mut len = 0;
+    let mut cursor = 0;
+    let mut history_age = 0;  // 0 means the line being edited isn't from history
+    let mut stashed: [u8; LINE_SIZE] = [0; LINE_SIZE];
+    let mut stashed_len = 0;
+
+    let res = loop
+    {
+        match read_key()
         {
-            buffer[buf_idx] = input_char as u8;
+            Key::Char(bytes, char_len) =>
+            {
+                if len + char_len > LINE_SIZE { continue; }
+
+                buf.copy_within(cursor..len, cursor + char_len);
+                buf[cursor..cursor + char_len].copy_from_slice(&bytes[..char_len]);
+                len += char_len;
+                print_bytes(&buf[cursor..len]);
+                cursor += char_len;
+                cursor_left(&buf[cursor..len]);
+            }
+            Key::Backspace =>
+            {
+                if cursor == 0 { continue; }
+
+                let char_len = prev_char_len(buf, cursor);
+                buf.copy_within(cursor..len, cursor - char_len);
+                len -= char_len;
+                cursor -= char_len;
+                print("\x08");
+                print_bytes(&buf[cursor..len]);
+                print(" \x08");
+                cursor_left(&buf[cursor..len]);
+            }
+            Key::Delete =>
+            {
+                if cursor == len { continue; }
 
-            if buf_idx < buffer.len() - 1 {
-                buf_idx += 1;
+                let char_len = next_char_len(buf, cursor, len);
+                buf.copy_within(cursor + char_len..len, cursor);
+                len -= char_len;
+                print_bytes(&buf[cursor..len]);
+                print(" \x08");
+                cursor_left(&buf[cursor..len]);
             }
+            Key::Left  => if cursor > 0 { cursor -= prev_char_len(buf, cursor); print("\x08"); },
+            Key::Right =>
+            {
+                if cursor < len
+                {
+                    let char_len = next_char_len(buf, cursor, len);
+                    print_bytes(&buf[cursor..cursor + char_len]);
+                    cursor += char_len;
+                }
+            }
+            Key::Home  => { cursor_left(&buf[..cursor]); cursor = 0; },
+            Key::End   => { print_bytes(&buf[cursor..len]); cursor = len; },
+            Key::Up =>
+            {
+                if let Some(line) = history.get(history_age + 1)
+                {
+                    if history_age == 0
+                    {
+                        stashed[..len].copy_from_slice(&buf[..len]);
+                        stashed_len = len;
+                    }
+
+                    history_age += 1;
+                    let mut line_copy: [u8; LINE_SIZE] = [0; LINE_SIZE];
+                    line_copy[..line.len()].copy_from_slice(line);
+                    replace_line(buf, &mut len, &mut cursor, &line_copy[..line.len()]);
+                }
+            }
+            Key::Down =>
+            {
+                if history_age == 0 { continue; }
+
+                history_age -= 1;
+                let mut line_copy: [u8; LINE_SIZE] = [0; LINE_SIZE];
+                let line_len = match history.get(history_age)
+                {
+                    Some(line) => { line_copy[..line.len()].copy_from_slice(line); line.len() },
+                    None => { line_copy[..stashed_len].copy_from_slice(&stashed[..stashed_len]); stashed_len },
+                };
+                replace_line(buf, &mut len, &mut cursor, &line_copy[..line_len]);
+            }
+            Key::KillLine =>
+            {
+                replace_line(buf, &mut len, &mut cursor, &[]);
+            }
+            Key::Interrupt =>
+            {
+                println("^C");
+                break Some(0);
+            }
+            Key::Eof =>
+            {
+                if len == 0 { break None; }
+            }
+            Key::Enter =>
+            {
+                println("");
+                history.push(&buf[..len]);
+                break Some(len);
+            }
+            Key::Other => {},
         }
-    }
+    };
+
+    set_tty_mode(old_mode);
+    return res;
 }
 
 pub fn rec_fib_main() -> u64
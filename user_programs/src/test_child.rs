@@ -0,0 +1,23 @@
+// Launched by test_tasks, which checks its exit status.
+
+#![no_std]
+#![no_main]
+#![allow(dead_code)]
+#![allow(unused_variables)]
+
+use core::panic::PanicInfo;
+mod tinyos_userlib;
+use crate::tinyos_userlib::*;
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !
+{
+    exit(101);
+    loop {}
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _start()
+{
+    exit(42);
+}
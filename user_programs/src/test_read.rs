@@ -0,0 +1,36 @@
+// Checks reading from the terminal. The kernel's test runner
+// types "hello\nxé" before starting it.
+
+#![no_std]
+#![no_main]
+#![allow(dead_code)]
+#![allow(unused_variables)]
+
+use core::panic::PanicInfo;
+mod tinyos_userlib;
+use crate::tinyos_userlib::*;
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !
+{
+    test_report("panic", false);
+    exit(101);
+    loop {}
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _start()
+{
+    let mut buf: [u8; 32] = [0; 32];
+    test_report("read_line", read_next_line(&mut buf) == Some("hello"));
+
+    // The rest hasn't been sent with a newline, so it becomes
+    // readable only when leaving canonical mode
+    let mode = get_tty_mode();
+    set_tty_mode(mode & !(TTY_CANONICAL | TTY_ECHO));
+    test_report("read_char", read_char() == 'x');
+    test_report("read_char_utf8", read_char() == 'é');
+    set_tty_mode(mode);
+
+    exit(0);
+}
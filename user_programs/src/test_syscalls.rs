@@ -0,0 +1,139 @@
+// Checks the syscalls that don't need other tasks or input.
+// Run by the kernel when it's built with the user_tests feature.
+
+#![no_std]
+#![no_main]
+#![allow(dead_code)]
+#![allow(unused_variables)]
+
+extern crate alloc;
+
+use alloc::{boxed::Box, string::String, vec::Vec};
+use core::panic::PanicInfo;
+use core::sync::atomic::{AtomicU32, Ordering};
+mod tinyos_userlib;
+use crate::tinyos_userlib::*;
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !
+{
+    test_report("panic", false);
+    exit(101);
+    loop {}
+}
+
+static SIGNALS_RECEIVED: AtomicU32 = AtomicU32::new(0);
+
+extern "C" fn on_signal(sig: u32)
+{
+    SIGNALS_RECEIVED.fetch_add(1, Ordering::SeqCst);
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _start()
+{
+    // Unknown syscalls fail instead of bringing down the kernel
+    test_report("unknown_syscall", syscall(1000, 0, 0, 0, 0) == SYSCALL_UNKNOWN);
+
+    // Printing
+    print("print ");
+    print_num(42);
+    print_char('\n');
+    let invalid_char = syscall(Syscall::PrintChar as u64, 0xD800, 0, 0, 0);
+    test_report("print_char_rejects_surrogates", invalid_char != 0);
+
+    // Processes
+    let pid = get_pid();
+    test_report("get_pid", pid != 0);
+    test_report("get_arg_0", get_arg_0() == 0);
+
+    let mut processes: [ProcessInfo; 16] = Default::default();
+    let count = list_processes(&mut processes);
+    let me = processes[..count].iter().find(|p| p.pid == pid);
+    test_report("list_processes_has_self", me.is_some());
+    test_report("list_processes_name", me.map(|p| p.name()) == Some("test_syscalls"));
+    test_report("list_processes_running", me.map(|p| p.state_name()) == Some("running"));
+    test_report("list_processes_truncates", list_processes(&mut processes[..0]) == 0);
+
+    // Memory map. The code and the stack have to be in there.
+    let mut maps: [MemoryMapEntry; 64] = [MemoryMapEntry::default(); 64];
+    let count = memory_map(&mut maps);
+    let maps = &maps[..count];
+    let code_addr = _start as usize as u64;
+    let stack_addr = &count as *const usize as u64;
+    let code = maps.iter().find(|m| m.start <= code_addr && code_addr < m.end);
+    let stack = maps.iter().find(|m| m.start <= stack_addr && stack_addr < m.end);
+    test_report("memory_map_code", code.map(|m| m.flags & (MAP_EXEC | MAP_USER) == MAP_EXEC | MAP_USER) == Some(true));
+    test_report("memory_map_stack", stack.map(|m| m.flags & (MAP_WRITE | MAP_USER) == MAP_WRITE | MAP_USER) == Some(true));
+    test_report("memory_map_sorted", maps.windows(2).all(|w| w[0].end <= w[1].start));
+
+    // Terminal modes
+    let mode = get_tty_mode();
+    test_report("tty_default_mode", mode == TTY_CANONICAL | TTY_ECHO | TTY_SIGNALS);
+    set_tty_mode(TTY_SIGNALS);
+    test_report("tty_set_mode", get_tty_mode() == TTY_SIGNALS);
+    set_tty_mode(mode);
+
+    // Keyboard layouts
+    let mut buf: [u8; 16] = [0; 16];
+    let mut old_keymap: [u8; 16] = [0; 16];
+    let old_keymap = get_keymap(&mut old_keymap);
+    test_report("set_keymap", set_keymap("it"));
+    test_report("get_keymap", get_keymap(&mut buf) == "it");
+    test_report("set_keymap_unknown", !set_keymap("no_such_layout") && get_keymap(&mut buf) == "it");
+    set_keymap(old_keymap);
+
+    // Kernel log
+    let mut log_buf: [u8; 512] = [0; 512];
+    let log = dmesg(&mut log_buf);
+    test_report("dmesg", !log.is_empty() && log.ends_with('\n'));
+
+    // Signals
+    test_report("signal_handler", signal(SIGUSR1, SigHandler::Handler(on_signal)));
+    test_report("signal_sigkill_not_catchable", !signal(SIGKILL, SigHandler::Ignore));
+    test_report("kill_self", kill(pid, SIGUSR1));
+    test_report("signal_delivered", SIGNALS_RECEIVED.load(Ordering::SeqCst) == 1);
+
+    sigprocmask(SIG_BLOCK, 1 << SIGUSR1);
+    kill(pid, SIGUSR1);
+    test_report("signal_blocked", SIGNALS_RECEIVED.load(Ordering::SeqCst) == 1);
+    sigprocmask(SIG_UNBLOCK, 1 << SIGUSR1);
+    get_pid();  // Pending signals are delivered when returning from a syscall
+    test_report("signal_unblocked", SIGNALS_RECEIVED.load(Ordering::SeqCst) == 2);
+
+    signal(SIGUSR1, SigHandler::Ignore);
+    kill(pid, SIGUSR1);
+    test_report("signal_ignored", SIGNALS_RECEIVED.load(Ordering::SeqCst) == 2);
+
+    test_report("kill_no_such_task", !kill(u64::MAX - 10, 0));
+
+    // Syscall tracing
+    test_report("trace_self", set_tracing(0, true) && set_tracing(pid, false));
+    test_report("trace_no_such_task", !set_tracing(u64::MAX - 10, true));
+
+    // Heap
+    let start = brk(0);
+    test_report("brk_page_aligned", start != 0 && start % 4096 == 0);
+    let mem = sbrk(10000);
+    test_report("sbrk_returns_old_break", mem == Some(start as *mut u8) && brk(0) == start + 10000);
+    let mem = unsafe { core::slice::from_raw_parts_mut(start as *mut u8, 10000) };
+    test_report("sbrk_zeroed", mem.iter().all(|&b| b == 0));
+    mem[9999] = 1;
+    test_report("brk_shrink", brk(start) == start);
+    test_report("brk_below_start", brk(start - 4096) == start && sbrk(-1).is_none());
+    test_report("sbrk_too_big", sbrk(1 << 40).is_none() && brk(0) == start);
+
+    let boxed = Box::new(42u64);
+    let mut numbers: Vec<u64> = Vec::new();
+    for i in 0..1000 { numbers.push(i); }
+    let mut string = String::from("hello");
+    string.push_str(", heap");
+    test_report("alloc_box", *boxed == 42);
+    test_report("alloc_vec", numbers.iter().sum::<u64>() == 999 * 1000 / 2);
+    test_report("alloc_string", string == "hello, heap");
+    let first = &*boxed as *const u64;
+    drop(boxed);
+    test_report("alloc_reuses_freed", &*Box::new(7u64) as *const u64 == first);
+
+    exit(0);
+}
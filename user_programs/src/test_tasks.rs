@@ -0,0 +1,51 @@
+// Checks creating tasks and waiting for them.
+// Run by the kernel when it's built with the user_tests feature.
+
+#![no_std]
+#![no_main]
+#![allow(dead_code)]
+#![allow(unused_variables)]
+
+use core::panic::PanicInfo;
+mod tinyos_userlib;
+use crate::tinyos_userlib::*;
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> !
+{
+    test_report("panic", false);
+    exit(101);
+    loop {}
+}
+
+#[unsafe(no_mangle)]
+pub extern "C" fn _start()
+{
+    test_report("create_task_unknown", create_task("no_such_program").is_none());
+
+    let child = create_task("test_child");
+    test_report("create_task", child.is_some());
+
+    if let Some(child) = child
+    {
+        test_report("child_pid", child != get_pid());
+
+        // test_child exits with 42
+        let status = wait(child);
+        test_report("wait_exited", matches!(status, Some(WaitStatus::Exited(42))));
+        test_report("wait_collected", wait(child).is_none());
+    }
+
+    test_report("wait_not_a_child", wait(get_pid()).is_none());
+
+    // Several children at once
+    let mut children: [u64; 3] = [0; 3];
+    for child in children.iter_mut() {
+        *child = create_task("test_child").unwrap_or(0);
+    }
+    for &child in children.iter().rev() {
+        test_report("wait_many", matches!(wait(child), Some(WaitStatus::Exited(42))));
+    }
+
+    exit(0);
+}
@@ -1,5 +1,7 @@
 
+use core::alloc::{GlobalAlloc, Layout};
 use core::arch::asm;
+use core::cell::UnsafeCell;
 
 #[inline(never)]
 pub fn syscall(n: u64, arg1: u64, arg2: u64, arg3: u64, arg4: u64) -> u64
@@ -34,6 +36,69 @@ pub enum Syscall
     GetArg0 = 6,
     Exit = 7,
     Shutdown = 8,
+    ListProcesses = 9,
+    Kill = 10,
+    Signal = 11,
+    SigProcMask = 12,
+    SigReturn = 13,
+    GetPid = 14,
+    Wait = 15,
+    SetForeground = 16,
+    Read = 17,
+    Ioctl = 18,
+    SetKeymap = 19,
+    GetKeymap = 20,
+    Dmesg = 21,
+    TestReport = 22,
+    MemoryMap = 23,
+    Trace = 24,
+    Profile = 25,
+    Brk = 26,
+    Sbrk = 27,
+}
+
+/// Returned by syscalls with an unknown number.
+// NOTE: This should be kept up to date along with its
+// counterpart in kernel code.
+pub const SYSCALL_UNKNOWN: u64 = u64::MAX;
+
+pub const PROCESS_NAME_LEN: usize = 16;
+
+// NOTE: This should be kept up to date along with its
+// counterpart in kernel code.
+#[derive(Default, Clone, Copy)]
+#[repr(C)]
+pub struct ProcessInfo
+{
+    pub pid: u64,
+    pub state: u64,
+    pub user_ticks: u64,
+    pub kernel_ticks: u64,
+    pub context_switches: u64,
+    pub start_tick: u64,
+    pub console: u64,  // Virtual console the process is bound to
+    pub name: [u8; PROCESS_NAME_LEN],
+}
+
+impl ProcessInfo
+{
+    pub fn name(&self) -> &str
+    {
+        let len = self.name.iter().position(|&c| c == 0).unwrap_or(PROCESS_NAME_LEN);
+        return core::str::from_utf8(&self.name[..len]).unwrap_or("?");
+    }
+
+    pub fn state_name(&self) -> &'static str
+    {
+        return match self.state
+        {
+            0 => "ready",
+            1 => "running",
+            2 => "blocked",
+            3 => "stopped",
+            _ => "unknown",
+        };
+    }
 }
 
 pub fn print(string: &str)
@@ -46,6 +111,27 @@ pub fn print_num(num: u64)
     syscall(Syscall::PrintNum as u64, num, 0, 0, 0);
 }
 
+/// Prints a number in hexadecimal, with the 0x prefix.
+pub fn print_hex(num: u64)
+{
+    let mut buf: [u8; 18] = [0; 18];
+    let mut len = 0;
+    let mut shift = 60;
+    // Skip leading zeros
+    while shift > 0 && (num >> shift) & 0xF == 0 { shift -= 4; }
+
+    buf[0] = b'0'; buf[1] = b'x'; len += 2;
+    loop
+    {
+        buf[len] = b"0123456789abcdef"[((num >> shift) & 0xF) as usize];
+        len += 1;
+        if shift == 0 { break; }
+        shift -= 4;
+    }
+
+    print(core::str::from_utf8(&buf[..len]).unwrap_or(""));
+}
+
 pub fn print_char(c: char)
 {
     syscall(Syscall::PrintChar as u64, c as u64, 0, 0, 0);
@@ -67,9 +153,177 @@ pub fn shutdown()
     syscall(Syscall::Shutdown as u64, 0, 0, 0, 0);
 }
 
-pub fn create_task(task_name: &str) -> bool
+/// Launches a new task and returns its pid.
+pub fn create_task(task_name: &str) -> Option<u64>
+{
+    let pid = syscall(Syscall::CreateTask as u64, task_name.as_ptr() as *const u8 as u64, task_name.len() as u64, 0, 0);
+    return if pid != 0 { Some(pid) } else { None };
+}
+
+// Flags of the create_task syscall
+// NOTE: This should be kept up to date along with its
+// counterpart in kernel code.
+pub const CREATE_TRACED: u64 = 1 << 0;
+
+/// Like create_task, but the syscalls of the new task
+/// (and of the tasks it creates) are logged by the kernel.
+pub fn create_task_traced(task_name: &str) -> Option<u64>
+{
+    let pid = syscall(Syscall::CreateTask as u64, task_name.as_ptr() as *const u8 as u64, task_name.len() as u64, CREATE_TRACED, 0);
+    return if pid != 0 { Some(pid) } else { None };
+}
+
+// Commands of the profile syscall
+// NOTE: This should be kept up to date along with its
+// counterpart in kernel code.
+pub const PROFILE_START: u64 = 0;
+pub const PROFILE_STOP:  u64 = 1;
+pub const PROFILE_DUMP:  u64 = 2;
+
+/// Starts, stops or dumps (to the serial port) the kernel's sampling
+/// profiler. Returns the number of samples taken, or None for an unknown command.
+pub fn profile(command: u64) -> Option<u64>
+{
+    let samples = syscall(Syscall::Profile as u64, command, 0, 0, 0);
+    return if samples != u64::MAX { Some(samples) } else { None };
+}
+
+/// Turns syscall tracing on or off for a task (0 for the caller).
+/// Returns false if there's no such task.
+pub fn set_tracing(pid: u64, enable: bool) -> bool
 {
-    return unsafe { core::mem::transmute(syscall(Syscall::CreateTask as u64, task_name.as_ptr() as *const u8 as u64, task_name.len() as u64, 0, 0) as u8) };
+    return syscall(Syscall::Trace as u64, pid, enable as u64, 0, 0) != 0;
+}
+
+pub enum WaitStatus
+{
+    Exited(u64),
+    Signaled(u32),
+    Stopped(u32),
+}
+
+// NOTE: The status encoding should be kept up to
+// date along with its counterpart in kernel code.
+const WAIT_PENDING: u64 = u64::MAX - 1;
+const WAIT_NO_CHILD: u64 = u64::MAX;
+const WAIT_NO_HANG: u64 = 1 << 0;
+
+fn decode_wait_status(status: u64) -> WaitStatus
+{
+    if status & 0xFF == 0x7F { return WaitStatus::Stopped((status >> 8) as u32); }
+    if status & 0x7F != 0    { return WaitStatus::Signaled((status & 0x7F) as u32); }
+    return WaitStatus::Exited((status >> 8) & 0xFF);
+}
+
+/// Waits until the child task exits, gets killed or stops.
+/// Returns None if there's no such child.
+pub fn wait(pid: u64) -> Option<WaitStatus>
+{
+    loop
+    {
+        let status = syscall(Syscall::Wait as u64, pid, 0, 0, 0);
+        if status == WAIT_PENDING { continue; }
+        if status == WAIT_NO_CHILD { return None; }
+
+        return Some(decode_wait_status(status));
+    }
+}
+
+/// Like wait, but doesn't block. Returns Some(None) if the child
+/// hasn't changed state yet, and None if there's no such child.
+pub fn try_wait(pid: u64) -> Option<Option<WaitStatus>>
+{
+    let status = syscall(Syscall::Wait as u64, pid, WAIT_NO_HANG, 0, 0);
+    if status == WAIT_PENDING { return Some(None); }
+    if status == WAIT_NO_CHILD { return None; }
+
+    return Some(Some(decode_wait_status(status)));
+}
+
+/// Makes the task receive Ctrl-C and Ctrl-Z from the keyboard.
+pub fn set_foreground(pid: u64) -> bool
+{
+    return syscall(Syscall::SetForeground as u64, pid, 0, 0, 0) != 0;
+}
+
+/// Fills `buf` with information about the running processes,
+/// and returns the number of entries that were written.
+pub fn list_processes(buf: &mut [ProcessInfo]) -> usize
+{
+    return syscall(Syscall::ListProcesses as u64, buf.as_mut_ptr() as u64, buf.len() as u64, 0, 0) as usize;
+}
+
+// Signals
+
+// NOTE: These should be kept up to date along with their
+// counterparts in kernel code.
+pub const SIGINT:  u32 = 2;
+pub const SIGKILL: u32 = 9;
+pub const SIGUSR1: u32 = 10;
+pub const SIGSEGV: u32 = 11;
+pub const SIGUSR2: u32 = 12;
+pub const SIGTERM: u32 = 15;
+pub const SIGCHLD: u32 = 17;
+pub const SIGCONT: u32 = 18;
+pub const SIGSTOP: u32 = 19;
+pub const SIGTSTP: u32 = 20;
+
+pub const SIG_BLOCK:   u64 = 0;
+pub const SIG_UNBLOCK: u64 = 1;
+pub const SIG_SETMASK: u64 = 2;
+
+pub enum SigHandler
+{
+    Default,
+    Ignore,
+    Handler(extern "C" fn(u32)),
+}
+
+// Signal handlers return here, and we go back to the interrupted code.
+core::arch::global_asm!(
+    ".global tinyos_sigreturn_trampoline",
+    "tinyos_sigreturn_trampoline:",
+    "mov rax, {sigreturn}",
+    "int 0x80",
+    "ud2",
+    sigreturn = const Syscall::SigReturn as u64,
+);
+
+unsafe extern "C" {
+    fn tinyos_sigreturn_trampoline();
+}
+
+pub fn get_pid() -> u64
+{
+    return syscall(Syscall::GetPid as u64, 0, 0, 0, 0);
+}
+
+/// Sends a signal to a task, returns false if there is no such task.
+pub fn kill(pid: u64, sig: u32) -> bool
+{
+    return syscall(Syscall::Kill as u64, pid, sig as u64, 0, 0) != 0;
+}
+
+/// Sets what happens when the given signal is received.
+/// Returns false if the signal can't be caught.
+pub fn signal(sig: u32, handler: SigHandler) -> bool
+{
+    let handler_addr = match handler
+    {
+        SigHandler::Default => 0,
+        SigHandler::Ignore => 1,
+        SigHandler::Handler(f) => f as u64,
+    };
+
+    let restorer = tinyos_sigreturn_trampoline as u64;
+    return syscall(Syscall::Signal as u64, sig as u64, handler_addr, restorer, 0) != u64::MAX;
+}
+
+/// Changes the set of blocked signals (one bit per signal number),
+/// and returns the previous one.
+pub fn sigprocmask(how: u64, set: u32) -> u32
+{
+    return syscall(Syscall::SigProcMask as u64, how, set as u64, 0, 0) as u32;
 }
 
 pub fn get_arg_0() -> u64
@@ -77,30 +331,230 @@ pub fn get_arg_0() -> u64
     return syscall(Syscall::GetArg0 as u64, 0, 0, 0, 0);
 }
 
+/// Reads a single character (not byte) from the terminal.
 pub fn read_char() -> char
 {
     loop
     {
         let res = syscall(Syscall::ReadChar as u64, 0, 0, 0, 0);
-        if res != 0 { return res as u8 as char; }
+        if res != 0 { return char::from_u32(res as u32).unwrap_or(char::REPLACEMENT_CHARACTER); }
+    }
+}
+
+const READ_PENDING: u64 = u64::MAX - 1;
+
+/// Reads from the terminal. In canonical mode this returns
+/// at most one line. Returns 0 on end of file.
+pub fn read(buffer: &mut [u8]) -> usize
+{
+    loop
+    {
+        let res = syscall(Syscall::Read as u64, buffer.as_mut_ptr() as u64, buffer.len() as u64, 0, 0);
+        if res != READ_PENDING { return res as usize; }
     }
 }
 
-pub fn read_next_line(buffer: &mut [u8]) -> &str
+/// Reads a line from the terminal, without the newline. Whatever doesn't
+/// fit in the buffer is dropped. Returns None on end of file.
+pub fn read_next_line(buffer: &mut [u8]) -> Option<&str>
 {
-    let mut cur_idx = 0;
+    let mut len = 0;
     loop
     {
-        let next_char = read_char() as u8;
-        if next_char == 0 { continue; }
-        if next_char == '\n' as u8 { break; }
+        if len == buffer.len()
+        {
+            let mut discard: [u8; 1] = [0];
+            while read(&mut discard) != 0 && discard[0] != b'\n' {}
+            break;
+        }
+
+        let count = read(&mut buffer[len..]);
+        if count == 0
+        {
+            if len == 0 { return None; }
+            break;
+        }
 
-        cur_idx += 1;
+        len += count;
+        if buffer[len - 1] == b'\n' { len -= 1; break; }
     }
 
-    if let Ok(input) = core::str::from_utf8(buffer) {
-        return input;
-    } else {
-        return "";
+    return Some(core::str::from_utf8(&buffer[..len]).unwrap_or(""));
+}
+
+// Terminal modes
+
+// NOTE: These should be kept up to date along with their
+// counterparts in kernel code.
+/// Input is line buffered and can be edited before it's sent.
+pub const TTY_CANONICAL: u64 = 1 << 0;
+/// Input is echoed to the screen.
+pub const TTY_ECHO:      u64 = 1 << 1;
+/// Ctrl-C and Ctrl-Z send signals to the foreground task.
+pub const TTY_SIGNALS:   u64 = 1 << 2;
+
+const TTY_GET_MODE: u64 = 0;
+const TTY_SET_MODE: u64 = 1;
+
+pub fn get_tty_mode() -> u64
+{
+    return syscall(Syscall::Ioctl as u64, TTY_GET_MODE, 0, 0, 0);
+}
+
+pub fn set_tty_mode(mode: u64)
+{
+    syscall(Syscall::Ioctl as u64, TTY_SET_MODE, mode, 0, 0);
+}
+
+/// Switches the keyboard layout (e.g. "us", "it", "de"). Returns
+/// false if there's no layout with that name.
+pub fn set_keymap(name: &str) -> bool
+{
+    return syscall(Syscall::SetKeymap as u64, name.as_ptr() as u64, name.len() as u64, 0, 0) != 0;
+}
+
+/// Copies the most recent kernel log messages into `buf`.
+pub fn dmesg(buf: &mut [u8]) -> &str
+{
+    let len = syscall(Syscall::Dmesg as u64, buf.as_mut_ptr() as u64, buf.len() as u64, 0, 0) as usize;
+    return core::str::from_utf8(&buf[..len]).unwrap_or("");
+}
+
+/// Name of the current keyboard layout.
+pub fn get_keymap(buf: &mut [u8]) -> &str
+{
+    let len = syscall(Syscall::GetKeymap as u64, buf.as_mut_ptr() as u64, buf.len() as u64, 0, 0) as usize;
+    return core::str::from_utf8(&buf[..len]).unwrap_or("");
+}
+
+/// Reports the result of a check to the kernel's test runner
+/// (see the test_* programs). Returns `passed`.
+pub fn test_report(name: &str, passed: bool) -> bool
+{
+    syscall(Syscall::TestReport as u64, name.as_ptr() as u64, name.len() as u64, passed as u64, 0);
+    return passed;
+}
+
+// Memory map
+
+// NOTE: These should be kept up to date along with their
+// counterparts in kernel code.
+pub const MAP_READ:  u64 = 1 << 0;
+pub const MAP_WRITE: u64 = 1 << 1;
+pub const MAP_EXEC:  u64 = 1 << 2;
+pub const MAP_USER:  u64 = 1 << 3;
+
+// NOTE: This should be kept up to date along with its
+// counterpart in kernel code.
+#[derive(Default, Clone, Copy)]
+#[repr(C)]
+pub struct MemoryMapEntry
+{
+    pub start: u64,
+    pub end: u64,
+    pub flags: u64,
+    pub page_size: u64,
+}
+
+/// Fills `buf` with the memory mapped in the task's address space,
+/// and returns the number of entries that were written.
+pub fn memory_map(buf: &mut [MemoryMapEntry]) -> usize
+{
+    return syscall(Syscall::MemoryMap as u64, buf.as_mut_ptr() as u64, buf.len() as u64, 0, 0) as usize;
+}
+
+/// Moves the end of the heap to `addr` if it can, and returns where it is
+/// afterwards. brk(0) tells where it is without moving it.
+pub fn brk(addr: u64) -> u64
+{
+    return syscall(Syscall::Brk as u64, addr, 0, 0, 0);
+}
+
+/// Grows (or shrinks) the heap by `increment` bytes, and returns
+/// where the new memory starts. The new memory is zeroed.
+pub fn sbrk(increment: i64) -> Option<*mut u8>
+{
+    let old_brk = syscall(Syscall::Sbrk as u64, increment as u64, 0, 0, 0);
+    return if old_brk != u64::MAX { Some(old_brk as *mut u8) } else { None };
+}
+
+// Heap allocator, so that programs can use Vec, String and Box. Blocks are
+// rounded up to a power of two, and freed blocks are kept in a list per
+// size to be reused. Memory is never given back to the kernel.
+
+const NUM_SIZE_CLASSES: usize = 48;
+const MIN_BLOCK_SIZE: usize = 16;
+// Bigger blocks are only aligned to pages
+const MAX_BLOCK_ALIGN: usize = 4096;
+
+struct FreeBlock
+{
+    next: *mut FreeBlock,
+}
+
+pub struct UserAllocator
+{
+    // Free blocks of size 2^i
+    free_lists: UnsafeCell<[*mut FreeBlock; NUM_SIZE_CLASSES]>,
+}
+
+// User programs have a single thread
+unsafe impl Sync for UserAllocator {}
+
+#[global_allocator]
+static ALLOCATOR: UserAllocator = UserAllocator { free_lists: UnsafeCell::new([core::ptr::null_mut(); NUM_SIZE_CLASSES]) };
+
+impl UserAllocator
+{
+    // Blocks are aligned to their size (up to a page), so any block
+    // of a size class can be used for any layout that maps to it.
+    fn block_size(layout: &Layout) -> Option<usize>
+    {
+        if layout.align() > MAX_BLOCK_ALIGN { return None; }
+        let size = core::cmp::max(core::cmp::max(layout.size(), layout.align()), MIN_BLOCK_SIZE);
+        return size.checked_next_power_of_two();
+    }
+
+    // Takes a new block from the end of the heap
+    fn new_block(size: usize) -> *mut u8
+    {
+        let align = core::cmp::min(size, MAX_BLOCK_ALIGN) as u64;
+        let end = brk(0);
+        let padding = (align - end % align) % align;
+        return match sbrk((padding + size as u64) as i64)
+        {
+            Some(ptr) => unsafe { ptr.add(padding as usize) },
+            None => core::ptr::null_mut(),
+        };
+    }
+}
+
+unsafe impl GlobalAlloc for UserAllocator
+{
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8
+    {
+        let size = match Self::block_size(&layout)
+        {
+            Some(size) => size,
+            None => return core::ptr::null_mut(),
+        };
+
+        let free_list = unsafe { &mut (*self.free_lists.get())[size.trailing_zeros() as usize] };
+        if free_list.is_null() {
+            return Self::new_block(size);
+        }
+
+        let block = *free_list;
+        *free_list = unsafe { (*block).next };
+        return block as *mut u8;
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout)
+    {
+        let size = Self::block_size(&layout).unwrap();
+        let free_list = unsafe { &mut (*self.free_lists.get())[size.trailing_zeros() as usize] };
+        let block = ptr as *mut FreeBlock;
+        unsafe { (*block).next = *free_list };
+        *free_list = block;
     }
 }